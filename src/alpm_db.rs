@@ -0,0 +1,396 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+/// Package entry of pacman local or sync database
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DbPackage {
+    pub name: String,
+    pub version: String,
+    /// Sync database the package comes from, `None` for local database
+    pub repository: Option<String>,
+    /// Install reason, always `true` for sync database
+    pub explicit: bool,
+    /// Unix timestamp, local database only
+    pub install_date: Option<i64>,
+    /// Dependencies as written in database, with version constraints
+    pub depends: Vec<String>,
+    pub provides: Vec<String>,
+    /// Names of optional dependencies without descriptions and version constraints
+    pub optional_deps: Vec<String>,
+}
+
+impl DbPackage {
+    /// Whether package is `name` or provides it
+    pub fn satisfies(&self, name: &str) -> bool {
+        self.name == name
+            || self
+                .provides
+                .iter()
+                .any(|provided| dep_name(provided) == name)
+    }
+}
+
+/// Name of a dependency like `name>=1.0`, `name: description` or `name [installed]` of `pacman -Qi`
+pub fn dep_name(dep: &str) -> &str {
+    dep.trim_start()
+        .split([':', '<', '>', '=', ' '])
+        .next()
+        .unwrap_or_default()
+        .trim()
+}
+
+/// Reads `<db_path>/local/*/desc`, the local database pacman keeps installed packages in
+pub fn read_local_db(db_path: &Path) -> Result<Vec<DbPackage>> {
+    let local = db_path.join("local");
+    let mut packages = Vec::new();
+
+    for entry in fs::read_dir(&local)
+        .with_context(|| format!("failed to read local database {}", local.display()))?
+    {
+        let dir = entry?.path();
+        let desc = dir.join("desc");
+        if !desc.is_file() {
+            // ALPM_DB_VERSION file and leftovers of interrupted transactions
+            continue;
+        }
+        let text = fs::read_to_string(&desc)
+            .with_context(|| format!("failed to read {}", desc.display()))?;
+        let mut pkg = DbPackage {
+            explicit: true,
+            ..Default::default()
+        };
+        parse_desc(&text, &mut pkg);
+        packages.push(pkg);
+    }
+
+    packages.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(packages)
+}
+
+/// Reads every `<db_path>/sync/*.db` tarball, packages are ordered by repository name
+pub fn read_sync_dbs(db_path: &Path) -> Result<Vec<DbPackage>> {
+    let sync = db_path.join("sync");
+    let mut dbs: Vec<_> = fs::read_dir(&sync)
+        .with_context(|| format!("failed to read sync databases {}", sync.display()))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "db"))
+        .collect();
+    dbs.sort();
+
+    let mut packages = Vec::new();
+    for db in dbs {
+        let repository = db
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        packages.extend(
+            read_sync_db(&db, &repository)
+                .with_context(|| format!("failed to read sync database {}", db.display()))?,
+        );
+    }
+    Ok(packages)
+}
+
+/// Sync database is a (usually compressed) tarball with `name-version/desc` entry for each package,
+/// databases created by old pacman versions also have separate `name-version/depends` entry
+fn read_sync_db(path: &Path, repository: &str) -> Result<Vec<DbPackage>> {
+    let mut archive = tar::Archive::new(decompress(path)?);
+    let mut packages: BTreeMap<String, DbPackage> = BTreeMap::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_path = entry.path()?.to_path_buf();
+        let (Some(dir), Some(file)) = (entry_path.parent(), entry_path.file_name()) else {
+            continue;
+        };
+        if file != "desc" && file != "depends" {
+            continue;
+        }
+        let mut text = String::new();
+        entry.read_to_string(&mut text)?;
+
+        let pkg = packages
+            .entry(dir.to_string_lossy().to_string())
+            .or_insert_with(|| DbPackage {
+                repository: Some(repository.to_string()),
+                explicit: true,
+                ..Default::default()
+            });
+        parse_desc(&text, pkg);
+    }
+
+    Ok(packages.into_values().collect())
+}
+
+/// Opens file for reading, gzip and zstd compressed files (detected by magic bytes) are decompressed
+pub fn decompress(path: &Path) -> Result<Box<dyn Read>> {
+    let mut magic = [0u8; 4];
+    let read = File::open(path)?.read(&mut magic)?;
+    let file = BufReader::new(File::open(path)?);
+
+    Ok(match &magic[..read] {
+        [0x1f, 0x8b, ..] => Box::new(flate2::read::GzDecoder::new(file)),
+        [0x28, 0xb5, 0x2f, 0xfd] => Box::new(
+            ruzstd::decoding::StreamingDecoder::new(file)
+                .map_err(|e| anyhow::anyhow!("invalid zstd stream: {e}"))?,
+        ),
+        _ => Box::new(file),
+    })
+}
+
+/// Parses `%KEY%` sections separated by empty lines into `pkg`, unknown sections are ignored
+fn parse_desc(text: &str, pkg: &mut DbPackage) {
+    let mut key = "";
+    for line in text.lines() {
+        if line.starts_with('%') && line.ends_with('%') && line.len() > 1 {
+            key = line.trim_matches('%');
+            continue;
+        }
+        if line.is_empty() {
+            key = "";
+            continue;
+        }
+        match key {
+            "NAME" => pkg.name = line.to_string(),
+            "VERSION" => pkg.version = line.to_string(),
+            "INSTALLDATE" => pkg.install_date = line.parse().ok(),
+            // 0 is explicit, 1 is dependency
+            "REASON" => pkg.explicit = line != "1",
+            "DEPENDS" => pkg.depends.push(line.to_string()),
+            "PROVIDES" => pkg.provides.push(line.to_string()),
+            "OPTDEPENDS" => pkg.optional_deps.push(dep_name(line).to_string()),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const LOCAL_DESC: &str = "%NAME%
+python-foo
+
+%VERSION%
+1.2.3-1
+
+%DESC%
+Foo library
+
+%INSTALLDATE%
+1700000000
+
+%REASON%
+1
+
+%PROVIDES%
+foo=1.2.3
+libfoo.so=1-64
+
+%DEPENDS%
+python>=3.12
+glibc
+
+%OPTDEPENDS%
+python-bar: bar support
+python-baz>=2.0: baz support
+libx
+
+";
+
+    const SYNC_DESC: &str = "%FILENAME%
+bar-2.0-1-x86_64.pkg.tar.zst
+
+%NAME%
+bar
+
+%VERSION%
+2.0-1
+
+%PROVIDES%
+bar-virtual
+
+";
+
+    /// `depends` entry of sync databases created by old pacman versions
+    const SYNC_DEPENDS: &str = "%DEPENDS%
+glibc
+
+%OPTDEPENDS%
+python: scripts
+";
+
+    fn parsed(text: &str) -> DbPackage {
+        let mut pkg = DbPackage {
+            explicit: true,
+            ..Default::default()
+        };
+        parse_desc(text, &mut pkg);
+        pkg
+    }
+
+    fn write(path: &Path, content: &[u8]) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    /// Tarball with `(path, content)` entries
+    fn tarball(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, content) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, content.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn desc_sections_are_parsed() {
+        let pkg = parsed(LOCAL_DESC);
+        assert_eq!(pkg.name, "python-foo");
+        assert_eq!(pkg.version, "1.2.3-1");
+        assert_eq!(pkg.install_date, Some(1700000000));
+        assert!(!pkg.explicit);
+        assert_eq!(pkg.provides, ["foo=1.2.3", "libfoo.so=1-64"]);
+        assert_eq!(pkg.depends, ["python>=3.12", "glibc"]);
+        assert_eq!(pkg.optional_deps, ["python-bar", "python-baz", "libx"]);
+        assert!(pkg.satisfies("foo"));
+        assert!(pkg.satisfies("libfoo.so"));
+        assert!(!pkg.satisfies("python"));
+    }
+
+    #[test]
+    fn malformed_desc_keeps_what_can_be_read() {
+        let pkg =
+            parsed("%NAME%\nfoo\n\n%INSTALLDATE%\nyesterday\n\n%REASON%\n0\n\n%UNKNOWN%\nvalue\n");
+        assert_eq!(pkg.name, "foo");
+        assert_eq!(pkg.install_date, None);
+        assert!(pkg.explicit);
+        assert!(pkg.version.is_empty());
+
+        // lines outside of a section and a lone `%` are ignored
+        let pkg = parsed("stray\n%\n%DEPENDS%\nglibc\n\nafter-blank\n");
+        assert!(pkg.name.is_empty());
+        assert_eq!(pkg.depends, ["glibc"]);
+
+        let pkg = parsed("");
+        assert!(pkg.name.is_empty());
+        assert!(pkg.explicit);
+    }
+
+    #[test]
+    fn local_db_skips_entries_without_desc() {
+        let db = TempDir::new().unwrap();
+        let local = db.path().join("local");
+        write(
+            &local.join("python-foo-1.2.3-1/desc"),
+            LOCAL_DESC.as_bytes(),
+        );
+        write(
+            &local.join("bar-2.0-1/desc"),
+            b"%NAME%\nbar\n\n%VERSION%\n2.0-1\n",
+        );
+        write(&local.join("ALPM_DB_VERSION"), b"9\n");
+        fs::create_dir_all(local.join("interrupted-1.0-1")).unwrap();
+
+        let packages = read_local_db(db.path()).unwrap();
+
+        let names: Vec<&str> = packages.iter().map(|pkg| pkg.name.as_str()).collect();
+        assert_eq!(names, ["bar", "python-foo"]);
+        // missing %REASON% means explicitly installed
+        assert!(packages[0].explicit);
+        assert!(!packages[1].explicit);
+        assert_eq!(packages[1].repository, None);
+    }
+
+    #[test]
+    fn missing_local_db_is_an_error() {
+        let db = TempDir::new().unwrap();
+        let err = read_local_db(db.path()).unwrap_err();
+        assert!(err.to_string().contains("failed to read local database"));
+    }
+
+    #[test]
+    fn sync_dbs_are_read_in_repository_order() {
+        let db = TempDir::new().unwrap();
+        let sync = db.path().join("sync");
+        write(
+            &sync.join("extra.db"),
+            &gzip(&tarball(&[
+                ("bar-2.0-1/desc", SYNC_DESC),
+                ("bar-2.0-1/depends", SYNC_DEPENDS),
+                ("bar-2.0-1/files", "%FILES%\nusr/bin/bar\n"),
+            ])),
+        );
+        // uncompressed database
+        write(
+            &sync.join("core.db"),
+            &tarball(&[("glibc-2.40-1/desc", "%NAME%\nglibc\n\n%VERSION%\n2.40-1\n")]),
+        );
+        write(&sync.join("extra.files"), b"not a database");
+        write(&sync.join("core.db.sig"), b"not a database");
+
+        let packages = read_sync_dbs(db.path()).unwrap();
+
+        let names: Vec<(&str, &str)> = packages
+            .iter()
+            .map(|pkg| (pkg.repository.as_deref().unwrap(), pkg.name.as_str()))
+            .collect();
+        assert_eq!(names, [("core", "glibc"), ("extra", "bar")]);
+        let bar = &packages[1];
+        assert_eq!(bar.version, "2.0-1");
+        assert_eq!(bar.provides, ["bar-virtual"]);
+        assert_eq!(bar.depends, ["glibc"]);
+        assert_eq!(bar.optional_deps, ["python"]);
+        assert!(bar.explicit);
+        assert!(bar.satisfies("bar-virtual"));
+    }
+
+    #[test]
+    fn corrupt_sync_db_is_an_error() {
+        let db = TempDir::new().unwrap();
+        let sync = db.path().join("sync");
+        write(
+            &sync.join("core.db"),
+            &gzip(&tarball(&[("a-1-1/desc", "%NAME%\na\n")])),
+        );
+        let mut truncated = gzip(&tarball(&[("b-1-1/desc", "%NAME%\nb\n")]));
+        truncated.truncate(truncated.len() / 2);
+        write(&sync.join("extra.db"), &truncated);
+
+        let err = read_sync_dbs(db.path()).unwrap_err();
+
+        assert!(format!("{err:#}").contains("extra.db"), "{err:#}");
+    }
+
+    #[test]
+    fn dependency_names_drop_constraints_and_descriptions() {
+        assert_eq!(dep_name("python>=3.12"), "python");
+        assert_eq!(dep_name("libfoo.so=1-64"), "libfoo.so");
+        assert_eq!(dep_name("python-bar: bar support"), "python-bar");
+        assert_eq!(dep_name("glibc"), "glibc");
+        assert_eq!(dep_name("libx [installed]"), "libx");
+    }
+}
@@ -1,11 +1,13 @@
 use std::{fs, path::PathBuf};
 
-use anyhow::{Result, bail};
+use anyhow::Result;
 
 use crate::{
+    backend::{self, Backend},
     cli::{self, Cli},
-    config::Config,
-    list_pkgs::collect_documents,
+    config::{Config, DEFAULT_CONFIG},
+    list_pkgs::{collect_documents, declaration_warnings},
+    output::coded,
 };
 use colored::*;
 
@@ -14,6 +16,7 @@ use kdl::KdlDocument;
 pub struct App {
     pub docs: Vec<(PathBuf, KdlDocument)>,
     pub config: Config,
+    pub backend: Box<dyn Backend>,
 }
 
 impl App {
@@ -40,27 +43,31 @@ impl App {
                         if let Some(parent) = config_file.parent() {
                             fs::create_dir_all(parent)?;
                         }
-                        fs::write(&config_file, "")?; // TODO: default config content
-                        println!("Created declaration file at {}", config_file.display());
+                        fs::write(&config_file, DEFAULT_CONFIG)?;
+                        println!("Created configuration file at {}", config_file.display());
                     }
                 }
                 _ => {
-                    bail!(
-                        "{} {}\nPlease create it with {} or choose different file with --cfg or PACDEC_CONFIG environment variable.",
-                        "Config file not found at".red(),
-                        config_file.display().to_string().italic(),
-                        "pacdec gen".blue().bold(),
-                    )
+                    return Err(coded(
+                        "config_not_found",
+                        format!(
+                            "{} {}\nPlease create it with {} or choose different file with --cfg or PACDEC_CONFIG environment variable.",
+                            "Config file not found at".red(),
+                            config_file.display().to_string().italic(),
+                            "pacdec gen".blue().bold(),
+                        ),
+                    ));
                 }
             }
         }
-        let config = Config::default(); // TODO: parse from file
+        let mut config = if config_file.exists() {
+            Config::load(&config_file)?
+        } else {
+            Config::default()
+        };
+        config.apply_cli(cli);
 
-        let declare_file = cli
-            .declare
-            .clone()
-            // .or_else(|| std::env::var("PACDEC_DECLARE").ok().map(PathBuf::from))
-            .unwrap_or(config.declaration_file.clone());
+        let declare_file = config.declaration_file.clone();
         if !declare_file.exists() {
             match cli.command {
                 cli::Commands::Generate(_) => {
@@ -80,17 +87,29 @@ impl App {
                     }
                 }
                 _ => {
-                    bail!(
-                        "{} {}\nPlease choose different file or run {} to create it.",
-                        "Declaration file not found at".red(),
-                        declare_file.display().to_string().italic(),
-                        "pacdec gen".blue().bold()
-                    )
+                    return Err(coded(
+                        "declaration_not_found",
+                        format!(
+                            "{} {}\nPlease choose different file or run {} to create it.",
+                            "Declaration file not found at".red(),
+                            declare_file.display().to_string().italic(),
+                            "pacdec gen".blue().bold()
+                        ),
+                    ));
                 }
             }
         }
+        let docs = match cli.command {
+            // check loads documents itself to report broken includes instead of failing
+            cli::Commands::Check(_) => Vec::new(),
+            _ => collect_documents(&declare_file)?,
+        };
+        for warning in declaration_warnings(&docs) {
+            eprintln!("{}", warning.yellow());
+        }
         Ok(App {
-            docs: collect_documents(&declare_file)?,
+            docs,
+            backend: backend::from_config(&config)?,
             config,
         })
     }
@@ -0,0 +1,108 @@
+use anyhow::Result;
+
+use crate::alpm_db::DbPackage;
+use crate::config::Config;
+#[cfg(any(test, feature = "fake-backend"))]
+use crate::fake_backend::FakeBackend;
+use crate::pacman::PacmanBackend;
+
+/// Flags of an install transaction
+#[derive(Debug, Clone, Default)]
+pub struct InstallOptions<'a> {
+    /// Install with `--asdeps`, otherwise with `--asexplicit`
+    pub as_dependency: bool,
+    /// Do not reinstall up to date packages
+    pub needed: bool,
+    /// Packages not to upgrade in this transaction
+    pub ignore: &'a [String],
+}
+
+/// Package manager pacdec queries and runs transactions with. Transactions respect `dry_run` of config the
+/// backend was created from.
+pub trait Backend: Send + Sync {
+    /// Name shown in messages
+    fn name(&self) -> &str;
+
+    /// Transactions applied so far, only recorded by fake backend
+    #[cfg(test)]
+    fn transactions(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// All installed packages with their install reason, version and dependencies
+    fn query_local(&self) -> Result<Vec<DbPackage>>;
+
+    /// Names of explicitly installed packages
+    fn query_explicit(&self) -> Result<Vec<String>> {
+        Ok(self
+            .query_local()?
+            .into_iter()
+            .filter(|pkg| pkg.explicit)
+            .map(|pkg| pkg.name)
+            .collect())
+    }
+
+    /// Names of all installed packages
+    fn query_installed(&self) -> Result<Vec<String>> {
+        Ok(self
+            .query_local()?
+            .into_iter()
+            .map(|pkg| pkg.name)
+            .collect())
+    }
+
+    /// Names of packages installed as dependencies
+    fn query_dependencies(&self) -> Result<Vec<String>> {
+        Ok(self
+            .query_local()?
+            .into_iter()
+            .filter(|pkg| !pkg.explicit)
+            .map(|pkg| pkg.name)
+            .collect())
+    }
+
+    /// All packages in sync databases as `repo/name`
+    fn list_sync(&self) -> Result<Vec<String>>;
+
+    /// Looks up `pkg` (`name` or `repo/name`) in sync databases, `None` if no repository has it or a
+    /// package providing it
+    fn sync_info(&self, pkg: &str) -> Result<Option<DbPackage>>;
+
+    fn install(&self, pkgs: &[String], options: &InstallOptions) -> Result<()>;
+
+    /// Removes packages with their dependencies not required by other packages
+    fn remove(&self, pkgs: &[String]) -> Result<()>;
+
+    /// Changes install reason of installed packages to dependency
+    fn mark_as_deps(&self, pkgs: &[String]) -> Result<()>;
+
+    /// Changes install reason of installed packages to explicitly installed
+    fn mark_as_explicit(&self, pkgs: &[String]) -> Result<()>;
+
+    /// Information about installed package as printed by `pacman -Qi`
+    fn package_info(&self, pkg: &str) -> Result<String>;
+
+    /// Shell command printing information about package `{}` in fzf preview, `sync` is set when the
+    /// package may not be installed
+    fn preview_command(&self, sync: bool) -> String;
+}
+
+/// Creates backend from `package_manager` config key: `pacman`, `paru`, `yay`, any other pacman compatible
+/// program or `fake:path/to/fixture.kdl` when built with `fake-backend` feature
+pub fn from_config(cfg: &Config) -> Result<Box<dyn Backend>> {
+    Ok(match cfg.package_manager.strip_prefix("fake:") {
+        #[cfg(any(test, feature = "fake-backend"))]
+        Some(fixture) => Box::new(FakeBackend::load(
+            shellexpand::tilde(fixture).as_ref().as_ref(),
+            cfg,
+        )?),
+        #[cfg(not(any(test, feature = "fake-backend")))]
+        Some(_) => {
+            return Err(crate::output::coded(
+                "config_invalid",
+                "fake package manager is only available in builds with the fake-backend feature",
+            ));
+        }
+        None => Box::new(PacmanBackend::new(cfg)),
+    })
+}
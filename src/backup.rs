@@ -0,0 +1,732 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{Context, Result, bail};
+use colored::*;
+
+use crate::app::App;
+use crate::config::{BackupMode, Config};
+use crate::list_pkgs::{collect_packages_from_doc, parse_kdl_document, parse_kdl_source};
+use crate::packages::Package;
+
+/// Snapshot id of basic backups, milliseconds keep changes made within one second apart
+const TIMESTAMP_FORMAT: &str = "%Y%m%d_%H%M%S%3f";
+
+/// Length of a timestamp formatted with `TIMESTAMP_FORMAT`, e.g. "20250101_120000123"
+const TIMESTAMP_FORMAT_LEN: usize = 18;
+
+/// Extension of the file marking a basic snapshot taken by `revert`, it contains id of the reverted snapshot
+const REVERT_MARKER_EXTENSION: &str = "revert";
+
+/// Git trailer of a commit made by `revert` with id of the reverted snapshot
+const REVERT_TRAILER: &str = "Reverts";
+
+/// Format of snapshot ids written by older versions, e.g. "20250101_120000"
+const LEGACY_TIMESTAMP_FORMAT: &str = "%Y%m%d_%H%M%S";
+const LEGACY_TIMESTAMP_FORMAT_LEN: usize = 15;
+
+/// Where the previous content of a declaration file can be read from
+pub enum BackupSource {
+    File(PathBuf),
+    /// File `path` (relative to `repo`) at revision `rev`
+    Git {
+        repo: PathBuf,
+        rev: String,
+        path: String,
+    },
+}
+
+impl BackupSource {
+    pub fn read(&self) -> Result<String> {
+        match self {
+            BackupSource::File(path) => fs::read_to_string(path)
+                .with_context(|| format!("failed to read backup file {}", path.display())),
+            BackupSource::Git { repo, rev, path } => git(repo, &["show", &format!("{rev}:{path}")]),
+        }
+    }
+}
+
+/// State of declaration files before a single `apply_dec_changes` call
+pub struct Snapshot {
+    /// Backup timestamp or git commit hash
+    pub id: String,
+    pub date: String,
+    /// Commit subject, only available in git mode
+    pub message: Option<String>,
+    /// Pairs of (declaration file, its content before the change)
+    pub files: Vec<(PathBuf, BackupSource)>,
+    /// Id of the snapshot this change reverted to, set for changes made by `revert`
+    pub reverted: Option<String>,
+}
+
+/// Backs up declaration files around a single write of changed declarations
+pub struct Transaction<'a> {
+    cfg: &'a Config,
+    timestamp: String,
+    /// Repository top level directory and commit message in git mode
+    git: Option<(PathBuf, String)>,
+    /// Id of the snapshot the change reverts to
+    reverted: Option<String>,
+}
+
+impl<'a> Transaction<'a> {
+    /// Must be called before any declaration file is written
+    pub fn begin(app: &'a App) -> Result<Self> {
+        let git = match app.config.backup.mode {
+            BackupMode::Git => {
+                let repo = git_prepare(app)?;
+                Some((repo, commit_message(app)?))
+            }
+            _ => None,
+        };
+        Ok(Transaction {
+            cfg: &app.config,
+            timestamp: unique_timestamp(app)?,
+            git,
+            reverted: None,
+        })
+    }
+
+    /// Marks the change as revert to the state before snapshot `id`, the next revert steps past both
+    pub fn reverting(mut self, id: &str) -> Self {
+        if let Some((_, message)) = &mut self.git {
+            message.push_str(&format!("\n\n{REVERT_TRAILER}: {id}"));
+        }
+        self.reverted = Some(id.to_string());
+        self
+    }
+
+    pub fn backup(&self, path: &Path) -> Result<()> {
+        match self.cfg.backup.mode {
+            BackupMode::Basic if path.exists() => {
+                let backup_dir = backup_dir(self.cfg, path)?;
+                fs::create_dir_all(&backup_dir)?;
+
+                let file_name = path
+                    .file_name()
+                    .context("failed to get file name for backup")?;
+                let backup_path = backup_dir.join(format!(
+                    "{}_{}",
+                    self.timestamp,
+                    file_name.to_string_lossy()
+                ));
+
+                // existing backup holds an older state, it must not be lost
+                let mut backup = fs::File::create_new(&backup_path).with_context(|| {
+                    format!("failed to create backup file {}", backup_path.display())
+                })?;
+                io::copy(&mut fs::File::open(path)?, &mut backup).with_context(|| {
+                    format!(
+                        "failed to backup file {} to {}",
+                        path.display(),
+                        backup_path.display()
+                    )
+                })?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Must be called after all changed files are written
+    pub fn finish(self, written: &[&PathBuf]) -> Result<()> {
+        if let Some((repo, message)) = &self.git {
+            git_commit(repo, &tracked_files(repo, written.iter().copied()), message)?;
+        }
+        if let (BackupMode::Basic, Some(reverted)) = (&self.cfg.backup.mode, &self.reverted) {
+            let mut dirs = HashSet::new();
+            for file in written {
+                dirs.insert(backup_dir(self.cfg, file)?);
+            }
+            for dir in dirs.into_iter().filter(|dir| dir.is_dir()) {
+                let marker = dir.join(format!("{}.{REVERT_MARKER_EXTENSION}", self.timestamp));
+                fs::write(&marker, reverted).with_context(|| {
+                    format!("failed to write revert marker {}", marker.display())
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Current time formatted with `TIMESTAMP_FORMAT`, moved forward while any backup directory of
+/// declaration files already has a snapshot with this id
+fn unique_timestamp(app: &App) -> Result<String> {
+    let mut used = HashSet::new();
+    for (file, _) in &app.docs {
+        let Ok(entries) = fs::read_dir(backup_dir(&app.config, file)?) else {
+            continue;
+        };
+        for entry in entries {
+            if let Some((timestamp, _)) = split_backup_name(&entry?.file_name().to_string_lossy()) {
+                used.insert(timestamp.to_string());
+            }
+        }
+    }
+
+    let mut time = chrono::Local::now().naive_local();
+    loop {
+        let timestamp = time.format(TIMESTAMP_FORMAT).to_string();
+        if !used.contains(&timestamp) {
+            return Ok(timestamp);
+        }
+        time += chrono::TimeDelta::milliseconds(1);
+    }
+}
+
+/// Splits backup file name "{timestamp}_{file_name}" into its parts, timestamp is in either format
+fn split_backup_name(name: &str) -> Option<(&str, &str)> {
+    [
+        (TIMESTAMP_FORMAT, TIMESTAMP_FORMAT_LEN),
+        (LEGACY_TIMESTAMP_FORMAT, LEGACY_TIMESTAMP_FORMAT_LEN),
+    ]
+    .into_iter()
+    .find_map(|(format, len)| {
+        let timestamp = name.get(..len)?;
+        let file_name = name.get(len..)?.strip_prefix('_')?;
+        chrono::NaiveDateTime::parse_from_str(timestamp, format).ok()?;
+        Some((timestamp, file_name))
+    })
+}
+
+/// Parses snapshot id in either format
+fn parse_timestamp(timestamp: &str) -> Option<chrono::NaiveDateTime> {
+    chrono::NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT)
+        .or_else(|_| chrono::NaiveDateTime::parse_from_str(timestamp, LEGACY_TIMESTAMP_FORMAT))
+        .ok()
+}
+
+fn backup_dir(cfg: &Config, path: &Path) -> Result<PathBuf> {
+    Ok(path
+        .parent()
+        .context("config file must have a parent directory")?
+        .join(&cfg.backup.dir))
+}
+
+/// Returns snapshots of declaration files, newest first
+pub fn list_snapshots(app: &App) -> Result<Vec<Snapshot>> {
+    match app.config.backup.mode {
+        BackupMode::Git => list_git_snapshots(app),
+        _ => list_file_snapshots(app),
+    }
+}
+
+fn list_file_snapshots(app: &App) -> Result<Vec<Snapshot>> {
+    let mut dirs = HashSet::new();
+    for (file, _) in &app.docs {
+        dirs.insert(backup_dir(&app.config, file)?);
+    }
+
+    let mut snapshots: BTreeMap<String, Vec<(PathBuf, PathBuf)>> = BTreeMap::new();
+    let mut reverted: HashMap<String, String> = HashMap::new();
+    for dir in dirs {
+        if !dir.is_dir() {
+            continue;
+        }
+        let parent = dir
+            .parent()
+            .context("backup directory must have a parent directory")?;
+
+        for entry in fs::read_dir(&dir)? {
+            let backup_path = entry?.path();
+            let Some(name) = backup_path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if let Some(timestamp) = name
+                .strip_suffix(REVERT_MARKER_EXTENSION)
+                .and_then(|name| name.strip_suffix('.'))
+                .filter(|timestamp| parse_timestamp(timestamp).is_some())
+            {
+                let id = fs::read_to_string(&backup_path)?.trim().to_string();
+                reverted.insert(timestamp.to_string(), id);
+                continue;
+            }
+            let Some((timestamp, file_name)) = split_backup_name(name) else {
+                continue;
+            };
+            snapshots
+                .entry(timestamp.to_string())
+                .or_default()
+                .push((parent.join(file_name), backup_path.clone()));
+        }
+    }
+
+    Ok(snapshots
+        .into_iter()
+        .rev()
+        .map(|(timestamp, mut files)| {
+            files.sort();
+            Snapshot {
+                date: parse_timestamp(&timestamp)
+                    .map(|date| date.format("%Y-%m-%d %H:%M:%S").to_string())
+                    .unwrap_or_else(|| timestamp.clone()),
+                reverted: reverted.get(&timestamp).cloned(),
+                id: timestamp,
+                message: None,
+                files: files
+                    .into_iter()
+                    .map(|(file, backup)| (file, BackupSource::File(backup)))
+                    .collect(),
+            }
+        })
+        .collect())
+}
+
+fn list_git_snapshots(app: &App) -> Result<Vec<Snapshot>> {
+    let Some(repo) = git_toplevel(&repo_dir(app)?) else {
+        return Ok(Vec::new());
+    };
+    let files = tracked_files(&repo, app.docs.iter().map(|(file, _)| file));
+    if files.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let format = format!(
+        "--format=%x00%h%x09%p%x09%ct%x09%(trailers:key={REVERT_TRAILER},valueonly,separator=%x2C)%x09%s"
+    );
+    let mut args = vec!["log", &format, "--name-only", "--"];
+    args.extend(files.iter().map(String::as_str));
+    let output = git(&repo, &args)?;
+
+    let mut snapshots = Vec::new();
+    for commit in output
+        .split('\0')
+        .filter(|commit| !commit.trim().is_empty())
+    {
+        let mut lines = commit.lines();
+        let header: Vec<&str> = lines.next().unwrap_or_default().splitn(5, '\t').collect();
+        let [id, parents, time, reverted, subject] = header[..] else {
+            continue;
+        };
+        // root commit only records files that existed before pacdec started tracking them
+        if parents.is_empty() {
+            continue;
+        }
+        let rev = format!("{id}^");
+        let changed = lines
+            .filter(|path| files.iter().any(|file| file == path))
+            .filter(|path| git(&repo, &["cat-file", "-e", &format!("{rev}:{path}")]).is_ok())
+            .map(|path| {
+                (
+                    repo.join(path),
+                    BackupSource::Git {
+                        repo: repo.clone(),
+                        rev: rev.clone(),
+                        path: path.to_string(),
+                    },
+                )
+            })
+            .collect();
+
+        snapshots.push(Snapshot {
+            id: id.to_string(),
+            date: time
+                .parse()
+                .ok()
+                .and_then(|time| chrono::DateTime::from_timestamp(time, 0))
+                .map(|date| {
+                    date.with_timezone(&chrono::Local)
+                        .format("%Y-%m-%d %H:%M:%S")
+                        .to_string()
+                })
+                .unwrap_or_default(),
+            message: Some(subject.to_string()),
+            files: changed,
+            reverted: Some(reverted.to_string()).filter(|reverted| !reverted.is_empty()),
+        });
+    }
+
+    Ok(snapshots)
+}
+
+/// Index of the snapshot `steps` changes back. A revert and the changes it undid are stepped past
+/// together, so consecutive reverts keep going back instead of undoing each other.
+pub fn revert_index(snapshots: &[Snapshot], steps: usize) -> Result<usize> {
+    let mut remaining = steps.max(1);
+    let mut available = 0;
+    let mut idx = 0;
+    while idx < snapshots.len() {
+        let undone = snapshots[idx].reverted.as_ref().and_then(|reverted| {
+            snapshots[idx..]
+                .iter()
+                .position(|snapshot| snapshot.id == *reverted)
+        });
+        if let Some(offset) = undone {
+            idx += offset + 1;
+            continue;
+        }
+        available += 1;
+        remaining -= 1;
+        if remaining == 0 {
+            return Ok(idx);
+        }
+        idx += 1;
+    }
+    bail!("cannot revert {steps} step(s), only {available} change(s) available")
+}
+
+/// Replaces documents in `app.docs` with their state before the `idx`-th newest snapshot.
+/// Files changed by several snapshots in between are restored from the oldest of them.
+pub fn restore_snapshot(app: &mut App, snapshots: &[Snapshot], idx: usize) -> Result<()> {
+    if idx >= snapshots.len() {
+        bail!(
+            "cannot revert {} step(s), only {} backup(s) available",
+            idx + 1,
+            snapshots.len()
+        );
+    }
+
+    let mut restored: HashMap<&PathBuf, &BackupSource> = HashMap::new();
+    for snapshot in snapshots[..=idx].iter().rev() {
+        for (file, source) in &snapshot.files {
+            restored.entry(file).or_insert(source);
+        }
+    }
+
+    for (file, source) in restored {
+        let doc = parse_kdl_source(&source.read()?, file)?;
+        match app.docs.iter_mut().find(|(path, _)| path == file) {
+            Some((_, current)) => *current = doc,
+            None => app.docs.push((file.clone(), doc)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints history of declaration changes recorded in git mode
+pub fn print_git_log(app: &App, limit: Option<usize>) -> Result<()> {
+    if !matches!(app.config.backup.mode, BackupMode::Git) {
+        bail!("history of changes is only recorded with backup mode \"git\"");
+    }
+    let Some(repo) = git_toplevel(&repo_dir(app)?) else {
+        bail!("no changes recorded yet");
+    };
+    let files = tracked_files(&repo, app.docs.iter().map(|(file, _)| file));
+
+    let limit = limit.map(|limit| format!("--max-count={limit}"));
+    // git writes to a pipe, whether output is coloured is decided like for the rest of pacdec output
+    let color = if colored::control::SHOULD_COLORIZE.should_colorize() {
+        "--color=always"
+    } else {
+        "--color=never"
+    };
+    let mut args = vec![
+        "log",
+        color,
+        "--date=format-local:%Y-%m-%d %H:%M:%S",
+        "--format=%C(yellow)%h%Creset %C(blue)%ad%Creset%n%B",
+    ];
+    args.extend(limit.as_deref());
+    args.push("--");
+    args.extend(files.iter().map(String::as_str));
+
+    print!("{}", git(&repo, &args)?);
+    Ok(())
+}
+
+fn git(repo: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(args)
+        .output()
+        .context("failed to run git")?;
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.first().unwrap_or(&""),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Directory of the root declaration file, git repository is created there if it is not inside one already
+fn repo_dir(app: &App) -> Result<PathBuf> {
+    let root = app
+        .config
+        .declaration_file
+        .canonicalize()
+        .unwrap_or_else(|_| app.config.declaration_file.clone());
+    Ok(root
+        .parent()
+        .context("declaration file must have a parent directory")?
+        .to_path_buf())
+}
+
+fn git_toplevel(dir: &Path) -> Option<PathBuf> {
+    git(dir, &["rev-parse", "--show-toplevel"])
+        .ok()
+        .map(|path| PathBuf::from(path.trim()))
+}
+
+/// Paths relative to the repository of declaration files that are inside of it
+fn tracked_files<'p>(repo: &Path, files: impl Iterator<Item = &'p PathBuf>) -> Vec<String> {
+    files
+        .filter_map(|file| match file.strip_prefix(repo) {
+            Ok(path) => Some(path.to_string_lossy().to_string()),
+            Err(_) => {
+                eprintln!(
+                    "{}",
+                    format!(
+                        "{} is outside of git repository {}, it is not tracked",
+                        file.display(),
+                        repo.display()
+                    )
+                    .yellow()
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+/// Makes sure repository exists and commits changes made to declaration files by hand
+fn git_prepare(app: &App) -> Result<PathBuf> {
+    let dir = repo_dir(app)?;
+    let repo = match git_toplevel(&dir) {
+        Some(repo) => repo,
+        None => {
+            git(&dir, &["init", "--quiet"])?;
+            git_toplevel(&dir).context("failed to initialize git repository")?
+        }
+    };
+
+    let files: Vec<String> = tracked_files(&repo, app.docs.iter().map(|(file, _)| file))
+        .into_iter()
+        .filter(|file| repo.join(file).exists())
+        .collect();
+    let mut args = vec!["status", "--porcelain", "--"];
+    args.extend(files.iter().map(String::as_str));
+    if !git(&repo, &args)?.trim().is_empty() {
+        git_commit(&repo, &files, "Manual changes")?;
+    }
+
+    Ok(repo)
+}
+
+fn git_commit(repo: &Path, files: &[String], message: &str) -> Result<()> {
+    if files.is_empty() {
+        return Ok(());
+    }
+    let mut args = vec!["add", "--"];
+    args.extend(files.iter().map(String::as_str));
+    git(repo, &args)?;
+
+    let mut args = Vec::new();
+    // commit even if user has no identity configured
+    if git(repo, &["config", "user.email"]).is_err() {
+        args.extend([
+            "-c",
+            "user.name=pacdec",
+            "-c",
+            "user.email=pacdec@localhost",
+        ]);
+    }
+    args.extend(["commit", "--quiet", "-m", message, "--"]);
+    args.extend(files.iter().map(String::as_str));
+    git(repo, &args)?;
+    Ok(())
+}
+
+/// Commit message with invoked subcommand and packages added to or removed from declaration
+fn commit_message(app: &App) -> Result<String> {
+    let mut before = HashSet::new();
+    let mut after = HashSet::new();
+    for (file, doc) in &app.docs {
+        if file.exists() {
+            collect_packages_from_doc(&parse_kdl_document(file)?, file, None, &mut before)?;
+        }
+        // reparse so that nodes commented out with slashdash are not counted
+        collect_packages_from_doc(
+            &parse_kdl_source(&doc.to_string(), file)?,
+            file,
+            None,
+            &mut after,
+        )?;
+    }
+
+    let mut added: Vec<&Package> = after.difference(&before).collect();
+    let mut removed: Vec<&Package> = before.difference(&after).collect();
+    added.sort();
+    removed.sort();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut message = format!("pacdec {}", args.join(" ")).trim_end().to_string();
+    if !added.is_empty() || !removed.is_empty() {
+        message.push('\n');
+    }
+    for (title, pkgs) in [("Added", added), ("Removed", removed)] {
+        if !pkgs.is_empty() {
+            let pkgs: Vec<String> = pkgs.iter().map(|pkg| pkg.to_string()).collect();
+            message.push_str(&format!("\n{title}: {}", pkgs.join(" ")));
+        }
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kdl_edit::{write_dec_changes, write_reverted_dec_changes};
+    use crate::testing::TestDir;
+
+    const ORIGINAL: &str = "cat:uncat {\n    a\n    b\n    c\n}\n";
+
+    #[test]
+    fn changes_within_one_second_keep_separate_snapshots() {
+        let test = TestDir::new(ORIGINAL, "");
+        let mut app = test.app(&test.cli(&["rm", "a"]));
+        let states = [
+            "cat:uncat {\n    b\n    c\n}\n",
+            "cat:uncat {\n    c\n}\n",
+            "cat:uncat {\n}\n",
+        ];
+        for state in states {
+            app.docs[0].1 = state.parse().unwrap();
+            write_dec_changes(&app).unwrap();
+        }
+
+        let snapshots = list_snapshots(&app).unwrap();
+        assert_eq!(snapshots.len(), states.len());
+        let ids: HashSet<&String> = snapshots.iter().map(|snapshot| &snapshot.id).collect();
+        assert_eq!(ids.len(), states.len());
+
+        restore_snapshot(&mut app, &snapshots, states.len() - 1).unwrap();
+        assert_eq!(app.docs[0].1.to_string(), ORIGINAL);
+    }
+
+    /// Steps `revert_cmd` takes to revert the last change
+    fn revert(app: &mut App) -> Result<()> {
+        let snapshots = list_snapshots(app)?;
+        let idx = revert_index(&snapshots, 1)?;
+        restore_snapshot(app, &snapshots, idx)?;
+        write_reverted_dec_changes(app, &snapshots[idx].id)
+    }
+
+    /// Two changes of `ORIGINAL` reverted one by one, declaration after every revert
+    fn revert_twice(config: &str) -> Vec<String> {
+        let test = TestDir::new(ORIGINAL, "");
+        test.write("config.kdl", config);
+        let mut app = test.app(&test.cli(&["rm", "a"]));
+        for state in ["cat:uncat {\n    b\n    c\n}\n", "cat:uncat {\n    c\n}\n"] {
+            app.docs[0].1 = state.parse().unwrap();
+            write_dec_changes(&app).unwrap();
+        }
+
+        let mut states = Vec::new();
+        for _ in 0..2 {
+            let mut app = test.app(&test.cli(&["revert"]));
+            revert(&mut app).unwrap();
+            states.push(test.read("packages.kdl"));
+        }
+
+        let mut app = test.app(&test.cli(&["revert"]));
+        let error = revert(&mut app).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "cannot revert 1 step(s), only 0 change(s) available"
+        );
+        states
+    }
+
+    #[test]
+    fn consecutive_reverts_keep_going_back() {
+        assert_eq!(
+            revert_twice(""),
+            ["cat:uncat {\n    b\n    c\n}\n", ORIGINAL]
+        );
+    }
+
+    #[test]
+    fn consecutive_reverts_keep_going_back_in_git_mode() {
+        assert_eq!(
+            revert_twice("backup {\n    mode \"git\"\n}\n"),
+            ["cat:uncat {\n    b\n    c\n}\n", ORIGINAL]
+        );
+    }
+
+    #[test]
+    fn reverted_changes_are_skipped_when_counting_steps() {
+        let snapshot = |id: &str, reverted: Option<&str>| Snapshot {
+            id: id.to_string(),
+            date: String::new(),
+            message: None,
+            files: Vec::new(),
+            reverted: reverted.map(str::to_string),
+        };
+        // 5 reverted 4 and 3, 2 and 1 are still in effect
+        let snapshots = [
+            snapshot("5", Some("3")),
+            snapshot("4", None),
+            snapshot("3", None),
+            snapshot("2", None),
+            snapshot("1", None),
+        ];
+
+        assert_eq!(revert_index(&snapshots, 1).unwrap(), 3);
+        assert_eq!(revert_index(&snapshots, 2).unwrap(), 4);
+        assert!(revert_index(&snapshots, 3).is_err());
+        // revert whose snapshot is gone counts as a change
+        assert_eq!(revert_index(&snapshots[..2], 1).unwrap(), 0);
+    }
+
+    #[test]
+    fn existing_backup_is_not_overwritten() {
+        let test = TestDir::new(ORIGINAL, "");
+        let app = test.app(&test.cli(&["list"]));
+        test.write(".backups/20240101_120000000_packages.kdl", "older state");
+        let transaction = Transaction {
+            cfg: &app.config,
+            timestamp: "20240101_120000000".to_string(),
+            git: None,
+            reverted: None,
+        };
+
+        assert!(transaction.backup(&test.path("packages.kdl")).is_err());
+        assert_eq!(
+            test.read(".backups/20240101_120000000_packages.kdl"),
+            "older state"
+        );
+    }
+
+    #[test]
+    fn legacy_snapshot_ids_are_listed_in_order() {
+        let test = TestDir::new(ORIGINAL, "");
+        let app = test.app(&test.cli(&["list"]));
+        test.write(".backups/20240101_120000_packages.kdl", "legacy");
+        test.write(".backups/20240101_120000500_packages.kdl", "new");
+        test.write(".backups/20240101_115959_packages.kdl", "older");
+        test.write(".backups/notes.txt", "not a backup");
+
+        let snapshots = list_snapshots(&app).unwrap();
+        let ids: Vec<&str> = snapshots.iter().map(|s| s.id.as_str()).collect();
+        assert_eq!(
+            ids,
+            ["20240101_120000500", "20240101_120000", "20240101_115959"]
+        );
+        assert_eq!(snapshots[1].date, "2024-01-01 12:00:00");
+        assert_eq!(
+            snapshots[1].files[0].0,
+            test.path("packages.kdl").canonicalize().unwrap()
+        );
+    }
+
+    #[test]
+    fn backup_names_are_split_in_both_formats() {
+        assert_eq!(
+            split_backup_name("20240101_120000123_packages.kdl"),
+            Some(("20240101_120000123", "packages.kdl"))
+        );
+        assert_eq!(
+            split_backup_name("20240101_120000_dev_tools.kdl"),
+            Some(("20240101_120000", "dev_tools.kdl"))
+        );
+        assert_eq!(split_backup_name("20240101_12000_packages.kdl"), None);
+        assert_eq!(split_backup_name("packages.kdl"), None);
+    }
+}
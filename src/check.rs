@@ -0,0 +1,660 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use kdl::KdlNode;
+use miette::{LabeledSpan, MietteDiagnostic, NamedSource, Severity, SourceSpan};
+use rayon::prelude::*;
+
+use crate::app::App;
+use crate::hooks::Hook;
+use crate::kdl_edit::is_reserved_category;
+use crate::list_pkgs::{IncludeProblem, file_category, file_category_arg, node_hosts, source_line};
+use crate::output::{DiagnosticJson, error_code};
+use crate::packages::{Category, Package, PackageOptions};
+
+/// Problem found in declaration files by `pacdec check`
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Stable identifier of the problem, e.g. `duplicate_package`
+    pub code: &'static str,
+    pub message: String,
+    /// Index of the document in `App::docs` and span of the problem, `None` if it can not be pointed at
+    pub location: Option<(usize, SourceSpan)>,
+    pub label: &'static str,
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    fn new(severity: Severity, code: &'static str, message: String) -> Self {
+        Diagnostic {
+            severity,
+            code,
+            message,
+            location: None,
+            label: "",
+            help: None,
+        }
+    }
+
+    fn at(mut self, doc: usize, span: SourceSpan, label: &'static str) -> Self {
+        self.location = Some((doc, span));
+        self.label = label;
+        self
+    }
+
+    fn with_help(mut self, help: String) -> Self {
+        self.help = Some(help);
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /// Miette report with the source snippet the diagnostic points at
+    pub fn render(&self, app: &App) -> String {
+        let mut diagnostic = MietteDiagnostic::new(self.message.clone())
+            .with_severity(self.severity)
+            .with_code(self.code);
+        if let Some(help) = &self.help {
+            diagnostic = diagnostic.with_help(help.clone());
+        }
+        let Some((doc, span)) = self.location else {
+            return format!("{:?}", miette::Report::new(diagnostic));
+        };
+        let (file, doc) = &app.docs[doc];
+        let report = miette::Report::new(diagnostic.with_label(LabeledSpan::at(span, self.label)))
+            .with_source_code(NamedSource::new(
+                file.display().to_string(),
+                doc.to_string(),
+            ));
+        format!("{report:?}")
+    }
+
+    pub fn to_json(&self, app: &App) -> DiagnosticJson {
+        let (file, line) = match self.location {
+            Some((doc, span)) => {
+                let (file, doc) = &app.docs[doc];
+                (
+                    Some(file.clone()),
+                    Some(source_line(&doc.to_string(), span.offset())),
+                )
+            }
+            None => (None, None),
+        };
+        DiagnosticJson {
+            severity: if self.is_error() { "error" } else { "warning" },
+            code: self.code,
+            message: self.message.clone(),
+            file,
+            line,
+            help: self.help.clone(),
+        }
+    }
+}
+
+/// Where a node is placed, decides which directives are allowed in it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Parent {
+    /// Top level of a file
+    Root,
+    Category,
+    Host,
+    Package,
+}
+
+/// Declaration of a package with hosts it is declared for, `None` means every host
+struct Occurrence {
+    pkg: Package,
+    doc: usize,
+    span: SourceSpan,
+    hosts: Option<BTreeSet<String>>,
+}
+
+struct Checker {
+    diagnostics: Vec<Diagnostic>,
+    occurrences: Vec<Occurrence>,
+    /// Explicit tags of packages and categories with their location
+    tags: Vec<(String, usize, SourceSpan)>,
+    categories: HashSet<String>,
+    default_category: Category,
+    /// Path of the category being checked
+    path: Vec<String>,
+}
+
+/// Checks documents of `app` loaded with `collect_documents_lenient`, `problems` are includes which failed
+/// to load. Packages are looked up in repositories unless `offline` is set. Diagnostics are ordered by file
+/// and position.
+pub fn check_declaration(
+    app: &App,
+    problems: Vec<IncludeProblem>,
+    offline: bool,
+) -> Vec<Diagnostic> {
+    let mut checker = Checker {
+        diagnostics: Vec::new(),
+        occurrences: Vec::new(),
+        tags: Vec::new(),
+        categories: HashSet::new(),
+        default_category: app.config.default_category.clone(),
+        path: Vec::new(),
+    };
+
+    for problem in problems {
+        let doc = app.docs.iter().position(|(file, _)| *file == problem.file);
+        let diagnostic = Diagnostic::new(
+            Severity::Error,
+            "include_invalid",
+            format!("{:#}", problem.error),
+        );
+        // parse errors carry their own report pointing into the included file
+        checker.diagnostics.push(match doc {
+            Some(doc) if error_code(&problem.error) != "declaration_invalid" => {
+                diagnostic.at(doc, problem.span, "included here")
+            }
+            _ => diagnostic,
+        });
+    }
+
+    for (doc, (_, kdl)) in app.docs.iter().enumerate() {
+        checker.path = file_category(kdl);
+        checker.categories.extend(checker.path.iter().cloned());
+        let has_packages = checker.check_nodes(doc, kdl.nodes(), Parent::Root, &None);
+        if let Some(name) = checker.path.last()
+            && !has_packages
+            && !checker.default_category.matches(&checker.path)
+            && let Some(node) = kdl.get("@file")
+        {
+            checker.diagnostics.push(
+                Diagnostic::new(
+                    Severity::Warning,
+                    "empty_category",
+                    format!("category '{name}' has no packages"),
+                )
+                .at(doc, node.span(), "empty category"),
+            );
+        }
+    }
+
+    checker.check_duplicates(app);
+    checker.check_tags();
+    if !offline {
+        checker.check_repositories(app);
+    }
+
+    let mut diagnostics = checker.diagnostics;
+    diagnostics
+        .sort_by_key(|diagnostic| diagnostic.location.map(|(doc, span)| (doc, span.offset())));
+    diagnostics
+}
+
+impl Checker {
+    /// Returns whether any package is declared in `nodes`
+    fn check_nodes(
+        &mut self,
+        doc: usize,
+        nodes: &[KdlNode],
+        parent: Parent,
+        hosts: &Option<BTreeSet<String>>,
+    ) -> bool {
+        let mut has_packages = false;
+        for node in nodes {
+            let name = node.name().value();
+            let span = node.name().span();
+            let hosts = match node_hosts(node) {
+                Some(node_hosts) => {
+                    let node_hosts: BTreeSet<String> =
+                        node_hosts.into_iter().map(str::to_string).collect();
+                    Some(match hosts {
+                        Some(hosts) => hosts.intersection(&node_hosts).cloned().collect(),
+                        None => node_hosts,
+                    })
+                }
+                None => hosts.clone(),
+            };
+
+            match name {
+                _ if !name.contains([':', '@']) => {
+                    has_packages = true;
+                    self.add_tags(doc, node);
+                    self.occurrences.push(Occurrence {
+                        pkg: Package::from_str(name),
+                        doc,
+                        span,
+                        hosts: hosts.clone(),
+                    });
+                    if let Some(children) = node.children() {
+                        self.check_nodes(doc, children.nodes(), Parent::Package, &hosts);
+                    }
+                }
+                "@include" | "@file" if parent != Parent::Root => self.warn(
+                    doc,
+                    span,
+                    "ignored",
+                    "misplaced_directive",
+                    format!("{name} is only allowed at top level of a file and is ignored here"),
+                ),
+                "@include" | "@file" => {
+                    let is_string = matches!(node.entries(), [entry] if entry.value().is_string());
+                    if !is_string {
+                        self.error(
+                            doc,
+                            node.span(),
+                            "expected a path",
+                            "directive_invalid",
+                            format!("{name} takes exactly one string argument"),
+                        );
+                    } else if name == "@file" && file_category_arg(node).is_none() {
+                        self.warn(
+                            doc,
+                            node.span(),
+                            "ignored",
+                            "directive_invalid",
+                            "@file expects 'cat:name' argument and is ignored".to_string(),
+                        );
+                    }
+                }
+                _ if parent == Parent::Root && is_reserved_category(name) => {}
+                _ if name.starts_with("cat:") => {
+                    let category = name.trim_start_matches("cat:");
+                    self.categories.insert(category.to_string());
+                    self.add_tags(doc, node);
+                    let children = node.children().map_or(&[][..], |children| children.nodes());
+                    self.path.push(category.to_string());
+                    let category_has_packages =
+                        self.check_nodes(doc, children, Parent::Category, &hosts);
+                    let is_default = self.default_category.matches(&self.path);
+                    self.path.pop();
+                    if !category_has_packages && !is_default {
+                        self.warn(
+                            doc,
+                            span,
+                            "empty category",
+                            "empty_category",
+                            format!("category '{category}' has no packages"),
+                        );
+                    }
+                    has_packages |= category_has_packages;
+                }
+                _ if name.starts_with("host:") => {
+                    if let Some(children) = node.children() {
+                        has_packages |=
+                            self.check_nodes(doc, children.nodes(), Parent::Host, &hosts);
+                    }
+                }
+                _ if name.starts_with("hook:") => {
+                    if let Err(e) = Hook::try_from(node) {
+                        self.error(doc, node.span(), "invalid hook", "hook_invalid", e);
+                    } else if !matches!(parent, Parent::Category | Parent::Package) {
+                        self.warn(
+                            doc,
+                            span,
+                            "ignored",
+                            "misplaced_directive",
+                            format!("{name} only applies inside a package or category and is ignored here"),
+                        );
+                    }
+                }
+                ":options" if parent != Parent::Package => self.warn(
+                    doc,
+                    span,
+                    "ignored",
+                    "misplaced_directive",
+                    ":options only applies inside a package and is ignored here".to_string(),
+                ),
+                ":options" => {
+                    if let Err(e) = PackageOptions::try_from(node) {
+                        self.error(doc, node.span(), "invalid options", "options_invalid", e);
+                    }
+                }
+                _ => self.error(
+                    doc,
+                    span,
+                    "unknown directive",
+                    "unknown_directive",
+                    format!("unknown directive '{name}'"),
+                ),
+            }
+        }
+        has_packages
+    }
+
+    /// Records tags of `node`, same as `node_tags` but with their spans
+    fn add_tags(&mut self, doc: usize, node: &KdlNode) {
+        for entry in node.entries().iter().filter(|entry| entry.name().is_none()) {
+            if let Some(tag) = entry.value().as_string() {
+                self.tags.push((tag.to_string(), doc, entry.span()));
+            }
+        }
+    }
+
+    fn error(
+        &mut self,
+        doc: usize,
+        span: SourceSpan,
+        label: &'static str,
+        code: &'static str,
+        message: String,
+    ) {
+        self.diagnostics
+            .push(Diagnostic::new(Severity::Error, code, message).at(doc, span, label));
+    }
+
+    fn warn(
+        &mut self,
+        doc: usize,
+        span: SourceSpan,
+        label: &'static str,
+        code: &'static str,
+        message: String,
+    ) {
+        self.diagnostics
+            .push(Diagnostic::new(Severity::Warning, code, message).at(doc, span, label));
+    }
+
+    /// Package declared twice for the same host would be installed with options and hooks of either
+    fn check_duplicates(&mut self, app: &App) {
+        let mut by_name: HashMap<&str, Vec<&Occurrence>> = HashMap::new();
+        for occurrence in &self.occurrences {
+            let previous = by_name.entry(&occurrence.pkg.name).or_default();
+            let overlapping =
+                previous
+                    .iter()
+                    .find(|previous| match (&previous.hosts, &occurrence.hosts) {
+                        (Some(a), Some(b)) => !a.is_disjoint(b),
+                        _ => true,
+                    });
+            if let Some(first) = overlapping {
+                self.diagnostics.push(
+                    Diagnostic::new(
+                        Severity::Error,
+                        "duplicate_package",
+                        format!(
+                            "package '{}' is declared more than once",
+                            occurrence.pkg.name
+                        ),
+                    )
+                    .at(occurrence.doc, occurrence.span, "declared again")
+                    .with_help(format!(
+                        "first declared at {}",
+                        location(app, first.doc, first.span)
+                    )),
+                );
+            }
+            previous.push(occurrence);
+        }
+    }
+
+    /// Categories add an implicit tag with their name, an explicit tag with the same name selects packages
+    /// of both
+    fn check_tags(&mut self) {
+        for (tag, doc, span) in std::mem::take(&mut self.tags) {
+            if self.categories.contains(&tag) {
+                self.diagnostics.push(
+                    Diagnostic::new(
+                        Severity::Warning,
+                        "tag_is_category",
+                        format!("tag '{tag}' has the same name as a category"),
+                    )
+                    .at(doc, span, "tag collides with category")
+                    .with_help(format!(
+                        "--tags {tag} selects these packages together with packages of category '{tag}'"
+                    )),
+                );
+            }
+        }
+    }
+
+    fn check_repositories(&mut self, app: &App) {
+        let mut seen = HashSet::new();
+        let unique: Vec<&Occurrence> = self
+            .occurrences
+            .iter()
+            .filter(|occurrence| seen.insert(occurrence.pkg.to_string()))
+            .collect();
+        let found: Vec<Diagnostic> = unique
+            .par_iter()
+            .filter_map(|occurrence| {
+                let pkg = &occurrence.pkg;
+                if let Ok(Some(_)) = app.backend.sync_info(&pkg.to_string()) {
+                    return None;
+                }
+                let repository = pkg.repository.as_ref().and_then(|repository| {
+                    let Ok(Some(info)) = app.backend.sync_info(&pkg.name) else {
+                        return None;
+                    };
+                    Some((
+                        repository,
+                        info.repository.unwrap_or_else(|| "AUR".to_string()),
+                    ))
+                });
+                Some(match repository {
+                    Some((declared, actual)) => Diagnostic::new(
+                        Severity::Error,
+                        "repository_mismatch",
+                        format!("package '{}' is not in repository '{declared}'", pkg.name),
+                    )
+                    .at(occurrence.doc, occurrence.span, "wrong repository")
+                    .with_help(format!(
+                        "it is in '{actual}', use \"{actual}/{}\"",
+                        pkg.name
+                    )),
+                    None => Diagnostic::new(
+                        Severity::Error,
+                        "package_not_found",
+                        format!("package '{pkg}' was not found by {}", app.backend.name()),
+                    )
+                    .at(occurrence.doc, occurrence.span, "unknown package"),
+                })
+            })
+            .collect();
+        self.diagnostics.extend(found);
+    }
+}
+
+/// `file:line` of `span` in document `doc`
+fn location(app: &App, doc: usize, span: SourceSpan) -> String {
+    let (file, doc) = &app.docs[doc];
+    format!(
+        "{}:{}",
+        file.display(),
+        source_line(&doc.to_string(), span.offset())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::list_pkgs::collect_documents_lenient;
+    use crate::testing::TestDir;
+
+    const FIXTURE: &str = r#"sync {
+    core {
+        git
+        vim
+    }
+    extra {
+        htop
+    }
+}
+"#;
+
+    /// `line code: message` of diagnostics found in `files` written next to `packages.kdl`
+    fn check(declaration: &str, files: &[(&str, &str)], offline: bool) -> Vec<String> {
+        let test = TestDir::new(declaration, FIXTURE);
+        for (name, content) in files {
+            test.write(name, content);
+        }
+        let mut app = test.app(&test.cli(&["check"]));
+        let mut problems = Vec::new();
+        app.docs = collect_documents_lenient(&app.config.declaration_file, &mut problems).unwrap();
+        check_declaration(&app, problems, offline)
+            .iter()
+            .map(|diagnostic| {
+                let json = diagnostic.to_json(&app);
+                format!("{} {}: {}", json.line.unwrap_or(0), json.code, json.message)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn duplicates_are_reported_for_overlapping_hosts_only() {
+        let declaration = r#"cat:a {
+    git hosts="desktop,laptop"
+    vim hosts="desktop"
+    htop
+}
+cat:b {
+    git hosts="laptop"
+    vim hosts="laptop"
+    htop hosts="server"
+}
+"#;
+        assert_eq!(
+            check(declaration, &[], true),
+            [
+                "7 duplicate_package: package 'git' is declared more than once",
+                "9 duplicate_package: package 'htop' is declared more than once",
+            ]
+        );
+    }
+
+    #[test]
+    fn misplaced_directives_are_warned_about() {
+        let declaration = r#"hook:after_install "true"
+cat:a {
+    git {
+        @include "other.kdl"
+    }
+    :options {
+        needed #true
+    }
+}
+"#;
+        assert_eq!(
+            check(declaration, &[], true),
+            [
+                "1 misplaced_directive: hook:after_install only applies inside a package or category and is ignored here",
+                "4 misplaced_directive: @include is only allowed at top level of a file and is ignored here",
+                "6 misplaced_directive: :options only applies inside a package and is ignored here",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_directives_are_reported() {
+        let files = [("dev.kdl", "@file \"dev\"\ngit\n")];
+        assert_eq!(
+            check("@include \"dev.kdl\"\n@include 1\nvim\n", &files, true),
+            [
+                "1 directive_invalid: @file expects 'cat:name' argument and is ignored",
+                "2 directive_invalid: @include takes exactly one string argument",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_hooks_and_options_are_errors() {
+        let declaration = r#"cat:a {
+    git {
+        hook:after_install 1
+        :options {
+            reason "maybe"
+        }
+    }
+    vim {
+        hook:before_remove "a" "b"
+        :options {
+            colour "blue"
+        }
+    }
+    htop {
+        :options {
+            needed "yes"
+        }
+    }
+}
+"#;
+        assert_eq!(
+            check(declaration, &[], true),
+            [
+                "3 hook_invalid: hook:after_install command must be a string",
+                "4 options_invalid: unknown install reason 'maybe'",
+                "9 hook_invalid: hook:before_remove takes exactly one command",
+                "10 options_invalid: unknown option 'colour'",
+                "15 options_invalid: option 'needed' must be #true or #false",
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_categories_are_warned_about_except_default() {
+        let declaration = r#"@include "empty.kdl"
+@include "full.kdl"
+cat:uncat {}
+cat:a {
+    cat:b {}
+    host:laptop {
+        git
+    }
+}
+cat:c {
+    host:laptop {}
+}
+"#;
+        let files = [
+            ("empty.kdl", "@file \"cat:e\"\n"),
+            ("full.kdl", "@file \"cat:f\"\nvim\n"),
+        ];
+        assert_eq!(
+            check(declaration, &files, true),
+            [
+                "1 empty_category: category 'e' has no packages",
+                "5 empty_category: category 'b' has no packages",
+                "10 empty_category: category 'c' has no packages",
+            ]
+        );
+    }
+
+    #[test]
+    fn tags_named_like_categories_are_warned_about() {
+        let declaration = r#"cat:dev {
+    git "web"
+    vim "editor"
+}
+cat:web "dev" {
+    htop
+}
+"#;
+        assert_eq!(
+            check(declaration, &[], true),
+            [
+                "2 tag_is_category: tag 'web' has the same name as a category",
+                "5 tag_is_category: tag 'dev' has the same name as a category",
+            ]
+        );
+    }
+
+    #[test]
+    fn packages_are_looked_up_in_repositories_unless_offline() {
+        let declaration = r#"cat:a {
+    "core/git"
+    "core/htop"
+    "aur/vim"
+    nonexistent
+}
+"#;
+        assert!(check(declaration, &[], true).is_empty());
+        let diagnostics = check(declaration, &[], false);
+        assert_eq!(
+            diagnostics[..2],
+            [
+                "3 repository_mismatch: package 'htop' is not in repository 'core'",
+                "4 repository_mismatch: package 'vim' is not in repository 'aur'",
+            ]
+        );
+        assert_eq!(diagnostics.len(), 3);
+        assert!(
+            diagnostics[2]
+                .starts_with("5 package_not_found: package 'nonexistent' was not found by fake:")
+        );
+    }
+}
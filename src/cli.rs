@@ -1,7 +1,10 @@
+use chrono::NaiveDate;
 use clap::{Args, Parser, Subcommand};
 use std::path::PathBuf;
 
-use crate::packages::{Category, Package};
+use crate::config::{PackageOrder, RemovalStrategy};
+use crate::output::OutputFormat;
+use crate::packages::{Category, Package, TagSelector};
 
 /// Declarative Package Manager
 #[derive(Parser, Debug)]
@@ -20,8 +23,48 @@ pub struct Cli {
     pub declare: Option<PathBuf>,
 
     /// Path to pacman log file
-    #[arg(long = "log-file", global = true)]
+    #[arg(long = "log-file", global = true, env = "PACDEC_LOG_FILE")]
     pub pacman_log_file: Option<PathBuf>,
+
+    /// Path to pacman database directory
+    #[arg(long = "db-path", global = true, env = "PACDEC_DB_PATH")]
+    pub db_path: Option<PathBuf>,
+
+    /// Package manager to use (pacman, paru, yay)
+    #[arg(
+        alias = "pm",
+        long = "package-manager",
+        global = true,
+        env = "PACDEC_PACKAGE_MANAGER"
+    )]
+    pub package_manager: Option<String>,
+
+    /// Host to use for per-host declarations instead of /etc/hostname
+    #[arg(long, global = true, env = "PACDEC_HOST")]
+    pub host: Option<String>,
+
+    /// Apply changes even if dry run is enabled in config
+    #[arg(long, global = true, env = "PACDEC_NO_DRY_RUN")]
+    pub no_dry_run: bool,
+
+    /// Print executed commands and declaration diffs
+    #[arg(short, long, global = true, conflicts_with = "quiet")]
+    pub verbose: bool,
+
+    /// Do not print executed commands and declaration diffs
+    #[arg(short, long, global = true)]
+    pub quiet: bool,
+
+    /// Output format. With json, read-only commands print a single json document, sync and generate only
+    /// report the diff without applying it
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t,
+        env = "PACDEC_OUTPUT"
+    )]
+    pub output: OutputFormat,
 }
 
 #[derive(Subcommand, Debug)]
@@ -48,12 +91,58 @@ pub enum Commands {
     #[command(alias = "unins")]
     Uninstall(UninstallArgs),
 
+    /// Move package(s) to another category (alias: mv)
+    #[command(alias = "mv")]
+    Move(MoveArgs),
+
+    /// Rename, delete, merge categories or move them to separate files
+    Cat(CatArgs),
+
     /// Interactive search for packages. For installed packages if no flags specified
     Search(SearchArgs),
 
     /// Revert last changes (alias: undo)
     #[command(alias = "undo")]
     Revert(RevertArgs),
+
+    /// Show history of declaration changes (requires git backup mode)
+    Log(LogArgs),
+
+    /// Show installs, removals and upgrades from pacman log with declaration categories
+    History(HistoryArgs),
+
+    /// Show packages declared for each host
+    Hosts(HostsArgs),
+
+    /// List declared packages with their tags and categories (alias: ls)
+    #[command(alias = "ls")]
+    List(ListArgs),
+
+    /// Show declared packages nested by categories and subpackages
+    Tree(TreeArgs),
+
+    /// Check declaration files for mistakes, exits with non-zero code if errors are found
+    Check(CheckArgs),
+
+    /// Format declaration files
+    Fmt(FmtArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct TagFilterArgs {
+    /// Only packages with these tags, prefix tag with ! to exclude it (e.g. dev,!gui)
+    #[arg(long = "tags", value_delimiter = ',', allow_hyphen_values = true)]
+    pub tags: Vec<String>,
+
+    /// Exclude packages with these tags
+    #[arg(long = "exclude-tags", value_delimiter = ',')]
+    pub exclude_tags: Vec<String>,
+}
+
+impl TagFilterArgs {
+    pub fn selector(&self) -> TagSelector {
+        TagSelector::new(&self.tags, &self.exclude_tags)
+    }
 }
 
 #[derive(Args, Debug)]
@@ -65,6 +154,13 @@ pub struct SyncArgs {
     /// Force sync, ignore warnings
     #[arg(short, long)]
     pub force: bool,
+
+    /// Also remove dependencies no package needs anymore, except `keep_orphans` from config
+    #[arg(long)]
+    pub prune_orphans: bool,
+
+    #[command(flatten)]
+    pub filter: TagFilterArgs,
 }
 
 #[derive(Args, Debug)]
@@ -76,6 +172,14 @@ pub struct GenerateArgs {
     /// Force sync, ignore warnings
     #[arg(short, long)]
     pub force: bool,
+
+    /// Decide interactively what to do with each undeclared package: add it to a category, ignore it, mark
+    /// it as dependency or uninstall it
+    #[arg(short, long)]
+    pub triage: bool,
+
+    #[command(flatten)]
+    pub filter: TagFilterArgs,
 }
 
 #[derive(Args, Debug)]
@@ -87,6 +191,10 @@ pub struct AddArgs {
     #[arg(short = 'c', long = "cat")]
     pub category: Option<Category>,
 
+    /// Declaration file to add to when category is declared in several files
+    #[arg(long = "cat-file")]
+    pub cat_file: Option<PathBuf>,
+
     /// Tags for the package (comma separated)
     #[arg(short = 't', long = "tag", value_delimiter = ',')]
     pub tags: Option<Vec<String>>,
@@ -101,6 +209,10 @@ pub struct InstallArgs {
     #[arg(short = 'c', long = "cat")]
     pub category: Option<Category>,
 
+    /// Declaration file to add to when category is declared in several files
+    #[arg(long = "cat-file")]
+    pub cat_file: Option<PathBuf>,
+
     /// Tags for the package (comma separated)
     #[arg(short = 't', long = "tag", value_delimiter = ',')]
     pub tags: Option<Vec<String>>,
@@ -111,9 +223,13 @@ pub struct RemoveArgs {
     /// Package(s) to remove (interactive picker if omitted)
     pub packages: Option<Vec<Package>>,
 
-    /// Comment out package(s) instead of deleting
+    /// Comment out package(s) instead of deleting (same as --strategy comment)
     #[arg(long)]
     pub comment: bool,
+
+    /// How to take package(s) out of declaration, `removal` config key by default
+    #[arg(long, value_enum, conflicts_with = "comment")]
+    pub strategy: Option<RemovalStrategy>,
 }
 
 #[derive(Args, Debug)]
@@ -121,9 +237,122 @@ pub struct UninstallArgs {
     /// Package(s) to uninstall (interactive picker if omitted)
     pub packages: Option<Vec<Package>>,
 
-    /// Comment out package(s) instead of deleting
+    /// Comment out package(s) instead of deleting (same as --strategy comment)
     #[arg(long)]
     pub comment: bool,
+
+    /// How to take package(s) out of declaration, `removal` config key by default
+    #[arg(long, value_enum, conflicts_with = "comment")]
+    pub strategy: Option<RemovalStrategy>,
+}
+
+#[derive(Args, Debug)]
+pub struct MoveArgs {
+    /// Package(s) to move (interactive picker if omitted and no --from-cat or --tags is given)
+    pub packages: Option<Vec<Package>>,
+
+    /// Move packages of this category (not of its subcategories)
+    #[arg(long = "from-cat")]
+    pub from_category: Option<Category>,
+
+    /// Target category (interactive picker if omitted)
+    #[arg(short = 'c', long = "to-cat", alias = "cat")]
+    pub category: Option<Category>,
+
+    /// Declaration file of target category when it is declared in several files
+    #[arg(long = "cat-file")]
+    pub cat_file: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub filter: TagFilterArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct CatArgs {
+    #[command(subcommand)]
+    pub command: CatCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CatCommands {
+    /// Rename category, its subcategories and packages keep their place
+    Rename(CatRenameArgs),
+
+    /// Delete category with its content (alias: rm)
+    #[command(alias = "rm")]
+    Remove(CatRemoveArgs),
+
+    /// Move content of a category to another category and delete it
+    Merge(CatMergeArgs),
+
+    /// Move category to a new declaration file and include it
+    Extract(CatExtractArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct CatRenameArgs {
+    /// Category to rename
+    pub category: Category,
+
+    /// New name of the category (without path)
+    pub name: String,
+
+    /// Declaration file category is declared in, when it exists in several places
+    #[arg(long = "cat-file")]
+    pub cat_file: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct CatRemoveArgs {
+    /// Category to delete
+    pub category: Category,
+
+    /// Move packages and subcategories to this category instead of deleting them
+    #[arg(long = "move-to", conflicts_with_all = ["uninstall", "keep_installed"])]
+    pub move_to: Option<Category>,
+
+    /// Uninstall packages of the category
+    #[arg(long, conflicts_with = "keep_installed")]
+    pub uninstall: bool,
+
+    /// Only delete packages from declaration, they stay installed until next sync
+    #[arg(long)]
+    pub keep_installed: bool,
+
+    /// How to take category out of declaration, `removal` config key by default
+    #[arg(long, value_enum)]
+    pub strategy: Option<RemovalStrategy>,
+
+    /// Declaration file category is declared in, when it exists in several places
+    #[arg(long = "cat-file")]
+    pub cat_file: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct CatMergeArgs {
+    /// Category to merge, it is deleted afterwards
+    pub category: Category,
+
+    /// Category to move content to
+    pub into: Category,
+
+    /// Declaration file of target category when it is declared in several files
+    #[arg(long = "cat-file")]
+    pub cat_file: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct CatExtractArgs {
+    /// Category to move
+    pub category: Category,
+
+    /// New declaration file, included from the file category is declared in. Relative path is relative to
+    /// that file like `@include`.
+    pub file: PathBuf,
+
+    /// Declaration file category is declared in, when it exists in several places
+    #[arg(long = "cat-file")]
+    pub cat_file: Option<PathBuf>,
 }
 
 #[derive(Args, Debug)]
@@ -139,7 +368,118 @@ pub struct SearchArgs {
     /// List explicitly installed packages ordered by time using pacman log
     #[arg(short, long)]
     pub chronological: bool,
+
+    #[command(flatten)]
+    pub filter: TagFilterArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct RevertArgs {
+    /// Number of changes to revert
+    #[arg(short, long, default_value_t = 1, conflicts_with = "to")]
+    pub steps: usize,
+
+    /// Revert to the state before change with given id (as shown by --list)
+    #[arg(long)]
+    pub to: Option<String>,
+
+    /// List available backups
+    #[arg(short, long)]
+    pub list: bool,
+
+    /// Also install/uninstall packages to match reverted declaration
+    #[arg(long)]
+    pub system: bool,
 }
 
 #[derive(Args, Debug)]
-pub struct RevertArgs {}
+pub struct LogArgs {
+    /// Show only last N changes
+    #[arg(short = 'n', long)]
+    pub limit: Option<usize>,
+}
+
+#[derive(Args, Debug)]
+pub struct HistoryArgs {
+    /// Only events of these package(s)
+    pub packages: Option<Vec<Package>>,
+
+    /// Only events of packages declared in this category or its subcategories
+    #[arg(short = 'c', long = "cat")]
+    pub category: Option<Category>,
+
+    /// Only events on or after this date (YYYY-MM-DD)
+    #[arg(long)]
+    pub since: Option<NaiveDate>,
+
+    /// Only events on or before this date (YYYY-MM-DD)
+    #[arg(long)]
+    pub until: Option<NaiveDate>,
+
+    /// Only packages missing from declaration
+    #[arg(short, long)]
+    pub undeclared: bool,
+
+    /// Also show packages installed as dependencies
+    #[arg(short, long)]
+    pub all: bool,
+
+    /// Show only last N events
+    #[arg(short = 'n', long)]
+    pub limit: Option<usize>,
+
+    #[command(flatten)]
+    pub filter: TagFilterArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct HostsArgs {
+    /// Host(s) to show, all hosts mentioned in declaration if omitted
+    pub hosts: Option<Vec<String>>,
+
+    /// List all packages of each host, not only host specific ones
+    #[arg(short, long)]
+    pub all: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct ListArgs {
+    /// List category tree instead of packages
+    #[arg(long)]
+    pub categories: bool,
+
+    #[command(flatten)]
+    pub filter: TagFilterArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct TreeArgs {
+    #[command(flatten)]
+    pub filter: TagFilterArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct CheckArgs {
+    /// Do not look packages up in repositories
+    #[arg(long)]
+    pub offline: bool,
+
+    /// Treat warnings as errors
+    #[arg(long)]
+    pub strict: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct FmtArgs {
+    /// Only show files that are not formatted, exit with code 2 if there are any
+    #[arg(long)]
+    pub check: bool,
+
+    /// Spaces per indentation level
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=16))]
+    pub indent: Option<u8>,
+
+    /// Order of packages in categories
+    #[arg(long)]
+    pub order: Option<PackageOrder>,
+}
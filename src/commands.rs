@@ -1,43 +1,70 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
 use anyhow::{Result, bail};
 use colored::*;
 use inquire::Confirm;
 use rayon::prelude::*;
 
 use crate::app::App;
+use crate::backend::InstallOptions;
+use crate::backup::{list_snapshots, print_git_log, restore_snapshot, revert_index};
+use crate::check::check_declaration;
 use crate::cli::*;
-use crate::config::Config;
-use crate::kdl_edit::{add_pkgs, apply_dec_changes, remove_pkgs};
-use crate::list_pkgs::get_pkg_diff;
-use crate::packages::{Package, PackageJoin};
-use crate::pacman::{check_pkg_exists, sudo_pacman};
+use crate::config::{BackupMode, Exclusion};
+use crate::formatter::format_document;
+use crate::hooks::{HookKind, collect_hooks, run_hooks};
+use crate::kdl_edit::{
+    add_pkgs, apply_dec_changes, category_occurrences, create_category, extract_category,
+    find_category, merge_category, move_pkgs, print_dec_changes, remove_category, remove_pkgs,
+    rename_category, write_dec_changes, write_reverted_dec_changes,
+};
+use crate::list_pkgs::{
+    PkgDiff, collect_documents_lenient, collect_hosts, collect_packages_from_doc, exclusion,
+    get_declared_pkg_list, get_declared_pkg_occurrences, get_enabled_pkg_list, get_host_pkg_list,
+    get_orphans, get_pkg_diff,
+};
+use crate::output::{
+    CategoryJson, CategoryTreeJson, DiagnosticJson, DiffJson, HistoryJson, OutputFormat,
+    PackageTreeJson, Reported, SnapshotJson, category_tree, coded, package_tree, packages_json,
+    print_json,
+};
+use crate::packages::{Category, Package, PackageJoin};
+use crate::pacman_log::{LogAction, LogEvent, read_log};
 use crate::prompts::*;
+use crate::triage::triage;
 
 pub fn add_cmd(app: &mut App, cli: Cli, and_install: bool) -> Result<()> {
     let pkgs = {
-        let (packages, category, tags) = match cli.command {
-            Commands::Add(args) => (args.packages, args.category, args.tags),
-            Commands::Install(args) => (args.packages, args.category, args.tags),
+        let (packages, category, cat_file, tags) = match cli.command {
+            Commands::Add(args) => (args.packages, args.category, args.cat_file, args.tags),
+            Commands::Install(args) => (args.packages, args.category, args.cat_file, args.tags),
             _ => unreachable!(),
         };
 
-        let categories = collect_categories(app);
-        if let Some(ref cat) = category
-            && !categories.contains(cat)
-        // TODO: Check for similarly named categories and for categories excluded due to rules
-        {
-            bail!("category '{}' not found", cat)
-        }
+        let category = match category {
+            Some(category) => Some(ensure_category(app, category, cat_file.as_deref())?),
+            None => None,
+        };
 
         if let Some(pkgs) = &packages {
             let missing: Vec<String> = pkgs
                 .par_iter()
-                .filter(|pkg| !check_pkg_exists(&app.config, &pkg.to_string()))
+                .filter(|pkg| !matches!(app.backend.sync_info(&pkg.to_string()), Ok(Some(_))))
                 .map(|pkg| pkg.to_string())
                 .collect();
 
             if !missing.is_empty() {
                 let pkg_list = missing.join(", ");
-                bail!("package(s) not found in repositories: {}", pkg_list);
+                return Err(coded(
+                    "package_not_found",
+                    format!(
+                        "package(s) not found in {} repositories: {}",
+                        app.backend.name(),
+                        pkg_list
+                    ),
+                ));
             }
         }
 
@@ -56,21 +83,60 @@ pub fn add_cmd(app: &mut App, cli: Cli, and_install: bool) -> Result<()> {
 
         let category = match category {
             Some(x) => x,
-            None => prompt_category(app)?,
+            None => {
+                let category = prompt_category(app)?;
+                if find_category(app, &category).is_empty() {
+                    create_category(app, &category, cat_file.as_deref())?;
+                }
+                category
+            }
         };
 
-        add_pkgs(app, &category, &pkgs)?;
+        add_pkgs(app, &category, cat_file.as_deref(), &pkgs)?;
+
+        let declared = get_declared_pkg_list(app)?;
+        let excluded: Vec<String> = pkgs
+            .iter()
+            .filter_map(|pkg| declared.get(pkg))
+            .filter_map(|pkg| {
+                let exclusion = exclusion(app, pkg, &declared)?;
+                Some(format!("{pkg} ({exclusion})"))
+            })
+            .collect();
+        if !excluded.is_empty() {
+            if and_install {
+                return Err(coded(
+                    "package_excluded",
+                    format!(
+                        "package(s) are excluded by whitelist/blacklist rules and would not be installed: {}",
+                        excluded.join(", ")
+                    ),
+                ));
+            }
+            println!(
+                "{} {}",
+                "Package(s) excluded by whitelist/blacklist rules, sync will not install them:"
+                    .yellow()
+                    .bold(),
+                excluded.join(", ")
+            );
+        }
 
         pkgs
     };
 
     if and_install {
-        install_pkgs(&app.config, pkgs)?;
+        let hooks = collect_hooks(app);
+        run_hooks(&app.config, &hooks, HookKind::BeforeInstall, &pkgs)?;
+        install_pkgs(app, &pkgs)?;
+        // packages are installed now, failing hooks must not keep them out of declaration
+        apply_dec_changes(app)?;
+        run_hooks(&app.config, &hooks, HookKind::AfterInstall, &pkgs)?;
+        run_hooks(&app.config, &hooks, HookKind::AfterSync, &pkgs)?;
+        return Ok(());
     }
 
-    apply_dec_changes(app)?;
-
-    Ok(())
+    apply_dec_changes(app)
 }
 
 pub fn remove_cmd(app: &mut App, cli: Cli, and_uninstall: bool) -> Result<()> {
@@ -82,36 +148,133 @@ pub fn remove_cmd(app: &mut App, cli: Cli, and_uninstall: bool) -> Result<()> {
         Some(pkgs) => pkgs.to_owned(),
         None => prompt_pkgs_exp(app)?,
     };
+    let pkgs = with_subpackages(app, pkgs)?;
 
     println!("{} {}", "Removing packages:".blue().bold(), pkgs.join(" "));
 
+    // hooks have to be collected before packages are removed from declaration
+    let hooks = collect_hooks(app);
+
     remove_pkgs(app, &pkgs)?;
 
     if and_uninstall {
-        uninstall_pkgs(&app.config, pkgs)?;
-    };
+        run_hooks(&app.config, &hooks, HookKind::BeforeRemove, &pkgs)?;
+        uninstall_pkgs(app, &pkgs)?;
+        // packages are uninstalled now, failing hooks must not keep them in declaration
+        apply_dec_changes(app)?;
+        return run_hooks(&app.config, &hooks, HookKind::AfterRemove, &pkgs);
+    }
 
-    apply_dec_changes(app)?;
+    apply_dec_changes(app)
+}
 
-    Ok(())
+/// Adds subpackages of `pkgs` (declared for any host) after confirmation. Subpackages that are not removed
+/// take place of their parent, which is what happens without a terminal.
+fn with_subpackages(app: &App, mut pkgs: Vec<Package>) -> Result<Vec<Package>> {
+    let mut declared = HashSet::new();
+    for (path, doc) in &app.docs {
+        collect_packages_from_doc(doc, path, None, &mut declared)?;
+    }
+    let mut subpackages: Vec<Package> = declared
+        .into_iter()
+        .filter(|sub| {
+            !pkgs.contains(sub)
+                && sub
+                    .parents
+                    .iter()
+                    .any(|parent| pkgs.iter().any(|pkg| pkg.name == *parent))
+        })
+        .collect();
+    if subpackages.is_empty() {
+        return Ok(pkgs);
+    }
+    subpackages.sort();
+
+    println!(
+        "{} {}",
+        "Subpackages of removed packages:".blue().bold(),
+        subpackages.join(" ")
+    );
+    if can_prompt()
+        && Confirm::new("Remove subpackages too?")
+            .with_default(true)
+            .prompt()?
+    {
+        pkgs.extend(subpackages);
+    } else {
+        println!("Subpackages are kept in place of their parent");
+    }
+    Ok(pkgs)
+}
+
+/// Returns `category` if it is declared, otherwise offers similarly named categories or creates it after
+/// confirmation. Without a terminal missing category is an error with suggestions.
+fn ensure_category(app: &mut App, category: Category, cat_file: Option<&Path>) -> Result<Category> {
+    if !find_category(app, &category).is_empty() {
+        return Ok(category);
+    }
+    let similar = similar_categories(app, &category);
+
+    if !can_prompt() {
+        let hint = if similar.is_empty() {
+            String::new()
+        } else {
+            let similar: Vec<String> = similar.iter().map(|cat| format!("'{cat}'")).collect();
+            format!(", did you mean {}?", similar.join(", "))
+        };
+        return Err(coded(
+            "category_not_found",
+            format!("category '{category}' not found{hint}"),
+        ));
+    }
+
+    match prompt_missing_category(&category, similar)? {
+        Some(similar) => Ok(similar),
+        None => {
+            create_category(app, &category, cat_file)?;
+            Ok(category)
+        }
+    }
 }
 
-pub fn gen_cmd(app: &mut App) -> Result<()> {
-    let (pkgs_to_add, pkgs_to_remove) = get_pkg_diff(app)?;
-    if pkgs_to_add.is_empty() && pkgs_to_remove.is_empty() {
+/// Returns whether declaration differs from system and was not updated
+pub fn gen_cmd(app: &mut App, args: &GenerateArgs) -> Result<bool> {
+    let diff = get_pkg_diff(app, &args.filter.selector())?;
+    if app.config.output == OutputFormat::Json {
+        return print_diff_json(&diff, None);
+    }
+    let PkgDiff {
+        installed_only,
+        declared_only: pkgs_to_remove,
+        excluded,
+        required_undeclared,
+        ..
+    } = diff;
+    print_excluded(&excluded);
+    if installed_only.is_empty() && required_undeclared.is_empty() && pkgs_to_remove.is_empty() {
         println!(
             "{}",
             "Packages are in sync, nothing to generate".blue().bold()
         );
-        return Ok(());
+        return Ok(false);
     }
-    if !pkgs_to_add.is_empty() {
+    if !installed_only.is_empty() {
         println!(
             "\n{} {}:",
             "Packages to add to config".blue().bold(),
-            pkgs_to_add.len().to_string().green()
+            installed_only.len().to_string().green()
+        );
+        println!("{}", installed_only.join(" "));
+    }
+    if !required_undeclared.is_empty() {
+        println!(
+            "\n{} {}:",
+            "Packages needed by declared packages to add to config"
+                .blue()
+                .bold(),
+            required_undeclared.len().to_string().green()
         );
-        println!("{}", pkgs_to_add.join(" "));
+        println!("{}", required_undeclared.join(" "));
     }
     if !pkgs_to_remove.is_empty() {
         println!(
@@ -123,82 +286,1821 @@ pub fn gen_cmd(app: &mut App) -> Result<()> {
     }
     println!();
 
+    if args.triage {
+        if !can_prompt() {
+            bail!("--triage needs an interactive terminal");
+        }
+        if !pkgs_to_remove.is_empty()
+            && Confirm::new("Remove packages which are not installed from config?")
+                .with_default(true)
+                .prompt()?
+        {
+            remove_pkgs(app, &pkgs_to_remove)?;
+        }
+        let undecided = triage(app, installed_only, required_undeclared)?;
+        apply_dec_changes(app)?;
+        return Ok(app.config.dry_run || undecided > 0);
+    }
+    // declaration follows the system, explicitly installed packages are declared even if others need them
+    let mut pkgs_to_add = installed_only;
+    pkgs_to_add.extend(required_undeclared);
+
     if !Confirm::new("Proceed?").with_default(true).prompt()? {
         println!("Operation cancelled");
-        return Ok(());
+        return Ok(true);
     }
 
     if !pkgs_to_remove.is_empty() {
         remove_pkgs(app, &pkgs_to_remove)?;
     }
     if !pkgs_to_add.is_empty() {
-        add_pkgs(app, &(app.config.default_category.clone()), &pkgs_to_add)?;
+        add_pkgs(
+            app,
+            &(app.config.default_category.clone()),
+            None,
+            &pkgs_to_add,
+        )?;
     }
 
     apply_dec_changes(app)?;
 
-    Ok(())
+    Ok(app.config.dry_run)
 }
 
-pub fn sync_cmd(app: &App) -> Result<()> {
-    let (pkgs_to_uninstall, pkgs_to_install) = get_pkg_diff(app)?;
+/// Returns whether system differs from declaration and was not synced
+pub fn sync_cmd(app: &App, args: &SyncArgs) -> Result<bool> {
+    let diff = get_pkg_diff(app, &args.filter.selector())?;
+    let orphans = get_orphans(app, &diff)?;
+    if app.config.output == OutputFormat::Json {
+        return print_diff_json(&diff, Some((&orphans, args.prune_orphans)));
+    }
+    print_excluded(&diff.excluded);
+    if !args.prune_orphans {
+        print_orphans(&orphans);
+    }
+    let plan = SyncPlan::new(diff, orphans, args.prune_orphans);
 
-    if pkgs_to_uninstall.is_empty() && pkgs_to_install.is_empty() {
+    if plan.is_empty() {
         println!("{}", "Packages are in sync, nothing to do".blue().bold());
-        return Ok(());
+        return Ok(false);
     }
-    if !pkgs_to_install.is_empty() {
+    if !plan.install.is_empty() {
         println!(
             "\n{} {}:",
             "Packages to install".blue().bold(),
-            pkgs_to_install.len().to_string().green()
+            plan.install.len().to_string().green()
         );
-        println!("{}", pkgs_to_install.join(" "));
+        println!("{}", plan.install.join(" "));
     }
-    if !pkgs_to_uninstall.is_empty() {
+    if !plan.uninstall.is_empty() {
         println!(
             "\n{} {}:",
             "Packages to uninstall".blue().bold(),
-            pkgs_to_uninstall.len().to_string().red()
+            plan.uninstall.len().to_string().red()
+        );
+        println!("{}", plan.uninstall.join(" "));
+    }
+    if !plan.orphans.is_empty() {
+        println!(
+            "\n{} {}:",
+            "Orphans to remove".blue().bold(),
+            plan.orphans.len().to_string().red()
         );
-        println!("{}", pkgs_to_uninstall.join(" "));
+        println!("{}", plan.orphans.join(" "));
+    }
+    if !plan.mark_deps.is_empty() {
+        println!(
+            "\n{} {}:",
+            "Packages to mark as dependencies".blue().bold(),
+            plan.mark_deps.len().to_string().yellow()
+        );
+        println!("{}", plan.mark_deps.join(" "));
+    }
+    if !plan.mark_explicit.is_empty() {
+        println!(
+            "\n{} {}:",
+            "Packages to mark as explicitly installed".blue().bold(),
+            plan.mark_explicit.len().to_string().yellow()
+        );
+        println!("{}", plan.mark_explicit.join(" "));
     }
     println!();
 
     if !Confirm::new("Proceed?").with_default(true).prompt()? {
         println!("Operation cancelled");
+        return Ok(true);
+    }
+
+    apply_sync(app, &plan)?;
+
+    Ok(app.config.dry_run)
+}
+
+/// Changes of installed packages made by `sync`
+struct SyncPlan {
+    install: Vec<Package>,
+    uninstall: Vec<Package>,
+    /// Orphans pruned together with `uninstall`
+    orphans: Vec<Package>,
+    mark_deps: Vec<Package>,
+    mark_explicit: Vec<Package>,
+}
+
+impl SyncPlan {
+    fn new(diff: PkgDiff, orphans: Vec<Package>, prune_orphans: bool) -> Self {
+        let mut mark_deps = diff.explicit_deps;
+        mark_deps.extend(diff.required_undeclared);
+        SyncPlan {
+            install: diff.declared_only,
+            uninstall: diff.installed_only,
+            orphans: if prune_orphans { orphans } else { Vec::new() },
+            mark_deps,
+            mark_explicit: diff.installed_as_deps,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.install.is_empty()
+            && self.uninstall.is_empty()
+            && self.orphans.is_empty()
+            && self.mark_deps.is_empty()
+            && self.mark_explicit.is_empty()
+    }
+}
+
+fn apply_sync(app: &App, plan: &SyncPlan) -> Result<()> {
+    // install reason is changed in local database, which knows packages by name only
+    let names =
+        |pkgs: &[Package]| -> Vec<String> { pkgs.iter().map(|pkg| pkg.name.clone()).collect() };
+    if !plan.mark_deps.is_empty() {
+        app.backend.mark_as_deps(&names(&plan.mark_deps))?;
+    }
+    if !plan.mark_explicit.is_empty() {
+        app.backend.mark_as_explicit(&names(&plan.mark_explicit))?;
+    }
+    let hooks = collect_hooks(app);
+    // subpackages are installed after packages they are nested in, hooks of a parent run before them
+    for round in install_rounds(&plan.install) {
+        run_hooks(&app.config, &hooks, HookKind::BeforeInstall, &round)?;
+        install_pkgs(app, &round)?;
+        run_hooks(&app.config, &hooks, HookKind::AfterInstall, &round)?;
+    }
+    if !plan.uninstall.is_empty() || !plan.orphans.is_empty() {
+        run_hooks(&app.config, &hooks, HookKind::BeforeRemove, &plan.uninstall)?;
+        // orphans go in the same transaction, removing packages first could orphan more of them
+        uninstall_pkgs(app, &[plan.uninstall.as_slice(), &plan.orphans].concat())?;
+        run_hooks(&app.config, &hooks, HookKind::AfterRemove, &plan.uninstall)?;
+    }
+    let enabled: Vec<Package> = get_enabled_pkg_list(app)?.into_iter().collect();
+    run_hooks(&app.config, &hooks, HookKind::AfterSync, &enabled)
+}
+
+/// Prints diff as json, returns whether there is a drift. `orphans` are given by `sync` with whether they
+/// are to be pruned, only then they count as a drift.
+fn print_diff_json(diff: &PkgDiff, orphans: Option<(&[Package], bool)>) -> Result<bool> {
+    let drift = !diff.installed_only.is_empty()
+        || !diff.declared_only.is_empty()
+        || !diff.explicit_deps.is_empty()
+        || !diff.installed_as_deps.is_empty()
+        || !diff.required_undeclared.is_empty()
+        || orphans.is_some_and(|(orphans, prune)| prune && !orphans.is_empty());
+    print_json(&DiffJson {
+        status: if drift { "drift" } else { "in_sync" },
+        installed_only: packages_json(&diff.installed_only),
+        declared_only: packages_json(&diff.declared_only),
+        excluded: diff.excluded.iter().map(Into::into).collect(),
+        explicit_deps: packages_json(&diff.explicit_deps),
+        installed_as_deps: packages_json(&diff.installed_as_deps),
+        required_undeclared: packages_json(&diff.required_undeclared),
+        orphans: orphans.map(|(orphans, _)| packages_json(orphans)),
+    })?;
+    Ok(drift)
+}
+
+fn print_orphans(orphans: &[Package]) {
+    if orphans.is_empty() {
+        return;
+    }
+    println!(
+        "\n{} {}:",
+        "Orphaned dependencies, remove them with --prune-orphans"
+            .dimmed()
+            .bold(),
+        orphans.len()
+    );
+    let orphans: Vec<String> = orphans.iter().map(ToString::to_string).collect();
+    println!("{}", orphans.join(" ").dimmed());
+}
+
+fn print_excluded(excluded: &[(Package, Exclusion)]) {
+    if excluded.is_empty() {
+        return;
+    }
+    println!(
+        "\n{} {}:",
+        "Packages excluded by whitelist/blacklist rules"
+            .dimmed()
+            .bold(),
+        excluded.len()
+    );
+    let excluded: Vec<String> = excluded
+        .iter()
+        .map(|(pkg, exclusion)| format!("{pkg} ({exclusion})"))
+        .collect();
+    println!("{}", excluded.join(", ").dimmed());
+}
+
+pub fn move_cmd(app: &mut App, args: &MoveArgs) -> Result<()> {
+    let selector = args.filter.selector();
+    let pkgs: Vec<Package> =
+        if args.packages.is_none() && args.from_category.is_none() && selector.is_empty() {
+            prompt_pkgs_declared(app, &selector)?
+        } else {
+            // only the selected declarations of a package declared in several places are moved
+            let mut pkgs: Vec<Package> = get_declared_pkg_occurrences(app)?
+                .into_iter()
+                .filter(|pkg| {
+                    args.packages
+                        .as_ref()
+                        .is_none_or(|names| names.iter().any(|name| name.name == pkg.name))
+                })
+                .filter(|pkg| {
+                    args.from_category.as_ref().is_none_or(|from| {
+                        pkg.category
+                            .as_ref()
+                            .is_some_and(|category| from.matches(&category.components()))
+                    })
+                })
+                .filter(|pkg| selector.matches(pkg))
+                .collect();
+            pkgs.sort();
+            pkgs
+        };
+    if let Some(names) = &args.packages {
+        let missing: Vec<String> = names
+            .iter()
+            .filter(|name| !pkgs.iter().any(|pkg| pkg.name == name.name))
+            .map(|name| name.to_string())
+            .collect();
+        if !missing.is_empty() {
+            return Err(coded(
+                "package_not_found",
+                format!("package(s) not declared: {}", missing.join(", ")),
+            ));
+        }
+    }
+    if pkgs.is_empty() {
+        println!("{}", "No packages to move".blue().bold());
         return Ok(());
     }
 
-    if !pkgs_to_install.is_empty() {
-        install_pkgs(&app.config, pkgs_to_install)?;
+    let category = match &args.category {
+        Some(category) => ensure_category(app, category.clone(), args.cat_file.as_deref())?,
+        None => {
+            let category = prompt_category(app)?;
+            if find_category(app, &category).is_empty() {
+                create_category(app, &category, args.cat_file.as_deref())?;
+            }
+            category
+        }
+    };
+
+    let mut names: Vec<String> = pkgs.iter().map(ToString::to_string).collect();
+    names.dedup();
+    println!(
+        "{} {} {}",
+        "Moving packages to".blue().bold(),
+        category.to_string().blue().bold(),
+        names.join(" ")
+    );
+    move_pkgs(app, &pkgs, &category, args.cat_file.as_deref())?;
+
+    if !print_dec_changes(app) {
+        println!(
+            "{}",
+            "Packages are already there, nothing to do".blue().bold()
+        );
+        return Ok(());
     }
-    if !pkgs_to_uninstall.is_empty() {
-        uninstall_pkgs(&app.config, pkgs_to_uninstall)?;
+    if !app.config.dry_run {
+        write_dec_changes(app)?;
+    }
+    Ok(())
+}
+
+pub fn cat_cmd(app: &mut App, args: &CatArgs) -> Result<()> {
+    match &args.command {
+        CatCommands::Rename(args) => {
+            let occurrences = category_occurrences(app, &args.category, args.cat_file.as_deref())?;
+            let category = occurrences[0].category.clone();
+            ensure_not_default(app, &category)?;
+            let renamed = rename_category(app, &occurrences, &args.name)?;
+            println!(
+                "{} {category} -> {renamed}",
+                "Renaming category:".blue().bold()
+            );
+        }
+        CatCommands::Remove(args) => return cat_remove_cmd(app, args),
+        CatCommands::Merge(args) => {
+            // target is created first, appended nodes do not move occurrences of merged category
+            let into = ensure_category(app, args.into.clone(), args.cat_file.as_deref())?;
+            let occurrences = category_occurrences(app, &args.category, None)?;
+            let category = occurrences[0].category.clone();
+            ensure_not_default(app, &category)?;
+            println!(
+                "{} {category} into {into}",
+                "Merging category:".blue().bold()
+            );
+            merge_category(app, occurrences, &into, args.cat_file.as_deref())?;
+        }
+        CatCommands::Extract(args) => {
+            println!(
+                "{} {} to {}",
+                "Moving category:".blue().bold(),
+                args.category,
+                args.file.display()
+            );
+            extract_category(app, &args.category, args.cat_file.as_deref(), &args.file)?;
+        }
+    }
+
+    apply_dec_changes(app)
+}
+
+fn cat_remove_cmd(app: &mut App, args: &CatRemoveArgs) -> Result<()> {
+    let occurrences = category_occurrences(app, &args.category, args.cat_file.as_deref())?;
+    let category = occurrences[0].category.clone();
+    ensure_not_default(app, &category)?;
+
+    let mut pkgs: Vec<Package> = get_declared_pkg_list(app)?
+        .into_iter()
+        .filter(|pkg| {
+            pkg.category
+                .as_ref()
+                .is_some_and(|pkg_category| category.includes(pkg_category))
+        })
+        .collect();
+    pkgs.sort();
+
+    let removal = if let Some(target) = &args.move_to {
+        CategoryRemoval::MoveTo(target.clone())
+    } else if args.uninstall {
+        CategoryRemoval::Uninstall
+    } else if args.keep_installed || pkgs.is_empty() {
+        CategoryRemoval::KeepInstalled
+    } else if can_prompt() {
+        prompt_category_removal(app, &category, pkgs.len())?
+    } else {
+        return Err(coded(
+            "category_not_empty",
+            format!(
+                "category '{category}' contains {} package(s), use --move-to, --uninstall or --keep-installed",
+                pkgs.len()
+            ),
+        ));
+    };
+
+    match removal {
+        CategoryRemoval::MoveTo(target) => {
+            let target = ensure_category(app, target, None)?;
+            println!(
+                "{} {category} into {target}",
+                "Merging category:".blue().bold()
+            );
+            merge_category(app, occurrences, &target, None)?;
+        }
+        CategoryRemoval::Uninstall => {
+            println!("{} {category}", "Removing category:".blue().bold());
+            // hooks have to be collected before packages are removed from declaration
+            let hooks = collect_hooks(app);
+            remove_category(app, occurrences)?;
+            if !pkgs.is_empty() {
+                println!(
+                    "{} {}",
+                    "Uninstalling packages:".blue().bold(),
+                    pkgs.join(" ")
+                );
+                run_hooks(&app.config, &hooks, HookKind::BeforeRemove, &pkgs)?;
+                uninstall_pkgs(app, &pkgs)?;
+                // packages are uninstalled now, failing hooks must not keep the category in declaration
+                apply_dec_changes(app)?;
+                return run_hooks(&app.config, &hooks, HookKind::AfterRemove, &pkgs);
+            }
+        }
+        CategoryRemoval::KeepInstalled => {
+            println!("{} {category}", "Removing category:".blue().bold());
+            remove_category(app, occurrences)?;
+        }
     }
+    apply_dec_changes(app)
+}
 
+/// Default category has to exist, packages are added to it by `generate`
+fn ensure_not_default(app: &App, category: &Category) -> Result<()> {
+    if app.config.default_category.matches(&category.components()) {
+        bail!("'{category}' is the default category, change `default_category` in config first");
+    }
     Ok(())
 }
 
 pub fn search_cmd(app: &App, args: &SearchArgs) -> Result<()> {
+    let selector = args.filter.selector();
     let pkgs = match args {
+        _ if !selector.is_empty() => prompt_pkgs_declared(app, &selector)?,
         SearchArgs { all: true, .. } => prompt_pkgs_all(app)?,
+        SearchArgs {
+            chronological: true,
+            ..
+        } => prompt_pkgs_chronological(app)?,
         SearchArgs { explicit: true, .. } => prompt_pkgs_exp(app)?,
         _ => prompt_pkgs_ins(app)?,
     };
 
+    if app.config.output == OutputFormat::Json {
+        return print_json(&packages_json(&pkgs));
+    }
     print!("{}", pkgs.join("\n"));
     Ok(())
 }
 
-fn uninstall_pkgs(cfg: &Config, pkgs: Vec<Package>) -> Result<()> {
-    let pkgs: Vec<String> = pkgs.into_iter().map(|pkg| pkg.to_string()).collect();
-    sudo_pacman(cfg, &["-Rns"], &pkgs)?;
+pub fn list_cmd(app: &App, args: &ListArgs) -> Result<()> {
+    if args.categories {
+        let tree = category_tree(&collect_categories(app));
+        if app.config.output == OutputFormat::Json {
+            return print_json(&tree);
+        }
+        fn print_tree(categories: &[CategoryJson], depth: usize) {
+            for category in categories {
+                println!("{}{}", "    ".repeat(depth), category.name.blue().bold());
+                print_tree(&category.children, depth + 1);
+            }
+        }
+        print_tree(&tree, 0);
+        return Ok(());
+    }
+
+    let selector = args.filter.selector();
+    let mut pkgs: Vec<Package> = get_declared_pkg_list(app)?
+        .into_iter()
+        .filter(|pkg| selector.matches(pkg))
+        .collect();
+    pkgs.sort_by_key(|pkg| {
+        (
+            pkg.category.as_ref().map(|cat| cat.full_path()),
+            pkg.clone(),
+        )
+    });
+    if app.config.output == OutputFormat::Json {
+        return print_json(&packages_json(&pkgs));
+    }
+
+    let mut current_category = None;
+    for pkg in &pkgs {
+        let category = pkg.category.as_ref().map(|cat| cat.full_path());
+        if current_category.as_ref() != Some(&category) {
+            println!(
+                "{}",
+                category.as_deref().unwrap_or("(no category)").blue().bold()
+            );
+            current_category = Some(category);
+        }
+
+        let implicit: Vec<String> = pkg
+            .resolved_tags()
+            .into_iter()
+            .filter(|tag| !pkg.tags.contains(tag))
+            .collect();
+        println!(
+            "    {} {} {}",
+            pkg,
+            pkg.tags.join(" ").green(),
+            implicit.join(" ").dimmed()
+        );
+    }
+
+    Ok(())
+}
+
+pub fn tree_cmd(app: &App, args: &TreeArgs) -> Result<()> {
+    let selector = args.filter.selector();
+    let declared = get_declared_pkg_list(app)?;
+    // package is identified by its category and chain of parents
+    let key = |pkg: &Package| {
+        let mut chain = pkg.parents.clone();
+        chain.push(pkg.name.clone());
+        (pkg.category.clone(), chain)
+    };
+    // parents of matching packages are shown too, so that subpackages stay in their place
+    let mut shown = HashSet::new();
+    for pkg in declared.iter().filter(|pkg| selector.matches(pkg)) {
+        let (category, chain) = key(pkg);
+        for len in 1..=chain.len() {
+            shown.insert((category.clone(), chain[..len].to_vec()));
+        }
+    }
+    let pkgs: Vec<(Package, bool)> = declared
+        .iter()
+        .filter(|pkg| shown.contains(&key(pkg)))
+        .map(|pkg| (pkg.clone(), exclusion(app, pkg, &declared).is_none()))
+        .collect();
+    let tree = package_tree(&pkgs);
+    if app.config.output == OutputFormat::Json {
+        return print_json(&tree);
+    }
+
+    fn print_packages(pkgs: &[PackageTreeJson], depth: usize) {
+        for pkg in pkgs {
+            let name = match &pkg.package.repository {
+                Some(repo) => format!("{repo}/{}", pkg.package.name),
+                None => pkg.package.name.clone(),
+            };
+            let tags: String = pkg
+                .package
+                .tags
+                .iter()
+                .map(|tag| format!(" {tag}"))
+                .collect();
+            let indent = "    ".repeat(depth);
+            if pkg.enabled {
+                println!("{indent}{name}{}", tags.green());
+            } else {
+                println!("{indent}{}", format!("{name}{tags} (disabled)").dimmed());
+            }
+            print_packages(&pkg.subpackages, depth + 1);
+        }
+    }
+    fn print_categories(categories: &[CategoryTreeJson], depth: usize) {
+        for category in categories {
+            println!("{}{}", "    ".repeat(depth), category.name.blue().bold());
+            print_packages(&category.packages, depth + 1);
+            print_categories(&category.children, depth + 1);
+        }
+    }
+    print_packages(&tree.packages, 0);
+    print_categories(&tree.categories, 0);
+
     Ok(())
 }
 
-fn install_pkgs(cfg: &Config, pkgs: Vec<Package>) -> Result<()> {
-    let pkgs: Vec<String> = pkgs.into_iter().map(|pkg| pkg.to_string()).collect();
-    sudo_pacman(cfg, &["-S", "--asexplicit"], &pkgs)?; // --asexplicit does not work with yay
+pub fn check_cmd(app: &mut App, args: &CheckArgs) -> Result<()> {
+    let mut problems = Vec::new();
+    app.docs = collect_documents_lenient(&app.config.declaration_file, &mut problems)?;
+    let diagnostics = check_declaration(app, problems, args.offline);
+    let errors = diagnostics.iter().filter(|d| d.is_error()).count();
+    let warnings = diagnostics.len() - errors;
+    let failed = errors > 0 || (args.strict && warnings > 0);
+    let failure = || {
+        coded(
+            "check_failed",
+            format!("{errors} error(s) and {warnings} warning(s) found in declaration"),
+        )
+    };
+
+    if app.config.output == OutputFormat::Json {
+        let diagnostics: Vec<DiagnosticJson> = diagnostics.iter().map(|d| d.to_json(app)).collect();
+        print_json(&serde_json::json!({
+            "status": if failed { "failed" } else { "ok" },
+            "diagnostics": diagnostics,
+        }))?;
+        // diagnostics are the whole json document, failure is reported by exit code only
+        if failed {
+            return Err(Reported(failure()).into());
+        }
+        return Ok(());
+    }
+
+    for diagnostic in &diagnostics {
+        println!("{}", diagnostic.render(app));
+    }
+    if diagnostics.is_empty() {
+        println!("{}", "No problems found".green().bold());
+    }
+    if failed {
+        return Err(failure());
+    }
+    if warnings > 0 {
+        println!(
+            "{}",
+            format!("{warnings} warning(s) found in declaration").yellow()
+        );
+    }
     Ok(())
 }
+
+/// Returns whether `--check` found files which are not formatted
+pub fn fmt_cmd(app: &mut App, args: &FmtArgs) -> Result<bool> {
+    for (_, doc) in &mut app.docs {
+        format_document(doc, &app.config.format);
+    }
+    let unformatted: Vec<&Path> = app
+        .docs
+        .iter()
+        .filter(|(file, doc)| fs::read_to_string(file).unwrap_or_default() != doc.to_string())
+        .map(|(file, _)| file.as_path())
+        .collect();
+    let drift = !unformatted.is_empty();
+
+    if app.config.output == OutputFormat::Json {
+        print_json(&serde_json::json!({
+            "status": if drift { "drift" } else { "in_sync" },
+            "files": unformatted,
+        }))?;
+        if !args.check && !app.config.dry_run {
+            write_dec_changes(app)?;
+        }
+        return Ok(args.check && drift);
+    }
+
+    if !drift {
+        println!("{}", "Declaration files are formatted".green().bold());
+        return Ok(false);
+    }
+    if args.check {
+        print_dec_changes(app);
+        println!(
+            "{}",
+            format!("{} file(s) are not formatted", unformatted.len()).yellow()
+        );
+        return Ok(true);
+    }
+    apply_dec_changes(app)?;
+    Ok(false)
+}
+
+pub fn revert_cmd(app: &mut App, args: &RevertArgs) -> Result<()> {
+    let snapshots = list_snapshots(app)?;
+    if snapshots.is_empty() {
+        return Err(coded(
+            "backup_not_found",
+            format!("no backups found in {}", app.config.backup.dir),
+        ));
+    }
+
+    if args.list && app.config.output == OutputFormat::Json {
+        let snapshots: Vec<SnapshotJson> = snapshots.iter().map(Into::into).collect();
+        return print_json(&snapshots);
+    }
+    if args.list {
+        for (idx, snapshot) in snapshots.iter().enumerate() {
+            let reverted = match &snapshot.reverted {
+                Some(reverted) => format!(" [reverts {reverted}]").dimmed().to_string(),
+                None => String::new(),
+            };
+            println!(
+                "{} {} {} {}{reverted}",
+                format!("{:>3}", idx + 1).blue(),
+                snapshot.id.bold(),
+                format!("({})", snapshot.date).dimmed(),
+                snapshot.message.as_deref().unwrap_or_default()
+            );
+            for (file, _) in &snapshot.files {
+                println!("      {}", file.display());
+            }
+        }
+        return Ok(());
+    }
+
+    let idx = match &args.to {
+        Some(timestamp) => snapshots
+            .iter()
+            .position(|snapshot| &snapshot.id == timestamp)
+            .ok_or_else(|| coded("backup_not_found", format!("no backup '{timestamp}' found")))?,
+        None => revert_index(&snapshots, args.steps)?,
+    };
+
+    let declared_before = get_declared_pkg_list(app)?;
+    restore_snapshot(app, &snapshots, idx)?;
+
+    println!(
+        "{} {}",
+        "Reverting to state before".blue().bold(),
+        snapshots[idx].date
+    );
+    if !print_dec_changes(app) {
+        println!(
+            "{}",
+            "Declaration files are unchanged, nothing to do"
+                .blue()
+                .bold()
+        );
+        return Ok(());
+    }
+
+    let (pkgs_to_install, pkgs_to_uninstall) = if args.system {
+        let declared_after = get_declared_pkg_list(app)?;
+        let mut to_install: Vec<Package> = declared_after
+            .difference(&declared_before)
+            .cloned()
+            .collect();
+        let mut to_uninstall: Vec<Package> = declared_before
+            .difference(&declared_after)
+            .cloned()
+            .collect();
+        to_install.sort();
+        to_uninstall.sort();
+        if !to_install.is_empty() {
+            println!(
+                "\n{} {}",
+                "Packages to install:".blue().bold(),
+                to_install.join(" ")
+            );
+        }
+        if !to_uninstall.is_empty() {
+            println!(
+                "\n{} {}",
+                "Packages to uninstall:".blue().bold(),
+                to_uninstall.join(" ")
+            );
+        }
+        (to_install, to_uninstall)
+    } else {
+        (Vec::new(), Vec::new())
+    };
+    println!();
+
+    if !Confirm::new("Proceed?").with_default(true).prompt()? {
+        println!("Operation cancelled");
+        return Ok(());
+    }
+
+    if !app.config.dry_run {
+        write_reverted_dec_changes(app, &snapshots[idx].id)?;
+    }
+    if !pkgs_to_install.is_empty() {
+        install_pkgs(app, &pkgs_to_install)?;
+    }
+    if !pkgs_to_uninstall.is_empty() {
+        uninstall_pkgs(app, &pkgs_to_uninstall)?;
+    }
+
+    Ok(())
+}
+
+pub fn log_cmd(app: &App, args: &LogArgs) -> Result<()> {
+    if app.config.output == OutputFormat::Json {
+        if !matches!(app.config.backup.mode, BackupMode::Git) {
+            bail!("history of changes is only recorded with backup mode \"git\"");
+        }
+        let snapshots: Vec<SnapshotJson> = list_snapshots(app)?
+            .iter()
+            .take(args.limit.unwrap_or(usize::MAX))
+            .map(Into::into)
+            .collect();
+        return print_json(&snapshots);
+    }
+    print_git_log(app, args.limit)
+}
+
+/// Events of `history_cmd` in log order with declaration of their package and whether it is installed
+/// explicitly, `None` if it is not installed
+fn select_history<'a>(
+    args: &HistoryArgs,
+    events: &'a [LogEvent],
+    declared: &'a HashSet<Package>,
+    local: &HashMap<String, bool>,
+) -> Vec<(&'a LogEvent, Option<&'a Package>, Option<bool>)> {
+    let selector = args.filter.selector();
+
+    let mut history = Vec::new();
+    for event in events {
+        let Some(name) = event.action.package() else {
+            continue;
+        };
+        let date = event.timestamp.date_naive();
+        if args.since.is_some_and(|since| date < since)
+            || args.until.is_some_and(|until| date > until)
+            || args
+                .packages
+                .as_ref()
+                .is_some_and(|pkgs| !pkgs.iter().any(|pkg| pkg.name == name))
+        {
+            continue;
+        }
+
+        let pkg = declared.get(&Package::from(name));
+        let installed = local.get(name).copied();
+        let is_explicit = installed == Some(true);
+        let shown = match pkg {
+            Some(pkg) => {
+                !args.undeclared
+                    && selector.matches(pkg)
+                    && args.category.as_ref().is_none_or(|cat| {
+                        pkg.category
+                            .as_ref()
+                            .is_some_and(|pkg_cat| cat.includes(pkg_cat))
+                    })
+            }
+            // dependencies are only shown when asked for
+            None => {
+                selector.is_empty()
+                    && args.category.is_none()
+                    && (is_explicit || args.all || args.packages.is_some())
+            }
+        };
+        if shown {
+            history.push((event, pkg, installed));
+        }
+    }
+    let skip = history
+        .len()
+        .saturating_sub(args.limit.unwrap_or(usize::MAX));
+    history.drain(..skip);
+    history
+}
+
+pub fn history_cmd(app: &App, args: &HistoryArgs) -> Result<()> {
+    let log_file = &app.config.pacman_log_file;
+    if !log_file.exists() {
+        return Err(coded(
+            "log_not_found",
+            format!("pacman log {} not found", log_file.display()),
+        ));
+    }
+    let events = read_log(log_file)?;
+    let declared = get_declared_pkg_list(app)?;
+    // installed package name to whether it is installed explicitly
+    let local: HashMap<String, bool> = app
+        .backend
+        .query_local()?
+        .into_iter()
+        .map(|pkg| (pkg.name, pkg.explicit))
+        .collect();
+    let history = select_history(args, &events, &declared, &local);
+
+    if app.config.output == OutputFormat::Json {
+        let history: Vec<HistoryJson> = history
+            .iter()
+            .map(|(event, pkg, installed)| HistoryJson {
+                event,
+                declared: pkg.is_some(),
+                installed: installed.is_some(),
+                explicit: *installed == Some(true),
+                category: pkg.and_then(|pkg| pkg.category.as_ref().map(Category::full_path)),
+                tags: pkg.map(|pkg| pkg.resolved_tags()).unwrap_or_default(),
+            })
+            .collect();
+        return print_json(&history);
+    }
+
+    if history.is_empty() {
+        println!("{}", "No matching events in pacman log".dimmed());
+    }
+    for (event, pkg, installed) in history {
+        let (action, color, versions) = match &event.action {
+            LogAction::Installed { version, .. } => ("installed", Color::Green, version.clone()),
+            LogAction::Removed { version, .. } => ("removed", Color::Red, version.clone()),
+            LogAction::Reinstalled { version, .. } => ("reinstalled", Color::Cyan, version.clone()),
+            LogAction::Upgraded { from, to, .. } => {
+                ("upgraded", Color::Blue, format!("{from} -> {to}"))
+            }
+            LogAction::Downgraded { from, to, .. } => {
+                ("downgraded", Color::Yellow, format!("{from} -> {to}"))
+            }
+            _ => continue,
+        };
+        let location = match pkg {
+            Some(pkg) => pkg
+                .category
+                .as_ref()
+                .map_or("(no category)".to_string(), Category::full_path)
+                .blue(),
+            None => match installed {
+                Some(true) => "not declared".yellow(),
+                Some(false) => "dependency".dimmed(),
+                None => "not installed".dimmed(),
+            },
+        };
+        println!(
+            "{} {} {} {} {}",
+            event
+                .timestamp
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+                .dimmed(),
+            format!("{action:<11}").color(color),
+            event.action.package().unwrap_or_default(),
+            versions.dimmed(),
+            location
+        );
+    }
+    Ok(())
+}
+
+pub fn hosts_cmd(app: &App, args: &HostsArgs) -> Result<()> {
+    let hosts: Vec<String> = match &args.hosts {
+        Some(hosts) => hosts.clone(),
+        None => {
+            let mut hosts = collect_hosts(app);
+            hosts.insert(app.config.host.clone());
+            hosts.into_iter().collect()
+        }
+    };
+
+    let mut host_pkgs = Vec::new();
+    for host in &hosts {
+        host_pkgs.push(get_host_pkg_list(app, host)?);
+    }
+    // packages declared for every shown host are not host specific
+    let common: HashSet<&Package> = host_pkgs
+        .first()
+        .map(|first| {
+            first
+                .iter()
+                .filter(|pkg| host_pkgs.iter().all(|pkgs| pkgs.contains(pkg)))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut hosts_json = BTreeMap::new();
+    for (host, pkgs) in hosts.iter().zip(&host_pkgs) {
+        let total = pkgs.len();
+        let mut pkgs: Vec<Package> = pkgs
+            .iter()
+            .filter(|pkg| args.all || !common.contains(pkg))
+            .cloned()
+            .collect();
+        pkgs.sort();
+
+        if app.config.output == OutputFormat::Json {
+            hosts_json.insert(
+                host,
+                serde_json::json!({
+                    "current": *host == app.config.host,
+                    "total": total,
+                    "host_specific": total - common.len(),
+                    "packages": packages_json(&pkgs),
+                }),
+            );
+            continue;
+        }
+        let current = if *host == app.config.host {
+            " (current)".dimmed()
+        } else {
+            "".into()
+        };
+        println!(
+            "{}{}: {} packages, {} host specific",
+            host.blue().bold(),
+            current,
+            total.to_string().green(),
+            total - common.len()
+        );
+        if !pkgs.is_empty() {
+            println!("{}", pkgs.join(" "));
+        }
+        println!();
+    }
+
+    if app.config.output == OutputFormat::Json {
+        return print_json(&hosts_json);
+    }
+    Ok(())
+}
+
+/// Splits packages into install rounds, a subpackage goes to a later round than any of `pkgs` it is
+/// nested in
+fn install_rounds(pkgs: &[Package]) -> Vec<Vec<Package>> {
+    let names: HashSet<&str> = pkgs.iter().map(|pkg| pkg.name.as_str()).collect();
+    let mut rounds: Vec<Vec<Package>> = Vec::new();
+    for pkg in pkgs {
+        let round = pkg
+            .parents
+            .iter()
+            .filter(|parent| names.contains(parent.as_str()))
+            .count();
+        if rounds.len() <= round {
+            rounds.resize(round + 1, Vec::new());
+        }
+        rounds[round].push(pkg.clone());
+    }
+    rounds.retain(|round| !round.is_empty());
+    rounds
+}
+
+fn uninstall_pkgs(app: &App, pkgs: &[Package]) -> Result<()> {
+    let pkgs: Vec<String> = pkgs.iter().map(|pkg| pkg.to_string()).collect();
+    app.backend.remove(&pkgs)
+}
+
+/// Installs packages translating their `:options` into install flags, packages needing different flags
+/// are installed in separate transactions
+fn install_pkgs(app: &App, pkgs: &[Package]) -> Result<()> {
+    let backend = &app.backend;
+
+    let ignored: Vec<String> = get_declared_pkg_list(app)?
+        .into_iter()
+        .filter(|pkg| pkg.options.ignore_upgrade && !pkgs.contains(pkg))
+        .map(|pkg| pkg.name)
+        .collect();
+    let as_deps = InstallOptions {
+        as_dependency: true,
+        needed: true,
+        ignore: &ignored,
+    };
+
+    // providers are installed first, so that pacman does not prompt to choose one
+    let providers: Vec<String> = pkgs
+        .iter()
+        .filter_map(|pkg| pkg.options.provider.clone())
+        .collect();
+    if !providers.is_empty() {
+        backend.install(&providers, &as_deps)?;
+    }
+
+    let mut transactions: BTreeMap<(bool, bool), Vec<String>> = BTreeMap::new();
+    for pkg in pkgs {
+        transactions
+            .entry((pkg.options.as_dependency, pkg.options.needed))
+            .or_default()
+            .push(pkg.to_string());
+    }
+    for ((as_dependency, needed), names) in transactions {
+        let options = InstallOptions {
+            as_dependency,
+            needed,
+            ignore: &ignored,
+        };
+        backend.install(&names, &options)?;
+    }
+
+    let mut optional_deps = Vec::new();
+    for pkg in pkgs.iter().filter(|pkg| pkg.options.full) {
+        if let Some(info) = backend.sync_info(&pkg.to_string())? {
+            optional_deps.extend(info.optional_deps);
+        }
+    }
+    if !optional_deps.is_empty() {
+        backend.install(&optional_deps, &as_deps)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::error_code;
+    use crate::packages::TagSelector;
+    use crate::testing::TestDir;
+
+    const FIXTURE: &str = r#"
+installed {
+    base
+}
+sync {
+    core {
+        base
+        newpkg
+    }
+}
+"#;
+
+    #[test]
+    fn failing_after_hook_keeps_installed_package_declared() {
+        let test = TestDir::new(
+            "cat:base {\n    hook:after_install \"false\"\n    base\n}\n",
+            FIXTURE,
+        );
+        let cli = test.cli(&["install", "newpkg", "-c", "base"]);
+        let mut app = test.app(&cli);
+
+        let err = add_cmd(&mut app, cli, true).unwrap_err();
+
+        assert!(err.to_string().contains("hook"), "{err}");
+        assert!(
+            app.backend
+                .query_installed()
+                .unwrap()
+                .contains(&"newpkg".to_string())
+        );
+        assert!(test.read("packages.kdl").contains("newpkg"));
+    }
+
+    #[test]
+    fn failing_after_hook_keeps_uninstalled_package_out_of_declaration() {
+        let test = TestDir::new(
+            "cat:base {\n    hook:after_remove \"false\"\n    base\n    newpkg\n}\n",
+            &FIXTURE.replace("    base\n}", "    base\n    newpkg\n}"),
+        );
+        let cli = test.cli(&["uninstall", "newpkg"]);
+        let mut app = test.app(&cli);
+
+        assert!(remove_cmd(&mut app, cli, true).is_err());
+        assert!(
+            !app.backend
+                .query_installed()
+                .unwrap()
+                .contains(&"newpkg".to_string())
+        );
+        assert!(!test.read("packages.kdl").contains("newpkg"));
+    }
+
+    const SYSTEM: &str = r#"
+installed {
+    keep {
+        depends lib
+    }
+    lib
+    extra {
+        depends leftover
+    }
+    leftover reason="dependency"
+    orphan reason="dependency"
+    dep reason="dependency"
+    tool
+}
+sync {
+    core {
+        keep
+        lib
+        extra
+        leftover
+        orphan
+        dep
+        tool
+        missing {
+            depends orphan
+        }
+        game
+    }
+}
+"#;
+
+    const DECLARATION: &str = r#"cat:base {
+    keep
+    dep
+    tool {
+        :options {
+            reason "dependency"
+        }
+    }
+    missing
+    game gui
+}
+"#;
+
+    fn names(pkgs: &[Package]) -> Vec<&str> {
+        pkgs.iter().map(|pkg| pkg.name.as_str()).collect()
+    }
+
+    fn sorted(mut pkgs: Vec<String>) -> Vec<String> {
+        pkgs.sort();
+        pkgs
+    }
+
+    #[test]
+    fn diff_sorts_packages_by_change() {
+        let test = TestDir::new(DECLARATION, SYSTEM);
+        let app = test.app(&test.cli(&["sync"]));
+
+        let diff = get_pkg_diff(&app, &TagSelector::new(&["!gui".to_string()], &[])).unwrap();
+
+        assert_eq!(names(&diff.installed_only), ["extra"]);
+        assert_eq!(names(&diff.declared_only), ["missing"]);
+        assert_eq!(names(&diff.explicit_deps), ["tool"]);
+        assert_eq!(names(&diff.installed_as_deps), ["dep"]);
+        assert_eq!(names(&diff.required_undeclared), ["lib"]);
+        assert!(diff.excluded.is_empty());
+
+        let diff = get_pkg_diff(&app, &TagSelector::default()).unwrap();
+        let mut declared_only = names(&diff.declared_only);
+        declared_only.sort();
+        assert_eq!(declared_only, ["game", "missing"]);
+    }
+
+    #[test]
+    fn orphans_needed_by_new_packages_are_kept() {
+        let test = TestDir::new(DECLARATION, SYSTEM);
+        let app = test.app(&test.cli(&["sync"]));
+
+        let diff = get_pkg_diff(&app, &TagSelector::default()).unwrap();
+
+        // dependencies of uninstalled `extra` go away with it
+        assert!(names(&get_orphans(&app, &diff).unwrap()).is_empty());
+
+        let test = TestDir::new(&DECLARATION.replace("    missing\n", ""), SYSTEM);
+        let app = test.app(&test.cli(&["sync"]));
+        let diff = get_pkg_diff(&app, &TagSelector::default()).unwrap();
+        assert_eq!(names(&get_orphans(&app, &diff).unwrap()), ["orphan"]);
+    }
+
+    #[test]
+    fn sync_changes_install_reasons_and_prunes_orphans() {
+        let test = TestDir::new(&DECLARATION.replace("    missing\n", ""), SYSTEM);
+        let app = test.app(&test.cli(&["sync", "--prune-orphans"]));
+        let diff = get_pkg_diff(&app, &TagSelector::default()).unwrap();
+        let orphans = get_orphans(&app, &diff).unwrap();
+
+        apply_sync(&app, &SyncPlan::new(diff, orphans, true)).unwrap();
+
+        assert_eq!(
+            sorted(app.backend.query_explicit().unwrap()),
+            ["dep", "game", "keep"]
+        );
+        // fake backend does not remove dependencies of removed packages like `pacman -Rns`
+        assert_eq!(
+            sorted(app.backend.query_dependencies().unwrap()),
+            ["leftover", "lib", "tool"]
+        );
+        let diff = get_pkg_diff(&app, &TagSelector::default()).unwrap();
+        assert!(SyncPlan::new(diff, Vec::new(), true).is_empty());
+    }
+
+    #[test]
+    fn orphans_are_kept_without_pruning() {
+        let test = TestDir::new(&DECLARATION.replace("    missing\n", ""), SYSTEM);
+        let app = test.app(&test.cli(&["sync"]));
+        let diff = get_pkg_diff(&app, &TagSelector::default()).unwrap();
+        let orphans = get_orphans(&app, &diff).unwrap();
+
+        apply_sync(&app, &SyncPlan::new(diff, orphans, false)).unwrap();
+
+        assert!(
+            app.backend
+                .query_dependencies()
+                .unwrap()
+                .contains(&"orphan".to_string())
+        );
+    }
+
+    #[test]
+    fn add_and_remove_write_declaration_without_touching_system() {
+        let test = TestDir::new(DECLARATION, SYSTEM);
+        let cli = test.cli(&["add", "extra", "-c", "base"]);
+        let mut app = test.app(&cli);
+        let installed = sorted(app.backend.query_installed().unwrap());
+
+        add_cmd(&mut app, cli, false).unwrap();
+        assert!(test.read("packages.kdl").contains("    extra\n"));
+
+        let cli = test.cli(&["remove", "keep", "dep"]);
+        let mut app = test.app(&cli);
+        remove_cmd(&mut app, cli, false).unwrap();
+        let declaration = test.read("packages.kdl");
+        assert!(!declaration.contains("keep"));
+        assert!(!declaration.contains("dep\n"));
+        assert!(declaration.contains("    extra\n"));
+
+        assert_eq!(sorted(app.backend.query_installed().unwrap()), installed);
+    }
+
+    const NESTED: &str =
+        "cat:dev {\n    cat:rust {\n        cargo\n    }\n    git\n}\ncat:uncat {}\n";
+
+    #[test]
+    fn add_to_existing_nested_category() {
+        let test = TestDir::new(NESTED, FIXTURE);
+
+        crate::run(test.cli(&["add", "newpkg", "-c", "rust"])).unwrap();
+
+        assert_eq!(
+            test.read("packages.kdl"),
+            "cat:dev {\n    cat:rust {\n        cargo\n        newpkg\n    }\n    git\n}\ncat:uncat {}\n"
+        );
+    }
+
+    #[test]
+    fn add_to_missing_category_suggests_similar_ones() {
+        let error = |category: &str| {
+            let test = TestDir::new(NESTED, FIXTURE);
+            let error = crate::run(test.cli(&["add", "newpkg", "-c", category]))
+                .err()
+                .unwrap();
+            assert_eq!(error_code(&error), "category_not_found");
+            assert_eq!(test.read("packages.kdl"), NESTED);
+            error.to_string()
+        };
+
+        assert_eq!(
+            error("dev/rsut"),
+            "category 'dev/rsut' not found, did you mean 'dev/rust'?"
+        );
+        assert_eq!(
+            error("rsut"),
+            "category 'rsut' not found, did you mean 'dev/rust'?"
+        );
+        // categories are only created after confirmation
+        assert_eq!(
+            error("dev/rust/new"),
+            "category 'dev/rust/new' not found, did you mean 'dev/rust'?"
+        );
+        assert_eq!(
+            error("dve"),
+            "category 'dve' not found, did you mean 'dev'?"
+        );
+        assert_eq!(error("zzz"), "category 'zzz' not found");
+    }
+
+    const HISTORY_LOG: &str = "\
+[2024-01-10T10:00:00+0000] [ALPM] installed lib (1.0-1)
+[2024-01-10T10:00:01+0000] [ALPM] installed git (2.0-1)
+[2024-01-11T10:00:00+0000] [PACMAN] Running 'pacman -S vim'
+[2024-01-12T10:00:00+0000] [ALPM] installed vim (9.0-1)
+[2024-01-13T10:00:00+0000] [ALPM] installed cargo (1.0-1)
+[2024-01-14T10:00:00+0000] [ALPM] upgraded git (2.0-1 -> 2.1-1)
+[2024-01-15T10:00:00+0000] [ALPM] removed cargo (1.0-1)
+";
+
+    /// `date action package` of events `history` with `args` shows
+    fn history(args: &[&str]) -> Vec<String> {
+        let test = TestDir::new(
+            "cat:dev {\n    git\n    cat:rust {\n        cargo\n    }\n}\ncat:uncat {}\n",
+            "installed {\n    git\n    vim\n    lib reason=\"dependency\"\n}\n",
+        );
+        test.write("pacman.log", HISTORY_LOG);
+        let log = test.path("pacman.log");
+        let mut argv = vec!["history", "--log-file", log.to_str().unwrap()];
+        argv.extend(args);
+        let cli = test.cli(&argv);
+        let app = test.app(&cli);
+        let Commands::History(args) = &cli.command else {
+            unreachable!()
+        };
+
+        let events = read_log(&app.config.pacman_log_file).unwrap();
+        let declared = get_declared_pkg_list(&app).unwrap();
+        let local: HashMap<String, bool> = app
+            .backend
+            .query_local()
+            .unwrap()
+            .into_iter()
+            .map(|pkg| (pkg.name, pkg.explicit))
+            .collect();
+        select_history(args, &events, &declared, &local)
+            .into_iter()
+            .map(|(event, _, _)| {
+                format!(
+                    "{} {} {}",
+                    event.timestamp.date_naive(),
+                    serde_json::to_value(&event.action).unwrap()["action"]
+                        .as_str()
+                        .unwrap(),
+                    event.action.package().unwrap()
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn history_shows_declared_and_explicit_packages_in_log_order() {
+        assert_eq!(
+            history(&[]),
+            [
+                "2024-01-10 installed git",
+                "2024-01-12 installed vim",
+                "2024-01-13 installed cargo",
+                "2024-01-14 upgraded git",
+                "2024-01-15 removed cargo",
+            ]
+        );
+        assert_eq!(
+            history(&["--all"])[..2],
+            ["2024-01-10 installed lib", "2024-01-10 installed git"]
+        );
+        assert_eq!(
+            history(&["-n", "2"]),
+            ["2024-01-14 upgraded git", "2024-01-15 removed cargo"]
+        );
+    }
+
+    #[test]
+    fn history_is_filtered() {
+        assert_eq!(
+            history(&["--since", "2024-01-12", "--until", "2024-01-14"]),
+            [
+                "2024-01-12 installed vim",
+                "2024-01-13 installed cargo",
+                "2024-01-14 upgraded git",
+            ]
+        );
+        assert_eq!(
+            history(&["-c", "dev/rust"]),
+            ["2024-01-13 installed cargo", "2024-01-15 removed cargo"]
+        );
+        assert_eq!(history(&["--undeclared"]), ["2024-01-12 installed vim"]);
+        assert_eq!(
+            history(&["git", "lib"]),
+            [
+                "2024-01-10 installed lib",
+                "2024-01-10 installed git",
+                "2024-01-14 upgraded git",
+            ]
+        );
+    }
+
+    #[test]
+    fn install_groups_transactions_by_options() {
+        let declaration = r#"cat:dev {
+    a
+    b {
+        :options {
+            needed #true
+        }
+    }
+    c {
+        :options {
+            reason "dependency"
+        }
+    }
+    d {
+        :options {
+            reason "dependency"
+            needed #true
+        }
+    }
+    e {
+        :options {
+            type "full"
+        }
+    }
+    f {
+        :options {
+            provider "prov"
+        }
+    }
+    g {
+        :options {
+            ignore_upgrade #true
+        }
+    }
+}
+"#;
+        let fixture = r#"
+installed {
+    g
+}
+sync {
+    extra {
+        a
+        b
+        c
+        d
+        e {
+            optional x y
+        }
+        f {
+            depends virtual
+        }
+        prov {
+            provides virtual
+        }
+        x
+        y
+        g
+    }
+}
+"#;
+        let test = TestDir::new(declaration, fixture);
+        let app = test.app(&test.cli(&["sync"]));
+        let mut pkgs: Vec<Package> = get_declared_pkg_list(&app)
+            .unwrap()
+            .into_iter()
+            .filter(|pkg| pkg.name != "g")
+            .collect();
+        pkgs.sort();
+
+        install_pkgs(&app, &pkgs).unwrap();
+
+        assert_eq!(
+            app.backend.transactions(),
+            [
+                // provider comes first so that pacman does not ask which one to install
+                "install --asdeps --needed --ignore g prov",
+                "install --asexplicit --ignore g a e f",
+                "install --asexplicit --needed --ignore g b",
+                "install --asdeps --ignore g c",
+                "install --asdeps --needed --ignore g d",
+                // optional dependencies of `type "full"` packages
+                "install --asdeps --needed --ignore g x y",
+            ]
+        );
+        assert_eq!(
+            sorted(app.backend.query_explicit().unwrap()),
+            ["a", "b", "e", "f", "g"]
+        );
+    }
+
+    /// Declaration with hooks of every kind on `cat:base`, each appends its name to `hooks.log`
+    fn hooked_declaration(test: &TestDir, packages: &str) -> String {
+        let log = test.path("hooks.log");
+        let hook = |kind: &str| format!("    hook:{kind} \"echo {kind} >> '{}'\"\n", log.display());
+        format!(
+            "cat:base {{\n{}{}{}{}{}{packages}}}\n",
+            hook("before_install"),
+            hook("after_install"),
+            hook("before_remove"),
+            hook("after_remove"),
+            hook("after_sync"),
+        )
+    }
+
+    fn hook_log(test: &TestDir) -> Vec<String> {
+        fs::read_to_string(test.path("hooks.log"))
+            .unwrap_or_default()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn hooks_run_around_transactions_in_order() {
+        let test = TestDir::new("", SYSTEM);
+        test.write(
+            "packages.kdl",
+            &hooked_declaration(&test, "    keep\n    missing\n"),
+        );
+        let app = test.app(&test.cli(&["sync"]));
+        let diff = get_pkg_diff(&app, &TagSelector::default()).unwrap();
+        apply_sync(&app, &SyncPlan::new(diff, Vec::new(), false)).unwrap();
+        assert_eq!(
+            hook_log(&test),
+            ["before_install", "after_install", "after_sync"]
+        );
+
+        fs::remove_file(test.path("hooks.log")).unwrap();
+        let cli = test.cli(&["install", "extra", "-c", "base"]);
+        let mut app = test.app(&cli);
+        add_cmd(&mut app, cli, true).unwrap();
+        assert_eq!(
+            hook_log(&test),
+            ["before_install", "after_install", "after_sync"]
+        );
+
+        fs::remove_file(test.path("hooks.log")).unwrap();
+        let cli = test.cli(&["uninstall", "extra"]);
+        let mut app = test.app(&cli);
+        remove_cmd(&mut app, cli, true).unwrap();
+        assert_eq!(hook_log(&test), ["before_remove", "after_remove"]);
+    }
+
+    #[test]
+    fn failing_before_hook_stops_transaction() {
+        let test = TestDir::new(
+            "cat:base {\n    hook:before_install \"false\"\n    base\n}\n",
+            FIXTURE,
+        );
+        let cli = test.cli(&["install", "newpkg", "-c", "base"]);
+        let mut app = test.app(&cli);
+
+        assert!(add_cmd(&mut app, cli, true).is_err());
+        assert!(
+            !app.backend
+                .query_installed()
+                .unwrap()
+                .contains(&"newpkg".to_string())
+        );
+        assert!(!test.read("packages.kdl").contains("newpkg"));
+    }
+
+    #[test]
+    fn failed_check_is_returned_in_both_output_formats() {
+        let test = TestDir::new(
+            "cat:base {\n    foo {\n        :options {\n            reason \"maybe\"\n        }\n    }\n}\n",
+            FIXTURE,
+        );
+        for output in ["text", "json"] {
+            let cli = test.cli(&["--output", output, "check", "--offline"]);
+            let mut app = test.app(&cli);
+            let Commands::Check(args) = &cli.command else {
+                unreachable!()
+            };
+
+            let err = check_cmd(&mut app, args).unwrap_err();
+
+            assert_eq!(error_code(&err), "check_failed", "{output}");
+            assert_eq!(err.is::<Reported>(), output == "json");
+        }
+    }
+
+    const SUBPACKAGES: &str = r#"
+installed {
+}
+sync {
+    extra {
+        python
+        uv
+        ruff
+        black
+    }
+}
+"#;
+
+    #[test]
+    fn subpackages_go_to_later_rounds_than_their_parents() {
+        let test = TestDir::new(
+            "cat:dev {\n    python {\n        uv {\n            ruff\n        }\n        black\n    }\n}\n",
+            SUBPACKAGES,
+        );
+        let app = test.app(&test.cli(&["sync"]));
+        let mut declared: Vec<Package> = get_declared_pkg_list(&app).unwrap().into_iter().collect();
+        declared.sort();
+        let rounds = |pkgs: &[Package]| -> Vec<Vec<String>> {
+            install_rounds(pkgs)
+                .iter()
+                .map(|round| round.iter().map(|pkg| pkg.name.clone()).collect())
+                .collect()
+        };
+
+        assert_eq!(
+            rounds(&declared),
+            [vec!["python"], vec!["black", "uv"], vec!["ruff"]]
+        );
+        // installed parent does not hold its subpackages back
+        declared.retain(|pkg| pkg.name != "python");
+        assert_eq!(rounds(&declared), [vec!["black", "uv"], vec!["ruff"]]);
+        declared.retain(|pkg| pkg.name != "uv");
+        assert_eq!(rounds(&declared), [vec!["black", "ruff"]]);
+    }
+
+    #[test]
+    fn sync_installs_parents_before_subpackages() {
+        let test = TestDir::new("", SUBPACKAGES);
+        let log = test.path("hooks.log");
+        let hook = |kind: &str, pkg: &str| {
+            format!("hook:{kind} \"echo {kind} {pkg} >> '{}'\"", log.display())
+        };
+        test.write(
+            "packages.kdl",
+            &format!(
+                "cat:dev {{\n    ruff {{\n        {}\n    }}\n    python {{\n        {}\n        {}\n        uv {{\n            {}\n            {}\n            ruff\n        }}\n    }}\n}}\n",
+                hook("before_install", "ruff"),
+                hook("before_install", "python"),
+                hook("after_install", "python"),
+                hook("before_install", "uv"),
+                hook("after_install", "uv"),
+            ),
+        );
+        let app = test.app(&test.cli(&["sync"]));
+        let diff = get_pkg_diff(&app, &TagSelector::default()).unwrap();
+
+        apply_sync(&app, &SyncPlan::new(diff, Vec::new(), false)).unwrap();
+
+        // `ruff` is declared at top level as well, it is not held back by `uv`
+        let mut log = hook_log(&test);
+        log[..2].sort();
+        assert_eq!(
+            log,
+            [
+                "before_install python",
+                "before_install ruff",
+                "after_install python",
+                "before_install uv",
+                "after_install uv",
+            ]
+        );
+        assert_eq!(
+            sorted(app.backend.query_explicit().unwrap()),
+            ["python", "ruff", "uv"]
+        );
+    }
+
+    #[test]
+    fn disabled_parent_disables_its_subpackages() {
+        let test = TestDir::new(
+            "cat:dev {\n    python {\n        python-pyqt6 gui\n        uv\n    }\n    zed gui\n}\n",
+            &SUBPACKAGES.replace("        black\n", "        python-pyqt6\n        zed\n"),
+        );
+        test.write("config.kdl", "packages {\n    whitelist gui\n}\n");
+        let app = test.app(&test.cli(&["sync"]));
+
+        let diff = get_pkg_diff(&app, &TagSelector::default()).unwrap();
+
+        assert_eq!(names(&diff.declared_only), ["zed"]);
+        let excluded: Vec<(&str, String)> = diff
+            .excluded
+            .iter()
+            .map(|(pkg, exclusion)| (pkg.name.as_str(), exclusion.to_string()))
+            .collect();
+        assert_eq!(
+            excluded,
+            [
+                ("python", Exclusion::NotWhitelisted.to_string()),
+                (
+                    "python-pyqt6",
+                    Exclusion::ParentDisabled("python".to_string()).to_string()
+                ),
+                ("uv", Exclusion::NotWhitelisted.to_string()),
+            ]
+        );
+
+        apply_sync(&app, &SyncPlan::new(diff, Vec::new(), false)).unwrap();
+        assert_eq!(app.backend.query_installed().unwrap(), ["zed"]);
+    }
+
+    #[test]
+    fn move_takes_only_selected_declarations_of_package() {
+        let test = TestDir::new(
+            "cat:base {\n    htop hosts=\"desktop\"\n}\ncat:uncat {\n    htop hosts=\"laptop\"\n}\ncat:dev {\n}\n",
+            FIXTURE,
+        );
+
+        crate::run(test.cli(&["mv", "--from-cat", "uncat", "--to-cat", "dev"])).unwrap();
+
+        assert_eq!(
+            test.read("packages.kdl"),
+            "cat:base {\n    htop hosts=\"desktop\"\n}\ncat:uncat {}\ncat:dev {\n    htop hosts=\"laptop\"\n}\n"
+        );
+    }
+
+    #[test]
+    fn cat_rename_updates_file_categories() {
+        let test = TestDir::new(
+            "@include \"rust.kdl\"\ncat:dev {\n    cat:tools {\n        git\n    }\n}\n",
+            FIXTURE,
+        );
+        test.write("rust.kdl", "@file \"cat:dev/rust\"\ncargo\n");
+
+        crate::run(test.cli(&["cat", "rename", "dev", "work"])).unwrap();
+
+        assert_eq!(
+            test.read("packages.kdl"),
+            "@include \"rust.kdl\"\ncat:work {\n    cat:tools {\n        git\n    }\n}\n"
+        );
+        assert_eq!(test.read("rust.kdl"), "@file \"cat:work/rust\"\ncargo\n");
+    }
+
+    #[test]
+    fn cat_rm_moves_content_to_another_category() {
+        let test = TestDir::new(
+            "cat:base {\n    git\n}\ncat:old tag {\n    htop\n    cat:sub {\n        vim\n    }\n}\n",
+            FIXTURE,
+        );
+
+        crate::run(test.cli(&["cat", "rm", "old", "--move-to", "base"])).unwrap();
+
+        assert_eq!(
+            test.read("packages.kdl"),
+            "cat:base {\n    git\n    htop\n    cat:sub {\n        vim\n    }\n}\n"
+        );
+    }
+
+    #[test]
+    fn cat_rm_uninstalls_content() {
+        let test = TestDir::new(
+            "cat:base {\n    base\n}\ncat:old {\n    newpkg\n    cat:sub {\n        htop\n    }\n}\n",
+            "installed {\n    base\n    newpkg\n    htop\n}\nsync {\n}\n",
+        );
+        let cli = test.cli(&["cat", "rm", "old", "--uninstall"]);
+        let mut app = test.app(&cli);
+        let Commands::Cat(args) = &cli.command else {
+            unreachable!()
+        };
+
+        cat_cmd(&mut app, args).unwrap();
+
+        assert_eq!(test.read("packages.kdl"), "cat:base {\n    base\n}\n");
+        assert_eq!(app.backend.query_installed().unwrap(), ["base"]);
+    }
+
+    #[test]
+    fn cat_merge_keeps_hosts_of_merged_content() {
+        let test = TestDir::new(
+            "cat:base {\n    git\n}\nhost:laptop {\n    cat:old {\n        hook:after_install \"true\"\n        htop\n    }\n}\n",
+            FIXTURE,
+        );
+
+        crate::run(test.cli(&["cat", "merge", "old", "base"])).unwrap();
+
+        assert_eq!(
+            test.read("packages.kdl"),
+            "cat:base {\n    git\n    htop hosts=\"laptop\"\n}\n"
+        );
+    }
+
+    #[test]
+    fn cat_extract_keeps_path_and_hosts() {
+        let test = TestDir::new(
+            "host:laptop {\n    cat:dev {\n        cat:rust {\n            cargo\n        }\n    }\n}\n",
+            FIXTURE,
+        );
+
+        // path is relative to the declaration, not to the working directory
+        crate::run(test.cli(&["cat", "extract", "dev/rust", "rust.kdl"])).unwrap();
+
+        assert_eq!(
+            test.read("packages.kdl"),
+            "@include \"rust.kdl\"\nhost:laptop {\n    cat:dev {}\n}\n"
+        );
+        assert_eq!(
+            test.read("rust.kdl"),
+            "@file \"cat:dev\"\ncat:rust hosts=\"laptop\" {\n    cargo\n}\n"
+        );
+    }
+
+    #[test]
+    fn cat_extract_includes_file_by_relative_path() {
+        let test = TestDir::new("cat:dev {\n    git\n}\ncat:web {\n    node\n}\n", FIXTURE);
+        test.write("sub/other.kdl", "");
+        let absolute = test.path("web.kdl");
+
+        crate::run(test.cli(&["cat", "extract", "dev", "sub/dev.kdl"])).unwrap();
+        crate::run(test.cli(&["cat", "extract", "web", &absolute.to_string_lossy()])).unwrap();
+
+        assert_eq!(
+            test.read("packages.kdl"),
+            "@include \"sub/dev.kdl\"\n@include \"web.kdl\"\n"
+        );
+        assert_eq!(test.read("sub/dev.kdl"), "cat:dev {\n    git\n}\n");
+        assert_eq!(test.read("web.kdl"), "cat:web {\n    node\n}\n");
+    }
+}
@@ -1,16 +1,62 @@
-use std::path::PathBuf;
+use std::cell::RefCell;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
 
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use kdl::{KdlDocument, KdlNode, KdlValue};
+use miette::{LabeledSpan, NamedSource, Severity, SourceSpan};
+
+use crate::cli::{CatArgs, CatCommands, CatRemoveArgs, Cli, Commands, RemoveArgs, UninstallArgs};
+use crate::output::{OutputFormat, coded};
 use crate::packages::Category;
 
+/// Content written to `config.kdl` when it is created by `pacdec gen`. Every key is optional.
+pub const DEFAULT_CONFIG: &str = r#"// declaration_file "~/.config/pacdec/packages.kdl"
+// pacman_log_file "/var/log/pacman.log"
+// db_path "/var/lib/pacman"
+// default_category "uncat"
+// removal "delete" // delete, comment or graveyard
+// package_manager "paru" // pacman, paru or yay
+// dry_run #true
+// verbose #true
+// host "laptop" // defaults to content of /etc/hostname
+// keep_orphans "pkg1" "pkg2" // never removed by sync --prune-orphans
+// backup {
+//     dir ".backups"
+//     mode "basic" // off, basic or git
+// }
+// packages {
+//     whitelist
+//     blacklist "off"
+// }
+// format {
+//     indent 4
+//     order "chronological" // chronological or alphabetical
+// }
+"#;
+
 pub struct Config {
     pub declaration_file: PathBuf,
     pub pacman_log_file: PathBuf,
+    /// Pacman database directory with `local` and `sync` databases
+    pub db_path: PathBuf,
     pub default_category: Category,
+    /// How `remove`, `uninstall` and `generate` take packages out of declaration
+    pub removal: RemovalStrategy,
     pub package_manager: String,
     pub dry_run: bool,
     pub verbose: bool,
+    /// Host name matched against `host:` nodes and `hosts` properties
+    pub host: String,
+    /// Packages installed as dependencies `sync --prune-orphans` keeps even if nothing needs them
+    pub keep_orphans: Vec<String>,
     pub backup: BackupConfig,
     pub packages: PackagesConfig,
+    pub format: FormatConfig,
+    /// Set only by `--output`
+    pub output: OutputFormat,
 }
 
 #[derive(Default)]
@@ -19,6 +65,65 @@ pub struct PackagesConfig {
     pub blacklist: Vec<String>,
 }
 
+/// Reason a declared package is disabled by `PackagesConfig` rules
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Exclusion {
+    Blacklisted(String),
+    NotWhitelisted,
+    /// Package the subpackage is nested in is disabled
+    ParentDisabled(String),
+}
+
+impl fmt::Display for Exclusion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Exclusion::Blacklisted(tag) => write!(f, "blacklisted tag '{tag}'"),
+            Exclusion::NotWhitelisted => write!(f, "no whitelisted tag"),
+            Exclusion::ParentDisabled(parent) => write!(f, "parent '{parent}' is disabled"),
+        }
+    }
+}
+
+impl PackagesConfig {
+    /// Returns why package or category with given resolved tags is disabled, blacklist takes precedence
+    pub fn exclusion(&self, tags: &[String]) -> Option<Exclusion> {
+        if let Some(tag) = self.blacklist.iter().find(|tag| tags.contains(tag)) {
+            return Some(Exclusion::Blacklisted(tag.clone()));
+        }
+        if !self.whitelist.is_empty() && !self.whitelist.iter().any(|tag| tags.contains(tag)) {
+            return Some(Exclusion::NotWhitelisted);
+        }
+        None
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RemovalStrategy {
+    /// Delete package node
+    #[default]
+    Delete,
+    /// Comment package node out with `/-`
+    Comment,
+    /// Move package node to `cat:removed` with removal date
+    Graveyard,
+}
+
+/// Layout of declaration files written by `fmt` and used for nodes added by other commands
+pub struct FormatConfig {
+    /// Spaces per indentation level
+    pub indent: usize,
+    pub order: PackageOrder,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PackageOrder {
+    /// Keep packages in the order they were added
+    #[default]
+    Chronological,
+    /// Sort packages in every category by name
+    Alphabetical,
+}
+
 pub struct BackupConfig {
     pub dir: String,
     pub mode: BackupMode,
@@ -34,10 +139,16 @@ impl Default for Config {
     fn default() -> Self {
         Self {
             default_category: "uncat".into(),
+            removal: RemovalStrategy::Delete,
             package_manager: "paru".into(),
             pacman_log_file: "/var/log/pacman.log".into(),
+            db_path: "/var/lib/pacman".into(),
             dry_run: true,
             verbose: true,
+            host: fs::read_to_string("/etc/hostname")
+                .map(|host| host.trim().to_string())
+                .unwrap_or_default(),
+            keep_orphans: Vec::new(),
             backup: BackupConfig {
                 dir: ".backups".into(),
                 mode: BackupMode::Basic,
@@ -49,6 +160,544 @@ impl Default for Config {
                 blacklist: vec!["off".into()],
                 ..Default::default()
             },
+            format: FormatConfig {
+                indent: 4,
+                order: PackageOrder::Chronological,
+            },
+            output: OutputFormat::Text,
+        }
+    }
+}
+
+impl Config {
+    /// Reads config from a KDL file, keys missing from the file keep their default values
+    pub fn load(path: &Path) -> Result<Self> {
+        let src = fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file: {}", path.display()))?;
+        let (config, warnings) = Self::parse(path, &src)?;
+        for warning in warnings {
+            eprintln!("{warning}");
+        }
+        Ok(config)
+    }
+
+    /// Parses config source read from `path`, returns it with rendered warnings about unknown keys
+    fn parse(path: &Path, src: &str) -> Result<(Self, Vec<String>)> {
+        let doc = src.parse::<KdlDocument>().map_err(|e| {
+            let report = miette::Report::new(e).with_source_code(src.to_string());
+            coded("config_invalid", format!("Failed to parse KDL: {report:?}"))
+                .context(format!("failed to parse config file: {}", path.display()))
+        })?;
+
+        let parser = ConfigParser {
+            path,
+            src,
+            warnings: RefCell::new(Vec::new()),
+        };
+        let mut config = Config::default();
+
+        for node in doc.nodes() {
+            match node.name().value() {
+                "declaration_file" => config.declaration_file = parser.path_arg(node)?,
+                "pacman_log_file" => config.pacman_log_file = parser.path_arg(node)?,
+                "db_path" => config.db_path = parser.path_arg(node)?,
+                "default_category" => config.default_category = parser.string_arg(node)?.into(),
+                "removal" => config.removal = parser.removal_arg(node)?,
+                "package_manager" => config.package_manager = parser.string_arg(node)?,
+                "dry_run" => config.dry_run = parser.bool_arg(node)?,
+                "verbose" => config.verbose = parser.bool_arg(node)?,
+                "host" => config.host = parser.string_arg(node)?,
+                "keep_orphans" => config.keep_orphans = parser.string_args(node)?,
+                "backup" => parser.parse_backup(node, &mut config.backup)?,
+                "packages" => parser.parse_packages(node, &mut config.packages)?,
+                "format" => parser.parse_format(node, &mut config.format)?,
+                _ => parser.warn_unknown(node),
+            }
+        }
+
+        Ok((config, parser.warnings.into_inner()))
+    }
+
+    /// Overrides config values with the ones given on the command line or through environment
+    pub fn apply_cli(&mut self, cli: &Cli) {
+        if let Some(declare) = &cli.declare {
+            self.declaration_file = declare.clone();
+        }
+        if let Some(log_file) = &cli.pacman_log_file {
+            self.pacman_log_file = log_file.clone();
+        }
+        if let Some(db_path) = &cli.db_path {
+            self.db_path = db_path.clone();
+        }
+        if let Some(package_manager) = &cli.package_manager {
+            self.package_manager = package_manager.clone();
+        }
+        if let Some(host) = &cli.host {
+            self.host = host.clone();
+        }
+        if cli.no_dry_run {
+            self.dry_run = false;
+        }
+        if cli.verbose {
+            self.verbose = true;
+        }
+        if cli.quiet {
+            self.verbose = false;
+        }
+        self.output = cli.output;
+        // stdout is reserved for json document
+        if self.output == OutputFormat::Json {
+            self.verbose = false;
+        }
+        match &cli.command {
+            Commands::Sync(args) if args.dry_run => self.dry_run = true,
+            Commands::Generate(args) if args.dry_run => self.dry_run = true,
+            Commands::Remove(RemoveArgs {
+                comment, strategy, ..
+            })
+            | Commands::Uninstall(UninstallArgs {
+                comment, strategy, ..
+            }) => {
+                if *comment {
+                    self.removal = RemovalStrategy::Comment;
+                }
+                if let Some(strategy) = strategy {
+                    self.removal = *strategy;
+                }
+            }
+            Commands::Cat(CatArgs {
+                command:
+                    CatCommands::Remove(CatRemoveArgs {
+                        strategy: Some(strategy),
+                        ..
+                    }),
+            }) => self.removal = *strategy,
+            Commands::Fmt(args) => {
+                if let Some(indent) = args.indent {
+                    self.format.indent = indent.into();
+                }
+                if let Some(order) = args.order {
+                    self.format.order = order;
+                }
+            }
+            _ => {}
         }
     }
 }
+
+struct ConfigParser<'a> {
+    path: &'a Path,
+    src: &'a str,
+    /// Rendered warnings, printed once the whole config is parsed
+    warnings: RefCell<Vec<String>>,
+}
+
+impl ConfigParser<'_> {
+    fn report(&self, severity: Severity, span: SourceSpan, label: &str, msg: &str) -> String {
+        let report = miette::miette!(
+            severity = severity,
+            labels = vec![LabeledSpan::at(span, label)],
+            "{msg}"
+        )
+        .with_source_code(NamedSource::new(
+            self.path.display().to_string(),
+            self.src.to_string(),
+        ));
+        format!("{report:?}")
+    }
+
+    fn error(&self, span: SourceSpan, label: &str, msg: &str) -> anyhow::Error {
+        coded(
+            "config_invalid",
+            format!(
+                "invalid config file {}\n{}",
+                self.path.display(),
+                self.report(Severity::Error, span, label, msg)
+            ),
+        )
+    }
+
+    fn warn_unknown(&self, node: &KdlNode) {
+        self.warnings.borrow_mut().push(self.report(
+            Severity::Warning,
+            node.name().span(),
+            "unknown key",
+            &format!("unknown config key '{}' is ignored", node.name().value()),
+        ));
+    }
+
+    fn single_arg<'n>(&self, node: &'n KdlNode) -> Result<&'n KdlValue> {
+        match node.entries() {
+            [entry] if entry.name().is_none() => Ok(entry.value()),
+            _ => Err(self.error(
+                node.span(),
+                "expected exactly one value",
+                &format!("'{}' takes exactly one argument", node.name().value()),
+            )),
+        }
+    }
+
+    fn string_arg(&self, node: &KdlNode) -> Result<String> {
+        let value = self.single_arg(node)?;
+        value.as_string().map(str::to_string).ok_or_else(|| {
+            self.error(
+                node.entries()[0].span(),
+                "expected a string",
+                &format!("'{}' must be a string", node.name().value()),
+            )
+        })
+    }
+
+    fn path_arg(&self, node: &KdlNode) -> Result<PathBuf> {
+        Ok(shellexpand::tilde(&self.string_arg(node)?).as_ref().into())
+    }
+
+    fn bool_arg(&self, node: &KdlNode) -> Result<bool> {
+        let value = self.single_arg(node)?;
+        value.as_bool().ok_or_else(|| {
+            self.error(
+                node.entries()[0].span(),
+                "expected #true or #false",
+                &format!("'{}' must be a boolean", node.name().value()),
+            )
+        })
+    }
+
+    fn removal_arg(&self, node: &KdlNode) -> Result<RemovalStrategy> {
+        match self.string_arg(node)?.as_str() {
+            "delete" => Ok(RemovalStrategy::Delete),
+            "comment" => Ok(RemovalStrategy::Comment),
+            "graveyard" => Ok(RemovalStrategy::Graveyard),
+            _ => Err(self.error(
+                node.entries()[0].span(),
+                "expected \"delete\", \"comment\" or \"graveyard\"",
+                "unknown removal strategy",
+            )),
+        }
+    }
+
+    fn string_args(&self, node: &KdlNode) -> Result<Vec<String>> {
+        node.entries()
+            .iter()
+            .map(|entry| match entry.value().as_string() {
+                Some(s) if entry.name().is_none() => Ok(s.to_string()),
+                _ => Err(self.error(
+                    entry.span(),
+                    "expected a string",
+                    &format!("'{}' only takes string arguments", node.name().value()),
+                )),
+            })
+            .collect()
+    }
+
+    fn parse_backup(&self, node: &KdlNode, backup: &mut BackupConfig) -> Result<()> {
+        for child in node.iter_children() {
+            match child.name().value() {
+                "dir" => backup.dir = self.string_arg(child)?,
+                "mode" => {
+                    backup.mode = match self.string_arg(child)?.as_str() {
+                        "off" => BackupMode::Off,
+                        "basic" => BackupMode::Basic,
+                        "git" => BackupMode::Git,
+                        _ => {
+                            return Err(self.error(
+                                child.entries()[0].span(),
+                                "expected \"off\", \"basic\" or \"git\"",
+                                "unknown backup mode",
+                            ));
+                        }
+                    }
+                }
+                _ => self.warn_unknown(child),
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_format(&self, node: &KdlNode, format: &mut FormatConfig) -> Result<()> {
+        for child in node.iter_children() {
+            match child.name().value() {
+                "indent" => {
+                    let value = self.single_arg(child)?;
+                    format.indent = match value.as_integer() {
+                        Some(indent @ 1..=16) => indent as usize,
+                        _ => {
+                            return Err(self.error(
+                                child.entries()[0].span(),
+                                "expected a number from 1 to 16",
+                                "invalid indent",
+                            ));
+                        }
+                    }
+                }
+                "order" => {
+                    format.order = match self.string_arg(child)?.as_str() {
+                        "chronological" => PackageOrder::Chronological,
+                        "alphabetical" => PackageOrder::Alphabetical,
+                        _ => {
+                            return Err(self.error(
+                                child.entries()[0].span(),
+                                "expected \"chronological\" or \"alphabetical\"",
+                                "unknown package order",
+                            ));
+                        }
+                    }
+                }
+                _ => self.warn_unknown(child),
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_packages(&self, node: &KdlNode, packages: &mut PackagesConfig) -> Result<()> {
+        for child in node.iter_children() {
+            match child.name().value() {
+                "whitelist" => packages.whitelist = self.string_args(child)?,
+                "blacklist" => packages.blacklist = self.string_args(child)?,
+                _ => self.warn_unknown(child),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::output::error_code;
+
+    fn parse(src: &str) -> Result<(Config, Vec<String>)> {
+        Config::parse(Path::new("config.kdl"), src)
+    }
+
+    fn parse_err(src: &str) -> anyhow::Error {
+        match parse(src) {
+            Ok(_) => panic!("config should be rejected:\n{src}"),
+            Err(e) => e,
+        }
+    }
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::try_parse_from([&["pacdec"], args].concat()).unwrap()
+    }
+
+    #[test]
+    fn empty_config_keeps_defaults() {
+        let (config, warnings) = parse("").unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(config.default_category, Category::from("uncat"));
+        assert_eq!(config.removal, RemovalStrategy::Delete);
+        assert_eq!(config.package_manager, "paru");
+        assert_eq!(config.pacman_log_file, PathBuf::from("/var/log/pacman.log"));
+        assert_eq!(config.db_path, PathBuf::from("/var/lib/pacman"));
+        assert!(config.dry_run);
+        assert!(config.verbose);
+        assert!(config.keep_orphans.is_empty());
+        assert_eq!(config.packages.blacklist, ["off"]);
+        assert!(config.packages.whitelist.is_empty());
+        assert_eq!(config.format.indent, 4);
+        assert_eq!(config.format.order, PackageOrder::Chronological);
+        assert!(matches!(config.backup.mode, BackupMode::Basic));
+        assert_eq!(config.backup.dir, ".backups");
+    }
+
+    #[test]
+    fn default_config_template_is_valid() {
+        let (_, warnings) = parse(DEFAULT_CONFIG).unwrap();
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn every_key_is_read() {
+        let (config, warnings) = parse(
+            r#"
+            declaration_file "/etc/pacdec/packages.kdl"
+            pacman_log_file "/tmp/pacman.log"
+            db_path "/tmp/db"
+            default_category "misc/new"
+            removal "graveyard"
+            package_manager "pacman"
+            dry_run #false
+            verbose #false
+            host "laptop"
+            keep_orphans "a" "b"
+            backup {
+                dir "bak"
+                mode "git"
+            }
+            packages {
+                whitelist "dev"
+                blacklist "games" "off"
+            }
+            format {
+                indent 2
+                order "alphabetical"
+            }
+            "#,
+        )
+        .unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(
+            config.declaration_file,
+            PathBuf::from("/etc/pacdec/packages.kdl")
+        );
+        assert_eq!(config.pacman_log_file, PathBuf::from("/tmp/pacman.log"));
+        assert_eq!(config.db_path, PathBuf::from("/tmp/db"));
+        assert_eq!(config.default_category, Category::from("misc/new"));
+        assert_eq!(config.removal, RemovalStrategy::Graveyard);
+        assert_eq!(config.package_manager, "pacman");
+        assert!(!config.dry_run);
+        assert!(!config.verbose);
+        assert_eq!(config.host, "laptop");
+        assert_eq!(config.keep_orphans, ["a", "b"]);
+        assert_eq!(config.backup.dir, "bak");
+        assert!(matches!(config.backup.mode, BackupMode::Git));
+        assert_eq!(config.packages.whitelist, ["dev"]);
+        assert_eq!(config.packages.blacklist, ["games", "off"]);
+        assert_eq!(config.format.indent, 2);
+        assert_eq!(config.format.order, PackageOrder::Alphabetical);
+    }
+
+    #[test]
+    fn unknown_keys_are_warned_about_and_ignored() {
+        let (config, warnings) =
+            parse("colour \"red\"\nremoval \"comment\"\nbackup {\n    keep 3\n}\n").unwrap();
+        assert_eq!(config.removal, RemovalStrategy::Comment);
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("unknown config key 'colour' is ignored"));
+        assert!(warnings[1].contains("unknown config key 'keep' is ignored"));
+    }
+
+    #[test]
+    fn invalid_values_are_rejected_with_their_location() {
+        let cases = [
+            ("removal \"shred\"", "unknown removal strategy"),
+            ("dry_run \"yes\"", "'dry_run' must be a boolean"),
+            ("host 1", "'host' must be a string"),
+            ("host \"a\" \"b\"", "'host' takes exactly one argument"),
+            ("backup {\n    mode \"cloud\"\n}", "unknown backup mode"),
+            (
+                "packages {\n    blacklist \"off\" 1\n}",
+                "only takes string arguments",
+            ),
+            ("format {\n    indent 0\n}", "invalid indent"),
+            ("format {\n    indent 17\n}", "invalid indent"),
+            ("format {\n    order \"random\"\n}", "unknown package order"),
+        ];
+        for (src, message) in cases {
+            let e = parse_err(src);
+            let text = format!("{e:#}");
+            assert_eq!(error_code(&e), "config_invalid", "{src}");
+            assert!(text.contains(message), "{src}: {text}");
+            assert!(text.contains("config.kdl"), "{src}: {text}");
+            // report shows the offending line
+            let line = src
+                .lines()
+                .find(|line| !line.ends_with(['{', '}']))
+                .unwrap();
+            assert!(text.contains(line.trim()), "{src}: {text}");
+        }
+    }
+
+    #[test]
+    fn kdl_syntax_errors_are_rejected() {
+        let e = parse_err("removal \"delete");
+        assert_eq!(error_code(&e), "config_invalid");
+        assert!(format!("{e:#}").contains("failed to parse config file: config.kdl"));
+    }
+
+    #[test]
+    fn command_line_overrides_config_file() {
+        let (mut config, _) = parse(
+            "declaration_file \"/a.kdl\"\nhost \"desk\"\npackage_manager \"yay\"\nremoval \"graveyard\"\n",
+        )
+        .unwrap();
+        config.apply_cli(&cli(&[
+            "--declare",
+            "/b.kdl",
+            "--host",
+            "laptop",
+            "--package-manager",
+            "pacman",
+            "--db-path",
+            "/db",
+            "--log-file",
+            "/log",
+            "--no-dry-run",
+            "--quiet",
+            "rm",
+            "--comment",
+            "foo",
+        ]));
+        assert_eq!(config.declaration_file, PathBuf::from("/b.kdl"));
+        assert_eq!(config.host, "laptop");
+        assert_eq!(config.package_manager, "pacman");
+        assert_eq!(config.db_path, PathBuf::from("/db"));
+        assert_eq!(config.pacman_log_file, PathBuf::from("/log"));
+        assert!(!config.dry_run);
+        assert!(!config.verbose);
+        assert_eq!(config.removal, RemovalStrategy::Comment);
+    }
+
+    #[test]
+    fn missing_flags_keep_config_file_values() {
+        let (mut config, _) =
+            parse("declaration_file \"/a.kdl\"\ndry_run #false\nverbose #false\n").unwrap();
+        config.apply_cli(&cli(&["list"]));
+        assert_eq!(config.declaration_file, PathBuf::from("/a.kdl"));
+        assert!(!config.dry_run);
+        assert!(!config.verbose);
+    }
+
+    #[test]
+    fn command_flags_override_config_file() {
+        let (mut config, _) = parse("dry_run #false\nformat {\n    indent 8\n}\n").unwrap();
+        config.apply_cli(&cli(&["sync", "-n"]));
+        assert!(config.dry_run);
+
+        config.apply_cli(&cli(&["fmt", "--indent", "2", "--order", "alphabetical"]));
+        assert_eq!(config.format.indent, 2);
+        assert_eq!(config.format.order, PackageOrder::Alphabetical);
+
+        config.apply_cli(&cli(&["uninstall", "--strategy", "graveyard", "foo"]));
+        assert_eq!(config.removal, RemovalStrategy::Graveyard);
+    }
+
+    #[test]
+    fn json_output_silences_verbose_output() {
+        let mut config = Config::default();
+        config.apply_cli(&cli(&["--verbose", "--output", "json", "list"]));
+        assert_eq!(config.output, OutputFormat::Json);
+        assert!(!config.verbose);
+    }
+
+    #[test]
+    fn environment_overrides_config_file_and_flags_override_environment() {
+        // variable is set for a child process only, setting it here would race with other tests parsing
+        // command line
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "config::tests::host_from_environment",
+                "--ignored",
+                "--quiet",
+            ])
+            .env("PACDEC_HOST", "from-env")
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    /// Run by the test above with `PACDEC_HOST` set
+    #[test]
+    #[ignore = "needs PACDEC_HOST, run by environment_overrides_config_file_and_flags_override_environment"]
+    fn host_from_environment() {
+        let (mut config, _) = parse("host \"from-file\"\n").unwrap();
+        config.apply_cli(&cli(&["list"]));
+        assert_eq!(config.host, "from-env");
+        config.apply_cli(&cli(&["--host", "from-flag", "list"]));
+        assert_eq!(config.host, "from-flag");
+    }
+}
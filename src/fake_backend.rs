@@ -0,0 +1,325 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{Result, bail};
+use kdl::KdlNode;
+
+use crate::alpm_db::DbPackage;
+use crate::backend::{Backend, InstallOptions};
+use crate::config::Config;
+use crate::list_pkgs::parse_kdl_document;
+use crate::output::coded;
+
+/// In-memory backend for testing without an Arch system, selected with `package_manager "fake:fixture.kdl"`.
+/// Fixture declares installed packages and sync databases:
+///
+/// ```kdl
+/// installed {
+///     foo version="1.0-1"
+///     dep1 reason="dependency"
+/// }
+/// sync {
+///     extra {
+///         foo
+///         bar {
+///             depends glibc
+///             provides bar-virtual
+///             optional python-foo libx
+///         }
+///     }
+/// }
+/// ```
+///
+/// Transactions only change the in-memory state, fixture file is never written.
+pub struct FakeBackend {
+    name: String,
+    dry_run: bool,
+    verbose: bool,
+    installed: Mutex<BTreeMap<String, DbPackage>>,
+    /// Packages of sync databases in fixture order
+    sync: Vec<DbPackage>,
+    /// Applied transactions as printed in verbose mode
+    transactions: Mutex<Vec<String>>,
+}
+
+impl FakeBackend {
+    pub fn load(fixture: &Path, cfg: &Config) -> Result<Self> {
+        let doc = parse_kdl_document(fixture)?;
+        let mut installed = BTreeMap::new();
+        let mut sync = Vec::new();
+
+        for node in doc.nodes() {
+            match node.name().value() {
+                "installed" => {
+                    for node in node.iter_children() {
+                        let mut pkg = fixture_package(node);
+                        pkg.explicit =
+                            match node.get("reason").and_then(|reason| reason.as_string()) {
+                                None | Some("explicit") => true,
+                                Some("dependency") => false,
+                                Some(other) => bail!(
+                                    "{}: unknown install reason '{other}' of {}",
+                                    fixture.display(),
+                                    pkg.name
+                                ),
+                            };
+                        installed.insert(pkg.name.clone(), pkg);
+                    }
+                }
+                "sync" => {
+                    for repo in node.iter_children() {
+                        for node in repo.iter_children() {
+                            let mut pkg = fixture_package(node);
+                            pkg.repository = Some(repo.name().value().to_string());
+                            sync.push(pkg);
+                        }
+                    }
+                }
+                other => bail!("{}: unknown fixture node '{other}'", fixture.display()),
+            }
+        }
+
+        Ok(FakeBackend {
+            name: format!("fake:{}", fixture.display()),
+            dry_run: cfg.dry_run,
+            verbose: cfg.verbose,
+            installed: Mutex::new(installed),
+            sync,
+            transactions: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Finds `pkg` (`name` or `repo/name`) in sync databases, exact name wins over packages providing it
+    fn find_sync(&self, pkg: &str) -> Option<&DbPackage> {
+        let (repo, name) = match pkg.rsplit_once('/') {
+            Some((repo, name)) => (Some(repo), name),
+            None => (None, pkg),
+        };
+        let in_repo =
+            |sync: &&DbPackage| repo.is_none_or(|repo| sync.repository.as_deref() == Some(repo));
+        self.sync
+            .iter()
+            .filter(in_repo)
+            .find(|sync| sync.name == name)
+            .or_else(|| {
+                self.sync
+                    .iter()
+                    .filter(in_repo)
+                    .find(|sync| sync.satisfies(name))
+            })
+    }
+
+    /// Prints transaction and returns whether it should be applied
+    fn begin(&self, action: &str, pkgs: &[String]) -> bool {
+        if self.dry_run {
+            println!("Dry run: would {action} {}", pkgs.join(" "));
+            return false;
+        }
+        let transaction = format!("{action} {}", pkgs.join(" "));
+        if self.verbose {
+            println!("Fake backend: {transaction}");
+        }
+        self.transactions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(transaction);
+        true
+    }
+
+    fn installed(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, DbPackage>> {
+        self.installed.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Package node with optional `version` property and `depends`, `provides` and `optional` children
+fn fixture_package(node: &KdlNode) -> DbPackage {
+    let list = |key: &str| -> Vec<String> {
+        node.iter_children()
+            .filter(|child| child.name().value() == key)
+            .flat_map(|child| child.entries())
+            .filter_map(|entry| entry.value().as_string())
+            .map(str::to_string)
+            .collect()
+    };
+    DbPackage {
+        name: node.name().value().to_string(),
+        version: node
+            .get("version")
+            .and_then(|version| version.as_string())
+            .unwrap_or("1.0-1")
+            .to_string(),
+        repository: None,
+        explicit: true,
+        install_date: None,
+        depends: list("depends"),
+        provides: list("provides"),
+        optional_deps: list("optional"),
+    }
+}
+
+fn strip_repo(pkg: &str) -> &str {
+    pkg.rsplit_once('/').map_or(pkg, |(_, name)| name)
+}
+
+fn target_not_found(backend: &str, missing: &[&str]) -> anyhow::Error {
+    coded(
+        "transaction_failed",
+        format!("{backend}: target not found: {}", missing.join(" ")),
+    )
+}
+
+impl Backend for FakeBackend {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[cfg(test)]
+    fn transactions(&self) -> Vec<String> {
+        self.transactions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    fn query_local(&self) -> Result<Vec<DbPackage>> {
+        Ok(self.installed().values().cloned().collect())
+    }
+
+    fn list_sync(&self) -> Result<Vec<String>> {
+        Ok(self
+            .sync
+            .iter()
+            .map(|pkg| {
+                format!(
+                    "{}/{}",
+                    pkg.repository.as_deref().unwrap_or_default(),
+                    pkg.name
+                )
+            })
+            .collect())
+    }
+
+    fn sync_info(&self, pkg: &str) -> Result<Option<DbPackage>> {
+        Ok(self.find_sync(pkg).cloned())
+    }
+
+    fn install(&self, pkgs: &[String], options: &InstallOptions) -> Result<()> {
+        let missing: Vec<&str> = pkgs
+            .iter()
+            .filter(|pkg| self.find_sync(pkg).is_none())
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            return Err(target_not_found(&self.name, &missing));
+        }
+        let mut action = vec!["install"];
+        action.push(if options.as_dependency {
+            "--asdeps"
+        } else {
+            "--asexplicit"
+        });
+        if options.needed {
+            action.push("--needed");
+        }
+        let ignored = options.ignore.join(",");
+        if !ignored.is_empty() {
+            action.extend(["--ignore", &ignored]);
+        }
+        if !self.begin(&action.join(" "), pkgs) {
+            return Ok(());
+        }
+
+        let mut installed = self.installed();
+        for sync in pkgs.iter().filter_map(|pkg| self.find_sync(pkg)) {
+            if options.needed && installed.contains_key(&sync.name) {
+                continue;
+            }
+            let pkg = DbPackage {
+                repository: None,
+                explicit: !options.as_dependency,
+                install_date: Some(chrono::Utc::now().timestamp()),
+                ..sync.clone()
+            };
+            installed.insert(pkg.name.clone(), pkg);
+        }
+        Ok(())
+    }
+
+    fn remove(&self, pkgs: &[String]) -> Result<()> {
+        let mut installed = self.installed();
+        let missing: Vec<&str> = pkgs
+            .iter()
+            .filter(|pkg| !installed.contains_key(strip_repo(pkg)))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            return Err(target_not_found(&self.name, &missing));
+        }
+        if !self.begin("remove", pkgs) {
+            return Ok(());
+        }
+
+        for pkg in pkgs {
+            installed.remove(strip_repo(pkg));
+        }
+        Ok(())
+    }
+
+    fn mark_as_deps(&self, pkgs: &[String]) -> Result<()> {
+        if !self.begin("mark as dependencies", pkgs) {
+            return Ok(());
+        }
+        let mut installed = self.installed();
+        for pkg in pkgs {
+            if let Some(pkg) = installed.get_mut(strip_repo(pkg)) {
+                pkg.explicit = false;
+            }
+        }
+        Ok(())
+    }
+
+    fn mark_as_explicit(&self, pkgs: &[String]) -> Result<()> {
+        if !self.begin("mark as explicitly installed", pkgs) {
+            return Ok(());
+        }
+        let mut installed = self.installed();
+        for pkg in pkgs {
+            if let Some(pkg) = installed.get_mut(strip_repo(pkg)) {
+                pkg.explicit = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn package_info(&self, pkg: &str) -> Result<String> {
+        let installed = self.installed();
+        let Some(pkg) = installed.get(strip_repo(pkg)) else {
+            bail!("{}: package '{pkg}' was not found", self.name);
+        };
+        let list = |items: &[String]| {
+            if items.is_empty() {
+                "None".to_string()
+            } else {
+                items.join("  ")
+            }
+        };
+        Ok(format!(
+            "Name            : {}\nVersion         : {}\nProvides        : {}\nDepends On      : {}\nOptional Deps   : {}\nInstall Reason  : {}\n",
+            pkg.name,
+            pkg.version,
+            list(&pkg.provides),
+            list(&pkg.depends),
+            list(&pkg.optional_deps),
+            if pkg.explicit {
+                "Explicitly installed"
+            } else {
+                "Installed as a dependency for another package"
+            }
+        ))
+    }
+
+    fn preview_command(&self, _sync: bool) -> String {
+        "echo {}".to_string()
+    }
+}
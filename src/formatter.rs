@@ -0,0 +1,395 @@
+use kdl::{KdlDocument, KdlDocumentFormat, KdlIdentifier, KdlNode, KdlNodeFormat};
+
+use crate::config::{FormatConfig, PackageOrder};
+use crate::kdl_edit::{node_indent, quote};
+use crate::list_pkgs::file_category;
+use crate::packages::Package;
+
+/// Rewrites whitespace of `doc` to the canonical layout: every node on its own line indented by
+/// `config.indent` spaces per level, at most one empty line between nodes, tags sorted and deduplicated,
+/// names quoted only when KDL requires it and other strings always quoted. Comments are kept with the node
+/// they precede. Packages are sorted by name with `order "alphabetical"`, except at top level of a file
+/// without `@file` category.
+pub fn format_document(doc: &mut KdlDocument, config: &FormatConfig) {
+    let formatter = Formatter {
+        width: config.indent,
+        sort: config.order == PackageOrder::Alphabetical,
+    };
+    let format = doc.format().cloned().unwrap_or_default();
+
+    if doc.nodes().is_empty() {
+        let comments = comment_lines(&format!("{}{}", format.leading, format.trailing), 0, 0);
+        doc.set_format(KdlDocumentFormat {
+            leading: String::new(),
+            trailing: comments,
+        });
+        return;
+    }
+
+    if let Some(first) = doc
+        .nodes_mut()
+        .first_mut()
+        .and_then(|node| node.format_mut())
+    {
+        first.leading.insert_str(0, &format.leading);
+    }
+    let sort = formatter.sort && !file_category(doc).is_empty();
+    formatter.format_nodes(doc.nodes_mut(), "", 0, sort, false);
+    doc.set_format(KdlDocumentFormat {
+        leading: String::new(),
+        trailing: comment_lines(&format.trailing, 0, 0),
+    });
+}
+
+/// Formats a node created in code, e.g. a new package, to be inserted at `indent` columns
+pub fn format_node(node: &mut KdlNode, indent: usize, width: usize) {
+    let formatter = Formatter { width, sort: false };
+    formatter.format_node(node, indent, false);
+    if let Some(format) = node.format_mut() {
+        format.leading = " ".repeat(indent);
+    }
+}
+
+struct Formatter {
+    /// Spaces per indentation level
+    width: usize,
+    /// Sort packages by name
+    sort: bool,
+}
+
+impl Formatter {
+    /// Formats nodes of a block at `indent` columns. `head` is the rest of the line with the opening brace,
+    /// it is kept there. `options` is set for children of `:options`.
+    fn format_nodes(
+        &self,
+        nodes: &mut [KdlNode],
+        head: &str,
+        indent: usize,
+        sort: bool,
+        options: bool,
+    ) {
+        if sort {
+            sort_packages(nodes);
+        }
+        for (idx, node) in nodes.iter_mut().enumerate() {
+            let leading = node
+                .format()
+                .map(|format| format.leading.clone())
+                .unwrap_or_default();
+            let mut new_leading = String::new();
+            if idx == 0 && indent > 0 {
+                new_leading += &inline_comment(head);
+                new_leading.push('\n');
+            }
+            new_leading += &format_leading(&leading, node_indent(node), indent, idx == 0);
+
+            self.format_node(node, indent, options);
+            if let Some(format) = node.format_mut() {
+                format.leading = new_leading;
+            }
+        }
+    }
+
+    /// Formats everything but the leading whitespace and comments of the node
+    fn format_node(&self, node: &mut KdlNode, indent: usize, options: bool) {
+        let name = node.name().value().to_string();
+        let has_tags = !options && (!name.contains([':', '@']) || name.starts_with("cat:"));
+        node.name_mut().clear_format();
+
+        // tags are sorted, properties and other arguments keep their place after them
+        let (mut tags, rest): (Vec<_>, Vec<_>) = node
+            .entries_mut()
+            .drain(..)
+            .partition(|entry| has_tags && entry.name().is_none() && entry.value().is_string());
+        tags.sort_by(|a, b| a.value().as_string().cmp(&b.value().as_string()));
+        tags.dedup_by(|a, b| a.value() == b.value());
+        for mut entry in tags.into_iter().chain(rest) {
+            let mut format = entry.format().cloned().unwrap_or_default();
+            format.leading = format!("{} ", inline_comment(&format.leading));
+            format.trailing = inline_comment(&format.trailing);
+            format.after_key = String::new();
+            format.after_eq = String::new();
+            if let Some(value) = entry.value().as_string() {
+                format.value_repr = if has_tags && entry.name().is_none() {
+                    KdlIdentifier::from(value).to_string()
+                } else {
+                    quote(value)
+                };
+            } else if format.value_repr.is_empty() {
+                format.value_repr = entry.value().to_string();
+            }
+            entry.set_format(format);
+            if let Some(key) = entry.name_mut() {
+                key.clear_format();
+            }
+            node.entries_mut().push(entry);
+        }
+
+        let old_indent = node_indent(node);
+        let old = node.format().cloned().unwrap_or_default();
+        let comment = old.terminator.trim_start_matches(';').trim().to_string();
+        let child_indent = indent + self.width;
+
+        if let Some(children) = node.children_mut() {
+            let format = children.format().cloned().unwrap_or_default();
+            let old_child_indent = children
+                .nodes()
+                .first()
+                .map_or(old_indent + self.width, node_indent);
+            let (leading, trailing) = if children.nodes().is_empty() {
+                let text = format!("{}{}", format.leading, format.trailing);
+                let (head, rest) = text.split_once('\n').unwrap_or((&text, ""));
+                let mut leading = inline_comment(head);
+                leading.push('\n');
+                leading += &comment_lines(rest, old_child_indent, child_indent);
+                leading += &" ".repeat(indent);
+                (leading, String::new())
+            } else {
+                let mut head = String::new();
+                if let Some(first) = children.nodes_mut()[0].format_mut() {
+                    let leading = format!("{}{}", format.leading, first.leading);
+                    match leading.split_once('\n') {
+                        Some((line, rest)) => {
+                            head = line.to_string();
+                            first.leading = rest.to_string();
+                        }
+                        None => first.leading = leading,
+                    }
+                }
+                let is_options = name == ":options";
+                self.format_nodes(
+                    children.nodes_mut(),
+                    &head,
+                    child_indent,
+                    self.sort && !is_options,
+                    is_options,
+                );
+                let trailing = comment_lines(&format.trailing, old_child_indent, child_indent)
+                    + &" ".repeat(indent);
+                (String::new(), trailing)
+            };
+            children.set_format(KdlDocumentFormat { leading, trailing });
+        }
+
+        node.set_format(KdlNodeFormat {
+            before_children: if node.children().is_some() {
+                " ".to_string()
+            } else {
+                String::new()
+            },
+            before_terminator: if comment.is_empty() {
+                String::new()
+            } else {
+                " ".to_string()
+            },
+            terminator: format!("{comment}\n"),
+            trailing: if old.trailing.trim().is_empty() {
+                String::new()
+            } else {
+                old.trailing.clone()
+            },
+            ..old
+        });
+    }
+}
+
+/// Leading whitespace and comments of a node that was at `old_indent` columns. Comment lines keep their
+/// indentation relative to the node, empty lines are collapsed and removed before the first node of a block.
+fn format_leading(leading: &str, old_indent: usize, indent: usize, first: bool) -> String {
+    let (lines, prefix) = leading.rsplit_once('\n').unwrap_or(("", leading));
+    let mut text = if lines.is_empty() && !leading.contains('\n') {
+        String::new()
+    } else {
+        comment_lines_keeping_gap(lines, old_indent, indent, !first)
+    };
+    text += &" ".repeat(indent);
+    // slashdash or block comment on the line of the node
+    let prefix = prefix.trim();
+    if !prefix.is_empty() {
+        text += prefix;
+        text.push(' ');
+    }
+    text
+}
+
+/// Comment lines of `text` moved from `old_indent` to `indent` columns, each ending with a newline. Empty
+/// lines around the comments are removed and runs of them inside are collapsed.
+fn comment_lines(text: &str, old_indent: usize, indent: usize) -> String {
+    let lines = comment_lines_keeping_gap(text, old_indent, indent, false);
+    let lines = lines.trim_end_matches('\n');
+    if lines.is_empty() {
+        String::new()
+    } else {
+        format!("{lines}\n")
+    }
+}
+
+/// Like `comment_lines`, but a single empty line at the end is kept when `keep_gap` is set
+fn comment_lines_keeping_gap(
+    text: &str,
+    old_indent: usize,
+    indent: usize,
+    keep_gap: bool,
+) -> String {
+    let mut out = String::new();
+    let mut gap = false;
+    for line in text.split('\n') {
+        let trimmed = line.trim_start();
+        if trimmed.trim_end().is_empty() {
+            gap = !out.is_empty() || keep_gap;
+            continue;
+        }
+        if gap {
+            out.push('\n');
+            gap = false;
+        }
+        let spaces = line.len() - trimmed.len();
+        out += &" ".repeat(indent + spaces.saturating_sub(old_indent));
+        out += trimmed.trim_end();
+        out.push('\n');
+    }
+    if gap {
+        out.push('\n');
+    }
+    out
+}
+
+/// Trimmed comment with a separating space, empty if there is none
+fn inline_comment(text: &str) -> String {
+    let text = text.trim();
+    if text.is_empty() {
+        String::new()
+    } else {
+        format!(" {text}")
+    }
+}
+
+/// Sorts every run of package nodes by name, other nodes like categories and hooks stay in place
+fn sort_packages(nodes: &mut [KdlNode]) {
+    let is_package = |node: &KdlNode| !node.name().value().contains([':', '@']);
+    let mut start = 0;
+    while start < nodes.len() {
+        if !is_package(&nodes[start]) {
+            start += 1;
+            continue;
+        }
+        let len = nodes[start..]
+            .iter()
+            .take_while(|node| is_package(node))
+            .count();
+        nodes[start..start + len].sort_by_cached_key(|node| {
+            let pkg = Package::from_str(node.name().value());
+            (pkg.name, pkg.repository)
+        });
+        start += len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(src: &str, indent: usize, order: PackageOrder) -> String {
+        let mut doc: KdlDocument = src.parse().unwrap();
+        format_document(&mut doc, &FormatConfig { indent, order });
+        doc.to_string()
+    }
+
+    fn chronological(src: &str) -> String {
+        format(src, 4, PackageOrder::Chronological)
+    }
+
+    fn alphabetical(src: &str) -> String {
+        format(src, 4, PackageOrder::Alphabetical)
+    }
+
+    #[test]
+    fn nodes_are_indented_by_configured_width() {
+        let src = "cat:dev {\n  rust\n  cat:web {\n     node\n  }\n}\n";
+        assert_eq!(
+            chronological(src),
+            "cat:dev {\n    rust\n    cat:web {\n        node\n    }\n}\n"
+        );
+        assert_eq!(
+            format(src, 2, PackageOrder::Chronological),
+            "cat:dev {\n  rust\n  cat:web {\n    node\n  }\n}\n"
+        );
+        assert_eq!(
+            chronological("cat:dev { rust; go; }"),
+            "cat:dev {\n    rust\n    go\n}\n"
+        );
+    }
+
+    #[test]
+    fn tags_are_sorted_and_deduplicated() {
+        assert_eq!(
+            chronological("cat:dev zeta alpha {\nfoo b a b \"c d\" key=#true\n}\n"),
+            "cat:dev alpha zeta {\n    foo a b \"c d\" key=#true\n}\n"
+        );
+    }
+
+    #[test]
+    fn only_names_and_tags_are_left_unquoted() {
+        assert_eq!(
+            chronological(
+                "\"cat:dev\" {\n    \"foo\" \"bar\" key=value\n    :options {\n        reason dependency\n    }\n    \"needs quote\"\n}\n"
+            ),
+            "cat:dev {\n    foo bar key=\"value\"\n    :options {\n        reason \"dependency\"\n    }\n    \"needs quote\"\n}\n"
+        );
+        assert_eq!(
+            chronological("hook:after_install echo\n"),
+            "hook:after_install \"echo\"\n"
+        );
+    }
+
+    #[test]
+    fn blank_lines_are_collapsed() {
+        assert_eq!(
+            chronological("\n\ncat:dev {\n\n\n    foo\n\n\n\n    bar\n\n}\n\n\ncat:web {\n}\n\n\n"),
+            "cat:dev {\n    foo\n\n    bar\n}\n\ncat:web {\n}\n"
+        );
+    }
+
+    #[test]
+    fn comments_stay_with_following_node() {
+        let src = "// top\ncat:dev { // head\n    // about foo\n    foo // trailing\n    /-bar\n  // about baz\n    baz\n    // end\n}\n// last\n";
+        assert_eq!(
+            chronological(src),
+            "// top\ncat:dev { // head\n    // about foo\n    foo // trailing\n    /-bar\n    // about baz\n    baz\n    // end\n}\n// last\n"
+        );
+        // sorting moves comments with their node
+        assert_eq!(
+            alphabetical(src),
+            "// top\ncat:dev { // head\n    /-bar\n    // about baz\n    baz\n    // about foo\n    foo // trailing\n    // end\n}\n// last\n"
+        );
+        assert_eq!(chronological("\n// only comment\n\n"), "// only comment\n");
+    }
+
+    #[test]
+    fn alphabetical_order_sorts_packages_between_other_nodes() {
+        let src = "cat:x {\n    c\n    b\n    hook:after_install \"x\"\n    \"extra/a\"\n    \"core/a\"\n    cat:y {\n        z\n        y\n    }\n}\n";
+        assert_eq!(chronological(src), src);
+        assert_eq!(
+            alphabetical(src),
+            "cat:x {\n    b\n    c\n    hook:after_install \"x\"\n    \"core/a\"\n    \"extra/a\"\n    cat:y {\n        y\n        z\n    }\n}\n"
+        );
+    }
+
+    #[test]
+    fn top_level_is_sorted_only_in_file_category() {
+        assert_eq!(alphabetical("zeta\nalpha\n"), "zeta\nalpha\n");
+        assert_eq!(
+            alphabetical("@file \"cat:dev\"\nzeta\nalpha\n"),
+            "@file \"cat:dev\"\nalpha\nzeta\n"
+        );
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let src = "// top\ncat:dev b a { // head\n\n  foo  x\n\n\n  // c\n  bar {\n  :options { needed #true; }\n  }\n}\n";
+        for order in [PackageOrder::Chronological, PackageOrder::Alphabetical] {
+            let formatted = format(src, 4, order);
+            assert_eq!(format(&formatted, 4, order), formatted);
+        }
+    }
+}
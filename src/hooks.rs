@@ -0,0 +1,210 @@
+use std::fmt;
+use std::process::Command;
+
+use anyhow::Result;
+use colored::*;
+use kdl::KdlNode;
+
+use crate::app::App;
+use crate::config::Config;
+use crate::list_pkgs::is_active_on_host;
+use crate::output::coded;
+use crate::packages::Package;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookKind {
+    BeforeInstall,
+    AfterInstall,
+    BeforeRemove,
+    AfterRemove,
+    AfterSync,
+}
+
+impl HookKind {
+    pub fn from_node_name(name: &str) -> Option<Self> {
+        match name.strip_prefix("hook:")? {
+            "before_install" => Some(Self::BeforeInstall),
+            "after_install" => Some(Self::AfterInstall),
+            "before_remove" => Some(Self::BeforeRemove),
+            "after_remove" => Some(Self::AfterRemove),
+            "after_sync" => Some(Self::AfterSync),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for HookKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::BeforeInstall => "before_install",
+            Self::AfterInstall => "after_install",
+            Self::BeforeRemove => "before_remove",
+            Self::AfterRemove => "after_remove",
+            Self::AfterSync => "after_sync",
+        };
+        write!(f, "hook:{name}")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Hook {
+    pub kind: HookKind,
+    pub command: String,
+}
+
+impl TryFrom<&KdlNode> for Hook {
+    type Error = String;
+
+    fn try_from(node: &KdlNode) -> Result<Self, Self::Error> {
+        let name = node.name().value();
+        let kind = HookKind::from_node_name(name).ok_or_else(|| format!("unknown hook {name}"))?;
+        match node.entries() {
+            [entry] if entry.name().is_none() => match entry.value().as_string() {
+                Some(command) => Ok(Hook {
+                    kind,
+                    command: command.to_string(),
+                }),
+                None => Err(format!("{name} command must be a string")),
+            },
+            _ => Err(format!("{name} takes exactly one command")),
+        }
+    }
+}
+
+/// Package or category with hooks and packages these hooks apply to
+pub struct HookOwner {
+    /// Package name or `cat:name`
+    pub name: String,
+    pub packages: Vec<Package>,
+    pub hooks: Vec<Hook>,
+}
+
+/// Returns hook owners active on current host in declaration order, a category comes before packages inside of it
+pub fn collect_hooks(app: &App) -> Vec<HookOwner> {
+    let mut owners = Vec::new();
+    for (_, doc) in &app.docs {
+        collect_hooks_from_nodes(doc.nodes(), &app.config.host, &mut owners);
+    }
+    owners
+}
+
+/// Returns all packages declared in `nodes`
+fn collect_hooks_from_nodes(
+    nodes: &[KdlNode],
+    host: &str,
+    owners: &mut Vec<HookOwner>,
+) -> Vec<Package> {
+    let mut packages = Vec::new();
+    for node in nodes {
+        let node_name = node.name().value();
+        let is_package = !node_name.contains([':', '@']);
+        if !is_active_on_host(node, host) {
+            continue;
+        }
+        if node_name.starts_with("host:") {
+            if let Some(children) = node.children() {
+                packages.extend(collect_hooks_from_nodes(children.nodes(), host, owners));
+            }
+            continue;
+        }
+        if !is_package && !node_name.starts_with("cat:") {
+            continue;
+        }
+
+        let idx = owners.len();
+        let mut owned = Vec::new();
+        if is_package && let Ok(pkg) = Package::try_from(node.clone()) {
+            owned.push(pkg);
+        }
+        let hooks = node_hooks(node);
+        if let Some(children) = node.children() {
+            owned.extend(collect_hooks_from_nodes(children.nodes(), host, owners));
+        }
+
+        if !hooks.is_empty() {
+            owners.insert(
+                idx,
+                HookOwner {
+                    name: node_name.to_string(),
+                    // hooks of a package do not apply to its subpackages
+                    packages: if is_package {
+                        owned.iter().take(1).cloned().collect()
+                    } else {
+                        owned.clone()
+                    },
+                    hooks,
+                },
+            );
+        }
+        packages.extend(owned);
+    }
+    packages
+}
+
+fn node_hooks(node: &KdlNode) -> Vec<Hook> {
+    node.iter_children()
+        .filter(|child| child.name().value().starts_with("hook:"))
+        .filter_map(|child| match Hook::try_from(child) {
+            Ok(hook) => Some(hook),
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    format!("Warning: {e} in '{}'. Skipping.", node.name().value()).yellow()
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+/// Runs hooks of `kind` whose owner contains any of `pkgs`, every hook runs at most once.
+/// All hooks are run even if some fail, error lists the failed ones.
+pub fn run_hooks(
+    cfg: &Config,
+    owners: &[HookOwner],
+    kind: HookKind,
+    pkgs: &[Package],
+) -> Result<()> {
+    let mut failed = Vec::new();
+
+    for owner in owners {
+        if !owner.packages.iter().any(|pkg| pkgs.contains(pkg)) {
+            continue;
+        }
+        for hook in owner.hooks.iter().filter(|hook| hook.kind == kind) {
+            if cfg.dry_run {
+                println!(
+                    "Dry run: would run {} of {}: '{}'",
+                    kind, owner.name, hook.command
+                );
+                continue;
+            }
+            if cfg.verbose {
+                println!("Running {} of {}: {}", kind, owner.name, hook.command);
+            }
+
+            let status = Command::new("sh").arg("-c").arg(&hook.command).status();
+            let error = match status {
+                Ok(status) if status.success() => continue,
+                Ok(status) => format!("exited with {status}"),
+                Err(e) => e.to_string(),
+            };
+            println!(
+                "{} {} of {} {}",
+                "Hook failed:".red().bold(),
+                kind,
+                owner.name,
+                error
+            );
+            failed.push(format!("{} of {}", kind, owner.name));
+        }
+    }
+
+    if !failed.is_empty() {
+        return Err(coded(
+            "hook_failed",
+            format!("{} hook(s) failed: {}", failed.len(), failed.join(", ")),
+        ));
+    }
+    Ok(())
+}
@@ -1,99 +1,1164 @@
+use std::fmt;
 use std::fs;
+use std::io::{self, IsTerminal};
 use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result};
-
-use anyhow::bail;
+use anyhow::{Context, Result, bail};
 use colored::*;
-use kdl::{FormatConfig, KdlNode};
+use inquire::Select;
+use kdl::{
+    KdlDocument, KdlDocumentFormat, KdlEntry, KdlEntryFormat, KdlIdentifier, KdlNode, KdlNodeFormat,
+};
 use similar::{ChangeTag, TextDiff};
 
 use crate::app::App;
-use crate::config::{BackupMode, Config};
+use crate::backup::Transaction;
+use crate::config::RemovalStrategy;
+use crate::formatter::format_node;
+use crate::list_pkgs::{file_category, node_tags, source_line};
+use crate::output::coded;
 use crate::packages::{Category, Package};
+use crate::prompts::{can_prompt, prompt_category_occurrence};
 
-pub fn add_pkgs(app: &mut App, category: &Category, pkgs: &[Package]) -> Result<()> {
-    let category_name = format!("cat:{}", category.name);
-    let mut stack = Vec::new();
-    for (_, doc) in &mut app.docs {
-        stack.push((doc.nodes_mut(), Vec::<String>::new()));
+/// Place of a category node in declaration files
+pub struct CategoryOccurrence {
+    /// Index into `App::docs`
+    doc: usize,
+    /// Indices of nodes leading from document root to the category node, empty for `@file` category
+    nodes: Vec<usize>,
+    /// Full category path including `@file` prefix
+    pub category: Category,
+    pub file: PathBuf,
+    pub line: usize,
+}
+
+impl fmt::Display for CategoryOccurrence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}:{})",
+            self.category,
+            self.file.display(),
+            self.line
+        )
+    }
+}
+
+/// Finds every node declaring `category`. Category without path matches categories with the same name
+/// anywhere, otherwise full path has to match.
+pub fn find_category(app: &App, category: &Category) -> Vec<CategoryOccurrence> {
+    fn traverse(
+        nodes: &[KdlNode],
+        category: &Category,
+        path: &mut Vec<String>,
+        indices: &mut Vec<usize>,
+        found: &mut Vec<(Vec<usize>, Vec<String>, usize)>,
+    ) {
+        for (idx, node) in nodes.iter().enumerate() {
+            let name = node.name().value().strip_prefix("cat:");
+            if let Some(name) = name {
+                path.push(name.to_string());
+            }
+            indices.push(idx);
+            if name.is_some() && category.matches(path) {
+                found.push((indices.clone(), path.clone(), node.span().offset()));
+            }
+            if let Some(children) = node.children() {
+                traverse(children.nodes(), category, path, indices, found);
+            }
+            indices.pop();
+            if name.is_some() {
+                path.pop();
+            }
+        }
+    }
+
+    let mut occurrences = Vec::new();
+    for (doc_idx, (file, doc)) in app.docs.iter().enumerate() {
+        // spans point into the file content, document may be modified already
+        let src = fs::read_to_string(file).unwrap_or_default();
+        let mut found = Vec::new();
+        let mut path = file_category(doc);
+        if !path.is_empty() && category.matches(&path) {
+            let offset = doc.get("@file").map_or(0, |node| node.span().offset());
+            found.push((Vec::new(), path.clone(), offset));
+        }
+        traverse(
+            doc.nodes(),
+            category,
+            &mut path,
+            &mut Vec::new(),
+            &mut found,
+        );
+
+        occurrences.extend(found.into_iter().filter_map(|(nodes, path, offset)| {
+            Some(CategoryOccurrence {
+                doc: doc_idx,
+                nodes,
+                category: Category::from_path(&path)?,
+                file: file.clone(),
+                line: source_line(&src, offset),
+            })
+        }));
+    }
+    occurrences
+}
+
+/// Chooses the occurrence of `category` to add packages to. Duplicates are narrowed down by `cat_file`, then
+/// chosen interactively, without a terminal they are an error.
+fn resolve_category(
+    app: &App,
+    category: &Category,
+    cat_file: Option<&Path>,
+) -> Result<CategoryOccurrence> {
+    let mut occurrences = find_category_in(app, category, cat_file)?;
+    if occurrences.len() == 1 {
+        return Ok(occurrences.remove(0));
+    }
+    choose_occurrence(category, occurrences)
+}
+
+/// Every occurrence of a single category matching `category`, e.g. the same category declared in several
+/// files. If `category` matches different categories, one of them is chosen like in `resolve_category`.
+pub fn category_occurrences(
+    app: &App,
+    category: &Category,
+    cat_file: Option<&Path>,
+) -> Result<Vec<CategoryOccurrence>> {
+    let occurrences = find_category_in(app, category, cat_file)?;
+    let chosen = if occurrences
+        .iter()
+        .all(|occurrence| occurrence.category == occurrences[0].category)
+    {
+        occurrences[0].category.clone()
+    } else {
+        choose_occurrence(category, occurrences)?.category
+    };
+
+    let mut occurrences = find_category_in(app, &chosen, cat_file)?;
+    occurrences.retain(|occurrence| occurrence.category == chosen);
+    Ok(occurrences)
+}
+
+/// Occurrences of `category` in `cat_file` or in any declaration file, at least one has to exist
+fn find_category_in(
+    app: &App,
+    category: &Category,
+    cat_file: Option<&Path>,
+) -> Result<Vec<CategoryOccurrence>> {
+    let mut occurrences = find_category(app, category);
+    if let Some(cat_file) = cat_file {
+        let cat_file = cat_file
+            .canonicalize()
+            .with_context(|| format!("failed to find declaration file {}", cat_file.display()))?;
+        occurrences.retain(|occurrence| occurrence.file == cat_file);
+        if occurrences.is_empty() {
+            return Err(coded(
+                "category_not_found",
+                format!("category '{category}' not found in {}", cat_file.display()),
+            ));
+        }
+    }
+    if occurrences.is_empty() {
+        return Err(coded(
+            "category_not_found",
+            format!("category '{category}' not found"),
+        ));
+    }
+    Ok(occurrences)
+}
+
+/// Prompts to choose one of `occurrences`, without a terminal they are an error
+fn choose_occurrence(
+    category: &Category,
+    occurrences: Vec<CategoryOccurrence>,
+) -> Result<CategoryOccurrence> {
+    if can_prompt() && io::stdout().is_terminal() {
+        return prompt_category_occurrence(category, occurrences);
+    }
+    Err(coded(
+        "category_ambiguous",
+        format!(
+            "category '{category}' exists in several places, use full category path or --cat-file:\n{}",
+            occurrences
+                .iter()
+                .map(|occurrence| format!("    {occurrence}"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        ),
+    ))
+}
+
+/// Adds packages to `category`, `cat_file` chooses declaration file if category is declared in several
+pub fn add_pkgs(
+    app: &mut App,
+    category: &Category,
+    cat_file: Option<&Path>,
+    pkgs: &[Package],
+) -> Result<()> {
+    let occurrence = resolve_category(app, category, cat_file)?;
+    let width = app.config.format.indent;
+    insert_nodes(app, &occurrence, |indent| {
+        pkgs.iter()
+            .map(|pkg| new_pkg_node(pkg, indent, width))
+            .collect()
+    });
+    Ok(())
+}
+
+/// Creates missing categories of `category` path under its longest existing parent. New top level category
+/// is placed in `cat_file`, or in a declaration file chosen by user.
+pub fn create_category(app: &mut App, category: &Category, cat_file: Option<&Path>) -> Result<()> {
+    let full_path: Vec<String> = category
+        .path
+        .iter()
+        .chain([&category.name])
+        .cloned()
+        .collect();
+    let cat_file = cat_file
+        .map(|file| {
+            file.canonicalize()
+                .with_context(|| format!("failed to find declaration file {}", file.display()))
+        })
+        .transpose()?;
+
+    for len in (1..full_path.len()).rev() {
+        let Some(parent) = Category::from_path(&full_path[..len]) else {
+            continue;
+        };
+        let exists = find_category(app, &parent).iter().any(|occurrence| {
+            cat_file
+                .as_ref()
+                .is_none_or(|file| occurrence.file == *file)
+        });
+        if exists {
+            let occurrence = resolve_category(app, &parent, cat_file.as_deref())?;
+            let width = app.config.format.indent;
+            insert_nodes(app, &occurrence, |indent| {
+                let mut node = category_node(&full_path[len..], width);
+                reindent(&mut node, 0, indent);
+                vec![node]
+            });
+            return Ok(());
+        }
     }
-    let mut cat_count = 0;
 
-    while let Some((nodes, path)) = stack.pop() {
-        for node in nodes {
-            let node_name = node.name().value().to_string();
-            if node_name == category_name && (category.path.is_empty() || path == category.path) {
-                cat_count += 1;
-                if cat_count > 1 {
-                    continue;
+    let doc = match cat_file {
+        Some(cat_file) => {
+            let doc = app
+                .docs
+                .iter()
+                .position(|(file, _)| *file == cat_file)
+                .ok_or_else(|| {
+                    coded(
+                        "declaration_not_found",
+                        format!("{} is not included in declaration", cat_file.display()),
+                    )
+                })?;
+            let file_path = file_category(&app.docs[doc].1);
+            if !file_path.is_empty() {
+                bail!(
+                    "{} belongs to category '{}', top level category '{category}' can not be created in it",
+                    cat_file.display(),
+                    file_path.join("/")
+                );
+            }
+            doc
+        }
+        None => choose_category_file(app)?,
+    };
+    let mut node = category_node(&full_path, app.config.format.indent);
+    if let Some(format) = node.format_mut() {
+        format.leading = "\n".to_string();
+    }
+    app.docs[doc].1.nodes_mut().push(node);
+    Ok(())
+}
+
+/// Index of declaration file to put a new top level category in. Files with `@file` category are skipped,
+/// user is prompted if there are several candidates, without a terminal root declaration file is used.
+fn choose_category_file(app: &App) -> Result<usize> {
+    // root declaration file is collected last
+    let mut candidates: Vec<usize> = (0..app.docs.len())
+        .rev()
+        .filter(|&idx| file_category(&app.docs[idx].1).is_empty())
+        .collect();
+    if candidates.len() <= 1 || !can_prompt() {
+        return Ok(candidates
+            .first()
+            .copied()
+            .unwrap_or(app.docs.len().saturating_sub(1)));
+    }
+
+    let files: Vec<String> = candidates
+        .iter()
+        .map(|&idx| app.docs[idx].0.display().to_string())
+        .collect();
+    let chosen = Select::new("file to create category in", files).raw_prompt()?;
+    Ok(candidates.swap_remove(chosen.index))
+}
+
+/// Nested empty category nodes at indentation level 0, e.g. `cat:a { cat:b { } }` for `[a, b]`, `width` is
+/// the number of spaces per indentation level
+fn category_node(path: &[String], width: usize) -> KdlNode {
+    let mut text = String::new();
+    for (depth, name) in path.iter().enumerate() {
+        text += &format!(
+            "{}{} {{\n",
+            " ".repeat(depth * width),
+            KdlIdentifier::from(format!("cat:{name}"))
+        );
+    }
+    for depth in (0..path.len()).rev() {
+        text += &format!("{}}}\n", " ".repeat(depth * width));
+    }
+    text.parse::<KdlDocument>()
+        .ok()
+        .and_then(|doc| doc.nodes().first().cloned())
+        .expect("generated category nodes are valid KDL")
+}
+
+/// Appends nodes made by `new_nodes` for given indentation (in columns) to category occurrence
+fn insert_nodes(
+    app: &mut App,
+    occurrence: &CategoryOccurrence,
+    new_nodes: impl FnOnce(usize) -> Vec<KdlNode>,
+) {
+    let width = app.config.format.indent;
+    let doc = &mut app.docs[occurrence.doc].1;
+
+    // content of `@file` category is placed at top level of the file
+    let Some((last, parents)) = occurrence.nodes.split_last() else {
+        doc.nodes_mut().extend(new_nodes(0));
+        return;
+    };
+    let node = &mut parent_document(doc, parents).nodes_mut()[*last];
+    let indent = node_indent(node);
+    let children = node.ensure_children();
+    // whitespace of an empty block is kept before its closing brace
+    if children.nodes().is_empty() {
+        children.set_format(KdlDocumentFormat {
+            leading: String::new(),
+            trailing: " ".repeat(indent),
+        });
+    }
+    // new nodes line up with existing children even if they use another indentation width
+    let child_indent = children.nodes().last().map_or(indent + width, node_indent);
+    children.nodes_mut().extend(new_nodes(child_indent));
+    ensure_leading_newline(children.nodes_mut());
+}
+
+/// Appends nodes taken from another place to category occurrence, reindenting them
+fn insert_moved_nodes(app: &mut App, occurrence: &CategoryOccurrence, nodes: Vec<KdlNode>) {
+    insert_nodes(app, occurrence, |indent| {
+        nodes
+            .into_iter()
+            .map(|mut node| {
+                if let Some(format) = node.format_mut()
+                    && let Some(leading) = format.leading.strip_prefix('\n')
+                {
+                    format.leading = leading.to_string();
                 }
+                let from = node_indent(&node);
+                reindent(&mut node, from, indent);
+                node
+            })
+            .collect()
+    });
+}
+
+/// Document containing node at `parents` path of indices, `doc` itself for empty path
+fn parent_document<'a>(doc: &'a mut KdlDocument, parents: &[usize]) -> &'a mut KdlDocument {
+    let mut doc = doc;
+    for &idx in parents {
+        doc = doc.nodes_mut()[idx].ensure_children();
+    }
+    doc
+}
+
+/// Moves package nodes with their children, hooks and comments to `category`. Only nodes `pkgs` were read
+/// from are moved, packages without source move from everywhere they are declared. Packages declared inside
+/// `host:` blocks keep the restriction as `hosts` property.
+pub fn move_pkgs(
+    app: &mut App,
+    pkgs: &[Package],
+    category: &Category,
+    cat_file: Option<&Path>,
+) -> Result<()> {
+    let mut extracted = Vec::new();
+    for (file, doc) in &mut app.docs {
+        // spans point into the source the document was parsed from, which is reproduced by `to_string`
+        let src = doc.to_string();
+        let is_moved = |node: &KdlNode| {
+            let name = Package::from_str(node.name().value()).name;
+            pkgs.iter().any(|pkg| {
+                pkg.name == name
+                    && pkg.source.as_ref().is_none_or(|source| {
+                        source.file == *file
+                            && source.line == source_line(&src, node.span().offset())
+                    })
+            })
+        };
+        extract_pkgs(doc.nodes_mut(), &is_moved, None, false, &mut extracted);
+    }
+
+    // target is resolved after extraction, node indices change when packages are taken out
+    let occurrence = resolve_category(app, category, cat_file)?;
+    insert_moved_nodes(app, &occurrence, extracted);
+    Ok(())
+}
+
+/// Takes package nodes for which `is_moved` returns true out of `nodes`, `hosts` are hosts of enclosing
+/// `host:` block
+fn extract_pkgs(
+    nodes: &mut Vec<KdlNode>,
+    is_moved: &dyn Fn(&KdlNode) -> bool,
+    hosts: Option<&str>,
+    nested: bool,
+    extracted: &mut Vec<KdlNode>,
+) {
+    let mut idx = 0;
+    while idx < nodes.len() {
+        let name = nodes[idx].name().value().to_string();
+        if !nested && is_reserved_category(&name) {
+            idx += 1;
+            continue;
+        }
 
-                let indent = node
-                    .format()
-                    .map(|format| format.leading.len() / 4)
-                    .unwrap_or(0);
-
-                node.ensure_children()
-                    .nodes_mut()
-                    .extend(pkgs.iter().map(|pkg| {
-                        let mut new_node: KdlNode = pkg.clone().into();
-                        new_node.autoformat_config(
-                            &FormatConfig::builder().indent_level(indent + 1).build(),
-                        ); // TODO: There should be a better way to do this
-                        new_node
-                    }));
+        let is_package = !name.contains([':', '@']);
+        if is_package && is_moved(&nodes[idx]) {
+            let mut node = nodes.remove(idx);
+            if let Some(hosts) = hosts
+                && node.get("hosts").is_none()
+            {
+                node.insert("hosts", string_entry(Some("hosts"), hosts));
             }
-            if let Some(children) = node.children_mut() {
-                let new_path = if node_name.starts_with("cat:") {
-                    let mut new_path = path.clone();
-                    new_path.push(node_name.trim_start_matches("cat:").to_string());
-                    new_path
-                } else {
-                    path.clone()
-                };
-                stack.push((children.nodes_mut(), new_path));
+            extracted.push(node);
+            continue;
+        }
+
+        let hosts = name.strip_prefix("host:").or(hosts);
+        if let Some(children) = nodes[idx].children_mut() {
+            let len = children.nodes().len();
+            extract_pkgs(children.nodes_mut(), is_moved, hosts, true, extracted);
+            // blocks that were empty already keep their formatting
+            if children.nodes().is_empty() && len > 0 {
+                children.set_format(KdlDocumentFormat::default());
+            }
+        }
+        idx += 1;
+    }
+    if nested {
+        ensure_leading_newline(nodes);
+    }
+}
+
+/// Renames every occurrence of a category (see `category_occurrences`) to `name`, subcategories and packages
+/// keep their place. `@file` directives of the category and its subcategories are updated as well.
+pub fn rename_category(
+    app: &mut App,
+    occurrences: &[CategoryOccurrence],
+    name: &str,
+) -> Result<Category> {
+    let name = name.trim();
+    if name.is_empty() || name.contains('/') {
+        bail!(
+            "invalid category name '{name}', use `pacdec cat merge` or `pacdec cat extract` to move category"
+        );
+    }
+    let Some(old) = occurrences
+        .first()
+        .map(|occurrence| occurrence.category.clone())
+    else {
+        return Ok(Category::from_str(name));
+    };
+    let renamed = Category {
+        name: name.to_string(),
+        path: old.path.clone(),
+    };
+    if find_category(app, &renamed)
+        .iter()
+        .any(|occurrence| occurrence.category == renamed)
+    {
+        return Err(coded(
+            "category_exists",
+            format!(
+                "category '{renamed}' already exists, use `pacdec cat merge` to merge categories"
+            ),
+        ));
+    }
+
+    for occurrence in occurrences {
+        if let Some((last, parents)) = occurrence.nodes.split_last() {
+            let doc = &mut app.docs[occurrence.doc].1;
+            parent_document(doc, parents).nodes_mut()[*last]
+                .set_name(KdlIdentifier::from(format!("cat:{name}")));
+        }
+    }
+
+    let old_path: Vec<String> = old.path.iter().chain([&old.name]).cloned().collect();
+    for (_, doc) in &mut app.docs {
+        let path = file_category(doc);
+        if !path.starts_with(&old_path) {
+            continue;
+        }
+        let new_path: Vec<String> = renamed
+            .path
+            .iter()
+            .chain([&renamed.name])
+            .chain(&path[old_path.len()..])
+            .cloned()
+            .collect();
+        if let Some(node) = doc.get_mut("@file") {
+            node.entries_mut()[0] = string_entry(None, &format!("cat:{}", new_path.join("/")));
+        }
+    }
+    Ok(renamed)
+}
+
+/// Deletes every occurrence of a category (see `category_occurrences`) with its content according to
+/// `removal` strategy: category nodes are deleted or commented out, with graveyard strategy its packages are
+/// moved to graveyard. Files declaring the category with `@file` are no longer included.
+pub fn remove_category(app: &mut App, mut occurrences: Vec<CategoryOccurrence>) -> Result<()> {
+    let strategy = app.config.removal;
+    let width = app.config.format.indent;
+    let mut removed = Vec::new();
+    let mut unlinked = Vec::new();
+
+    // later nodes first, so that indices of the remaining occurrences stay valid
+    occurrences.sort_by(|a, b| (b.doc, &b.nodes).cmp(&(a.doc, &a.nodes)));
+    for occurrence in &occurrences {
+        let Some((last, parents)) = occurrence.nodes.split_last() else {
+            if strategy == RemovalStrategy::Graveyard {
+                let doc = &mut app.docs[occurrence.doc].1;
+                extract_pkgs(doc.nodes_mut(), &|_| true, None, true, &mut removed);
+            }
+            unlinked.push(occurrence.doc);
+            continue;
+        };
+        if strategy == RemovalStrategy::Comment {
+            let doc = &mut app.docs[occurrence.doc].1;
+            if let Some(format) = parent_document(doc, parents).nodes_mut()[*last].format_mut() {
+                format.leading += "/- ";
+            }
+            continue;
+        }
+        if let Some(mut node) = take_category_node(app, occurrence)
+            && strategy == RemovalStrategy::Graveyard
+            && let Some(children) = node.children_mut()
+        {
+            extract_pkgs(children.nodes_mut(), &|_| true, None, true, &mut removed);
+        }
+    }
+
+    for doc in unlinked {
+        unlink_file(app, doc, strategy == RemovalStrategy::Comment)?;
+    }
+    if !removed.is_empty()
+        && let Some((_, doc)) = app.docs.last_mut()
+    {
+        bury(doc, removed, width);
+    }
+    Ok(())
+}
+
+/// Moves content of every occurrence of a category (see `category_occurrences`) to `target` and deletes the
+/// category. Its tags and hooks are dropped, files declaring it with `@file` are no longer included.
+pub fn merge_category(
+    app: &mut App,
+    mut occurrences: Vec<CategoryOccurrence>,
+    target: &Category,
+    cat_file: Option<&Path>,
+) -> Result<()> {
+    let Some(source) = occurrences
+        .first()
+        .map(|occurrence| occurrence.category.clone())
+    else {
+        return Ok(());
+    };
+    let target = resolve_category(app, target, cat_file)?;
+    let (target, target_file) = (target.category, target.file);
+    let source_path: Vec<&String> = source.path.iter().chain([&source.name]).collect();
+    let target_path: Vec<&String> = target.path.iter().chain([&target.name]).collect();
+    if target_path.starts_with(&source_path) {
+        bail!("can not merge category '{source}' into itself or its subcategory '{target}'");
+    }
+
+    let mut content = Vec::new();
+    let mut unlinked = Vec::new();
+    let mut dropped = false;
+    // later nodes first, so that indices of the remaining occurrences stay valid
+    occurrences.sort_by(|a, b| (b.doc, &b.nodes).cmp(&(a.doc, &a.nodes)));
+    for occurrence in &occurrences {
+        let (nodes, hosts) = if occurrence.nodes.is_empty() {
+            let doc = &mut app.docs[occurrence.doc].1;
+            let (directives, nodes): (Vec<KdlNode>, Vec<KdlNode>) = doc
+                .nodes_mut()
+                .drain(..)
+                .partition(|node| node.name().value().starts_with('@'));
+            *doc.nodes_mut() = directives;
+            unlinked.push(occurrence.doc);
+            (nodes, None)
+        } else {
+            let hosts = enclosing_hosts(&app.docs[occurrence.doc].1, &occurrence.nodes);
+            let Some(mut node) = take_category_node(app, occurrence) else {
+                continue;
+            };
+            dropped |= !node_tags(&node).is_empty();
+            let nodes = node
+                .children_mut()
+                .as_mut()
+                .map(|children| std::mem::take(children.nodes_mut()))
+                .unwrap_or_default();
+            (nodes, hosts)
+        };
+
+        let mut nodes: Vec<KdlNode> = nodes
+            .into_iter()
+            .filter(|node| {
+                let is_hook = node.name().value().starts_with("hook:");
+                dropped |= is_hook;
+                !is_hook
+            })
+            .collect();
+        if let Some(hosts) = hosts {
+            for node in nodes.iter_mut().filter(|node| node.get("hosts").is_none()) {
+                node.insert("hosts", string_entry(Some("hosts"), &hosts));
             }
         }
+        content.splice(0..0, nodes);
+    }
+
+    for doc in unlinked {
+        unlink_file(app, doc, false)?;
+    }
+    if dropped {
+        eprintln!(
+            "{}",
+            format!("Warning: tags and hooks of category '{source}' are dropped").yellow()
+        );
+    }
+    let occurrence = resolve_category(app, &target, Some(&target_file))?;
+    insert_moved_nodes(app, &occurrence, content);
+    Ok(())
+}
+
+/// Moves category node to new declaration file `file` included from the file category was declared in.
+/// Nested category keeps its path with `@file` directive of its parent category.
+pub fn extract_category(
+    app: &mut App,
+    category: &Category,
+    cat_file: Option<&Path>,
+    file: &Path,
+) -> Result<()> {
+    let occurrence = resolve_category(app, category, cat_file)?;
+    if occurrence.nodes.is_empty() {
+        bail!(
+            "category '{}' is already declared in its own file {}",
+            occurrence.category,
+            occurrence.file.display()
+        );
+    }
+    // relative path is resolved like `@include`, against the directory of the file declaring the category
+    let base = occurrence.file.parent().unwrap_or(Path::new("."));
+    let file = base.join(file);
+    if file.exists() {
+        bail!("{} already exists", file.display());
+    }
+    let file_name = file
+        .file_name()
+        .with_context(|| format!("invalid declaration file path {}", file.display()))?;
+    let dir = file.parent().unwrap_or(base);
+    let new_file = dir
+        .canonicalize()
+        .with_context(|| format!("failed to find directory {}", dir.display()))?
+        .join(file_name);
+    let include = relative_path(&new_file, base).to_string_lossy().to_string();
+
+    let doc = &app.docs[occurrence.doc].1;
+    let hosts = enclosing_hosts(doc, &occurrence.nodes);
+    let mut inherits = false;
+    let mut nodes = doc.nodes();
+    for &idx in &occurrence.nodes[..occurrence.nodes.len() - 1] {
+        let parent = &nodes[idx];
+        inherits |= !node_tags(parent).is_empty()
+            || parent
+                .iter_children()
+                .any(|child| child.name().value().starts_with("hook:"));
+        nodes = parent.children().map_or(&[], |children| children.nodes());
+    }
+
+    let Some(mut node) = take_category_node(app, &occurrence) else {
+        return Ok(());
+    };
+    if let Some(hosts) = hosts
+        && node.get("hosts").is_none()
+    {
+        node.insert("hosts", string_entry(Some("hosts"), &hosts));
+    }
+    let from = node_indent(&node);
+    reindent(&mut node, from, 0);
+    if let Some(format) = node.format_mut() {
+        format.leading = format.leading.trim_start_matches('\n').to_string();
+    }
+
+    let mut new_doc = KdlDocument::new();
+    if !occurrence.category.path.is_empty() {
+        new_doc.nodes_mut().push(directive_node(
+            "@file",
+            &format!("cat:{}", occurrence.category.path.join("/")),
+        ));
     }
-    if cat_count > 1 {
-        println!("{}", format!(
-            "category {category_name} exists in several places, adding package(s) only to 1st occurrence") // TODO: prompt to choose occurrence 
-            .yellow().bold()
+    new_doc.nodes_mut().push(node);
+
+    let doc = &mut app.docs[occurrence.doc].1;
+    let position = doc
+        .nodes()
+        .iter()
+        .rposition(|node| node.name().value().starts_with('@'))
+        .map_or(0, |idx| idx + 1);
+    doc.nodes_mut()
+        .insert(position, directive_node("@include", &include));
+    // included files come before the file including them
+    app.docs.insert(occurrence.doc, (new_file, new_doc));
+
+    if inherits {
+        eprintln!(
+            "{}",
+            format!(
+                "Warning: packages of '{}' no longer inherit tags and hooks of enclosing categories",
+                occurrence.category
+            )
+            .yellow()
         );
-    } else if cat_count == 0 {
-        bail!("no such category \"{category_name}\" exists");
     }
+    Ok(())
+}
+
+/// `path` relative to directory `base`, both are absolute
+fn relative_path(path: &Path, base: &Path) -> PathBuf {
+    let path: Vec<_> = path.components().collect();
+    let base: Vec<_> = base.components().collect();
+    let common = path.iter().zip(&base).take_while(|(a, b)| a == b).count();
+    let mut relative: PathBuf = base[common..].iter().map(|_| "..").collect();
+    relative.extend(&path[common..]);
+    relative
+}
+
+/// Takes node of category occurrence out of its document, `None` for `@file` category
+fn take_category_node(app: &mut App, occurrence: &CategoryOccurrence) -> Option<KdlNode> {
+    if occurrence.nodes.is_empty() {
+        return None;
+    }
+    Some(take_node(
+        &mut app.docs[occurrence.doc].1,
+        &occurrence.nodes,
+    ))
+}
+
+/// Takes node at non-empty path of indices out of `doc`, `host:` blocks left empty are deleted
+fn take_node(doc: &mut KdlDocument, indices: &[usize]) -> KdlNode {
+    let (last, parents) = indices.split_last().expect("node path is not empty");
+    let parent = parent_document(doc, parents);
+    let node = parent.nodes_mut().remove(*last);
+    if parents.is_empty() {
+        return node;
+    }
+    if !parent.nodes().is_empty() {
+        ensure_leading_newline(parent.nodes_mut());
+        return node;
+    }
+
+    parent.set_format(KdlDocumentFormat::default());
+    if let Some((parent_idx, grandparents)) = parents.split_last()
+        && parent_document(doc, grandparents).nodes()[*parent_idx]
+            .name()
+            .value()
+            .starts_with("host:")
+    {
+        take_node(doc, parents);
+    }
+    node
+}
+
+/// Hosts of the innermost `host:` block or node with `hosts` property enclosing node at `indices`
+fn enclosing_hosts(doc: &KdlDocument, indices: &[usize]) -> Option<String> {
+    let mut hosts = None;
+    let mut nodes = doc.nodes();
+    for &idx in &indices[..indices.len().saturating_sub(1)] {
+        let node = &nodes[idx];
+        if let Some(node_hosts) = node
+            .name()
+            .value()
+            .strip_prefix("host:")
+            .or_else(|| node.get("hosts").and_then(|hosts| hosts.as_string()))
+        {
+            hosts = Some(node_hosts.to_string());
+        }
+        nodes = node.children().map_or(&[], |children| children.nodes());
+    }
+    hosts
+}
+
+/// Removes (or comments out) `@include` of declaration file `doc` and drops the file from `App::docs`, the
+/// file itself is left untouched
+fn unlink_file(app: &mut App, doc: usize, comment: bool) -> Result<()> {
+    let file = app.docs[doc].0.clone();
+    let include = app
+        .docs
+        .iter()
+        .enumerate()
+        .find_map(|(idx, (including, doc))| {
+            let base = including.parent()?;
+            let position = doc.nodes().iter().position(|node| {
+                node.name().value() == "@include"
+                    && node
+                        .entries()
+                        .first()
+                        .and_then(|entry| entry.value().as_string())
+                        .and_then(|path| base.join(path).canonicalize().ok())
+                        .is_some_and(|path| path == file)
+            })?;
+            Some((idx, position))
+        });
+    let Some((including, position)) = include else {
+        bail!(
+            "{} is the root declaration file, it can not be removed",
+            file.display()
+        );
+    };
 
+    let nodes = app.docs[including].1.nodes_mut();
+    if comment {
+        if let Some(format) = nodes[position].format_mut() {
+            format.leading += "/- ";
+        }
+    } else {
+        nodes.remove(position);
+    }
+    app.docs.remove(doc);
+    println!(
+        "{} {} is no longer included, delete it if it is not needed",
+        "Note:".blue().bold(),
+        file.display()
+    );
     Ok(())
 }
 
+/// Top level directive like `@include "file.kdl"`
+fn directive_node(name: &str, value: &str) -> KdlNode {
+    format!("{name} {}\n", quote(value))
+        .parse::<KdlDocument>()
+        .ok()
+        .and_then(|doc| doc.nodes().first().cloned())
+        .expect("generated directive is valid KDL")
+}
+
+/// String argument or property written with quotes, e.g. `hosts="laptop"`
+fn string_entry(key: Option<&str>, value: &str) -> KdlEntry {
+    let mut entry = match key {
+        Some(key) => KdlEntry::new_prop(key, value),
+        None => KdlEntry::new(value),
+    };
+    entry.set_format(KdlEntryFormat {
+        value_repr: quote(value),
+        leading: " ".to_string(),
+        ..Default::default()
+    });
+    entry
+}
+
+pub fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Package node at `indent` columns, `width` is the number of spaces per indentation level
+fn new_pkg_node(pkg: &Package, indent: usize, width: usize) -> KdlNode {
+    let mut new_node: KdlNode = pkg.clone().into();
+    format_node(&mut new_node, indent, width);
+    new_node
+}
+
+/// Category removed packages are moved to by `removal "graveyard"`, packages in it are not declared
+pub const GRAVEYARD_CATEGORY: &str = "removed";
+
+/// Category of packages ignored by `generate --triage`, they are neither declared nor reported as undeclared
+pub const IGNORED_CATEGORY: &str = "ignored";
+
+/// Whether top level node is one of the categories pacdec keeps packages out of declaration in
+pub fn is_reserved_category(name: &str) -> bool {
+    matches!(
+        name.strip_prefix("cat:"),
+        Some(GRAVEYARD_CATEGORY | IGNORED_CATEGORY)
+    )
+}
+
+/// Adds packages to ignored category of the root declaration file
+pub fn ignore_pkgs(app: &mut App, pkgs: &[Package]) {
+    let width = app.config.format.indent;
+    let nodes = pkgs
+        .iter()
+        .map(|pkg| new_pkg_node(pkg, width, width))
+        .collect();
+    // root declaration file is collected last
+    if let Some((_, doc)) = app.docs.last_mut() {
+        add_dated_nodes(doc, IGNORED_CATEGORY, "ignored", nodes, width);
+    }
+}
+
+/// Takes packages out of declaration according to `removal` strategy. Subpackages of removed package take
+/// its place, categories and host blocks left empty by deletion are deleted as well (except default category).
 pub fn remove_pkgs(app: &mut App, pkgs: &[Package]) -> Result<()> {
-    let mut stack = Vec::new();
+    let mut removal = Removal {
+        pkgs,
+        strategy: app.config.removal,
+        default_category: &app.config.default_category,
+        removed: Vec::new(),
+    };
     for (_, doc) in &mut app.docs {
-        stack.push(doc.nodes_mut());
+        let mut path = file_category(doc);
+        removal.remove_from_nodes(doc.nodes_mut(), &mut path, false);
     }
-    let comment = true; // TODO: get from config
 
-    while let Some(nodes) = stack.pop() {
-        if !comment {
-            nodes.retain(|node| !pkgs.iter().any(|pkg| pkg.name == node.name().value()));
+    if removal.strategy == RemovalStrategy::Graveyard && !removal.removed.is_empty() {
+        let removed = removal.removed;
+        // root declaration file is collected last
+        if let Some((_, doc)) = app.docs.last_mut() {
+            bury(doc, removed, app.config.format.indent);
         }
-        for node in nodes {
-            if comment {
-                if pkgs.iter().any(|pkg| pkg.name == node.name().value()) {
-                    node.format_mut()
-                        .expect("every node should have format")
-                        .leading += "/- ";
+    }
+
+    Ok(())
+}
+
+struct Removal<'a> {
+    pkgs: &'a [Package],
+    strategy: RemovalStrategy,
+    default_category: &'a Category,
+    /// Package nodes to move to graveyard
+    removed: Vec<KdlNode>,
+}
+
+impl Removal<'_> {
+    /// Returns whether any node was deleted from `nodes`. `nested` is set for children of a node.
+    fn remove_from_nodes(
+        &mut self,
+        nodes: &mut Vec<KdlNode>,
+        path: &mut Vec<String>,
+        nested: bool,
+    ) -> bool {
+        let mut deleted = false;
+        let mut idx = 0;
+
+        while idx < nodes.len() {
+            let name = nodes[idx].name().value().to_string();
+            if path.is_empty() && is_reserved_category(&name) {
+                idx += 1;
+                continue;
+            }
+
+            let category = name.strip_prefix("cat:");
+            if let Some(children) = nodes[idx].children_mut() {
+                if let Some(category) = category {
+                    path.push(category.to_string());
+                }
+                let children_deleted = self.remove_from_nodes(children.nodes_mut(), path, true);
+                let is_default = category.is_some()
+                    && Category::from_path(path).as_ref() == Some(self.default_category);
+                if category.is_some() {
+                    path.pop();
                 }
-                if let Some(children) = node.children_mut() {
-                    stack.push(children.nodes_mut());
+
+                if children_deleted && children.nodes().is_empty() {
+                    children.set_format(KdlDocumentFormat::default());
+                    if (category.is_some() || name.starts_with("host:")) && !is_default {
+                        nodes.remove(idx);
+                        deleted = true;
+                        continue;
+                    }
+                    // package with all subpackages removed
+                    if category.is_none() {
+                        nodes[idx].clear_children();
+                    }
+                }
+            }
+
+            let is_package = !name.contains([':', '@']);
+            if !is_package
+                || !self
+                    .pkgs
+                    .iter()
+                    .any(|pkg| pkg.name == Package::from_str(&name).name)
+            {
+                idx += 1;
+                continue;
+            }
+
+            let mut node = nodes.remove(idx);
+            let subpackages = take_subpackages(&mut node);
+            match self.strategy {
+                RemovalStrategy::Delete => deleted = true,
+                RemovalStrategy::Comment => {
+                    if let Some(format) = node.format_mut() {
+                        format.leading += "/- ";
+                    }
+                    nodes.insert(idx, node);
+                    idx += 1;
+                }
+                RemovalStrategy::Graveyard => {
+                    self.removed.push(node);
+                    deleted = true;
                 }
             }
+            let count = subpackages.len();
+            nodes.splice(idx..idx, subpackages);
+            idx += count;
+        }
+
+        if nested {
+            ensure_leading_newline(nodes);
+        }
+        deleted
+    }
+}
+
+/// Removes subpackage children of package node and reindents them to the level of the package
+fn take_subpackages(node: &mut KdlNode) -> Vec<KdlNode> {
+    let indent = node_indent(node);
+    let Some(children) = node.children_mut() else {
+        return Vec::new();
+    };
+
+    let (mut subpackages, rest): (Vec<KdlNode>, Vec<KdlNode>) = children
+        .nodes_mut()
+        .drain(..)
+        .partition(|child| !child.name().value().contains([':', '@']));
+    *children.nodes_mut() = rest;
+    ensure_leading_newline(children.nodes_mut());
+    if children.nodes().is_empty() {
+        node.clear_children();
+    }
+
+    for subpackage in &mut subpackages {
+        let from = node_indent(subpackage);
+        reindent(subpackage, from, indent);
+        // only the first child starts on a new line of its own
+        if let Some(format) = subpackage.format_mut()
+            && let Some(leading) = format.leading.strip_prefix('\n')
+        {
+            format.leading = leading.to_string();
         }
     }
+    subpackages
+}
 
-    Ok(())
+/// Moves package nodes to graveyard category at top level of `doc` with `removed` date property, older
+/// graveyard entries of the same packages are replaced
+fn bury(doc: &mut KdlDocument, removed: Vec<KdlNode>, width: usize) {
+    add_dated_nodes(doc, GRAVEYARD_CATEGORY, "removed", removed, width);
+}
+
+/// Appends package nodes to reserved top level `category` of `doc` with today's date in `property`, older
+/// entries of the same packages are replaced. `width` is the number of spaces per indentation level.
+fn add_dated_nodes(
+    doc: &mut KdlDocument,
+    category: &str,
+    property: &str,
+    nodes: Vec<KdlNode>,
+    width: usize,
+) {
+    let category_name = format!("cat:{category}");
+    let date = chrono::Local::now().format("%Y-%m-%d").to_string();
+
+    let idx = match doc
+        .nodes()
+        .iter()
+        .position(|node| node.name().value() == category_name)
+    {
+        Some(idx) => idx,
+        None => {
+            let mut category = KdlNode::new(category_name);
+            category.set_format(KdlNodeFormat {
+                leading: "\n".to_string(),
+                before_children: " ".to_string(),
+                terminator: "\n".to_string(),
+                ..Default::default()
+            });
+            category
+                .ensure_children()
+                .set_format(KdlDocumentFormat::default());
+            doc.nodes_mut().push(category);
+            doc.nodes().len() - 1
+        }
+    };
+
+    let entries = doc.nodes_mut()[idx].ensure_children().nodes_mut();
+    for mut node in nodes {
+        entries.retain(|entry| entry.name().value() != node.name().value());
+        node.insert(property, date.clone());
+        let from = node_indent(&node);
+        reindent(&mut node, from, width);
+        // comments above the package described its old place
+        if let Some(format) = node.format_mut() {
+            format.leading = " ".repeat(width);
+        }
+        entries.push(node);
+    }
+    ensure_leading_newline(entries);
+}
+
+/// Indentation of node in columns
+pub(crate) fn node_indent(node: &KdlNode) -> usize {
+    node.format()
+        .and_then(|format| format.leading.rsplit('\n').next())
+        .map(|line| line.len() - line.trim_start_matches(' ').len())
+        .unwrap_or(0)
+}
+
+/// Moves node with its children from indentation `from` to `to` columns
+fn reindent(node: &mut KdlNode, from: usize, to: usize) {
+    fn shift(text: &str, from: usize, to: usize) -> String {
+        let lines: Vec<&str> = text.split('\n').collect();
+        let last = lines.len() - 1;
+        lines
+            .iter()
+            .enumerate()
+            .map(|(idx, line)| {
+                // empty lines have no indentation, last line is indentation of the node itself
+                if line.is_empty() && idx != last {
+                    return line.to_string();
+                }
+                let spaces = line.len() - line.trim_start_matches(' ').len();
+                let spaces = (spaces + to).saturating_sub(from);
+                format!("{}{}", " ".repeat(spaces), line.trim_start_matches(' '))
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    if from == to {
+        return;
+    }
+    if let Some(format) = node.format_mut() {
+        format.leading = shift(&format.leading, from, to);
+    }
+    if let Some(children) = node.children_mut() {
+        if let Some(format) = children.format_mut() {
+            format.trailing = shift(&format.trailing, from, to);
+        }
+        for child in children.nodes_mut() {
+            reindent(child, from, to);
+        }
+    }
+}
+
+/// First node of a children block has to start on a new line after the opening brace
+fn ensure_leading_newline(nodes: &mut [KdlNode]) {
+    if let Some(format) = nodes.first_mut().and_then(|node| node.format_mut())
+        && !format.leading.starts_with('\n')
+    {
+        format.leading.insert(0, '\n');
+    }
 }
 
 fn print_diff(path: &Path, old: &str, new: &str) {
@@ -143,54 +1208,372 @@ fn print_diff(path: &Path, old: &str, new: &str) {
 }
 
 pub fn apply_dec_changes(app: &App) -> Result<()> {
+    if app.config.dry_run || app.config.verbose {
+        print_dec_changes(app);
+    }
+
+    if app.config.dry_run {
+        return Ok(());
+    }
+
+    write_dec_changes(app)
+}
+
+/// Prints diff for every declaration file that differs from its content on disk, returns whether there are any changes
+pub fn print_dec_changes(app: &App) -> bool {
+    let mut changed = false;
     for (file, doc) in &app.docs {
         let new_content = doc.to_string();
         let current_content = fs::read_to_string(file).unwrap_or_default();
 
-        if current_content == new_content {
-            continue;
-        }
-
-        if app.config.dry_run || app.config.verbose {
+        if current_content != new_content {
             print_diff(file, &current_content, &new_content);
+            changed = true;
         }
+    }
+    changed
+}
 
-        if app.config.dry_run {
-            continue;
-        }
+/// Backs up and writes every changed declaration file as a single transaction
+pub fn write_dec_changes(app: &App) -> Result<()> {
+    write_changes(app, None)
+}
 
-        backup(&app.config, file)?;
-        fs::write(file, new_content)?;
+/// Like `write_dec_changes`, but the backup is marked as revert to the state before snapshot `reverted`
+pub fn write_reverted_dec_changes(app: &App, reverted: &str) -> Result<()> {
+    write_changes(app, Some(reverted))
+}
+
+fn write_changes(app: &App, reverted: Option<&str>) -> Result<()> {
+    let changes: Vec<(&PathBuf, String)> = app
+        .docs
+        .iter()
+        .map(|(file, doc)| (file, doc.to_string()))
+        .filter(|(file, new_content)| fs::read_to_string(file).unwrap_or_default() != *new_content)
+        .collect();
+
+    if changes.is_empty() {
+        return Ok(());
     }
 
-    Ok(())
+    let mut transaction = Transaction::begin(app)?;
+    if let Some(reverted) = reverted {
+        transaction = transaction.reverting(reverted);
+    }
+    for (file, new_content) in &changes {
+        transaction.backup(file)?;
+        fs::write(file, new_content)?;
+    }
+    transaction.finish(&changes.iter().map(|(file, _)| *file).collect::<Vec<_>>())
 }
-fn backup(cfg: &Config, path: &PathBuf) -> Result<()> {
-    match cfg.backup.mode {
-        BackupMode::Basic => {
-            let backup_dir = path
-                .parent()
-                .context("config file must have a parent directory")?
-                .join(cfg.backup.dir.clone());
-            fs::create_dir_all(&backup_dir)?;
-
-            let file_name = path
-                .file_name()
-                .context("failed to get file name for backup")?;
-            let timestamp = chrono::Local::now().format("%Y%m%d_%H%M%S");
-            let backup_path =
-                backup_dir.join(format!("{}_{}", timestamp, file_name.to_string_lossy()));
-
-            fs::copy(path, &backup_path).with_context(|| {
-                format!(
-                    "failed to backup file {} to {}",
-                    path.display(),
-                    backup_path.display()
-                )
-            })?;
-            Ok(())
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::error_code;
+    use crate::testing::TestDir;
+
+    fn declaration_of(app: &App) -> String {
+        app.docs[0].1.to_string()
+    }
+
+    #[test]
+    fn moving_packages_keeps_format_of_untouched_empty_blocks() {
+        let test = TestDir::new(
+            "cat:uncat {\n}\ncat:dev {\n    cat:rust {\n        cargo\n    }\n    cat:python {\n        python\n    }\n}\n",
+            "",
+        );
+        let mut app = test.app(&test.cli(&["list"]));
+
+        move_pkgs(
+            &mut app,
+            &[Package::from_str("python")],
+            &Category::from_str("dev/rust"),
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            declaration_of(&app),
+            "cat:uncat {\n}\ncat:dev {\n    cat:rust {\n        cargo\n        python\n    }\n    cat:python {}\n}\n"
+        );
+    }
+
+    const NESTED: &str = "cat:dev {\n    python tag {\n        :options {\n            reason \"dependency\"\n        }\n        hook:after_install \"true\"\n        uv\n        ruff lint {\n            black\n        }\n    }\n}\n";
+
+    /// Declaration after `remove_pkgs` of `pkgs` with `removal` strategy
+    fn removed(declaration: &str, removal: &str, pkgs: &[&str]) -> String {
+        let test = TestDir::new(declaration, "");
+        test.write("config.kdl", &format!("removal \"{removal}\"\n"));
+        let mut app = test.app(&test.cli(&["list"]));
+        let pkgs: Vec<Package> = pkgs.iter().map(|pkg| Package::from_str(pkg)).collect();
+        remove_pkgs(&mut app, &pkgs).unwrap();
+        declaration_of(&app)
+    }
+
+    fn today() -> String {
+        chrono::Local::now().format("%Y-%m-%d").to_string()
+    }
+
+    #[test]
+    fn removed_packages_are_deleted() {
+        assert_eq!(
+            removed("cat:dev {\n    git\n    vim\n}\n", "delete", &["git"]),
+            "cat:dev {\n    vim\n}\n"
+        );
+    }
+
+    #[test]
+    fn removed_packages_are_commented_out() {
+        assert_eq!(
+            removed("cat:dev {\n    git\n    vim\n}\n", "comment", &["git"]),
+            "cat:dev {\n    /- git\n    vim\n}\n"
+        );
+    }
+
+    #[test]
+    fn removed_packages_are_moved_to_graveyard_with_date() {
+        assert_eq!(
+            removed("cat:dev {\n    git\n    vim\n}\n", "graveyard", &["git"]),
+            format!(
+                "cat:dev {{\n    vim\n}}\n\ncat:removed {{\n    git removed=\"{}\"\n}}\n",
+                today()
+            )
+        );
+    }
+
+    #[test]
+    fn subpackages_take_place_of_removed_parent() {
+        let promoted = "cat:dev {\n    uv\n    ruff lint {\n        black\n    }\n}\n";
+        assert_eq!(removed(NESTED, "delete", &["python"]), promoted);
+        // options and hooks stay with the package
+        assert_eq!(
+            removed(NESTED, "comment", &["python"]),
+            "cat:dev {\n    /- python tag {\n        :options {\n            reason \"dependency\"\n        }\n        hook:after_install \"true\"\n    }\n    uv\n    ruff lint {\n        black\n    }\n}\n"
+        );
+        assert_eq!(
+            removed(NESTED, "graveyard", &["python"]),
+            format!(
+                "{promoted}\ncat:removed {{\n    python tag removed=\"{}\" {{\n        :options {{\n            reason \"dependency\"\n        }}\n        hook:after_install \"true\"\n    }}\n}}\n",
+                today()
+            )
+        );
+        assert_eq!(
+            removed(NESTED, "delete", &["ruff"]),
+            "cat:dev {\n    python tag {\n        :options {\n            reason \"dependency\"\n        }\n        hook:after_install \"true\"\n        uv\n        black\n    }\n}\n"
+        );
+    }
+
+    #[test]
+    fn blocks_left_empty_are_deleted_except_default_category() {
+        assert_eq!(
+            removed(
+                "cat:dev {\n    git\n    cat:sub {\n        vim\n    }\n}\nhost:laptop {\n    htop\n}\ncat:uncat {\n    nano\n}\ncat:keep {\n    ed\n}\n",
+                "delete",
+                &["git", "vim", "htop", "nano"]
+            ),
+            "cat:uncat {}\ncat:keep {\n    ed\n}\n"
+        );
+    }
+
+    #[test]
+    fn comment_flag_overrides_configured_strategy() {
+        let test = TestDir::new("cat:dev {\n    git\n    vim\n}\n", "");
+        test.write("config.kdl", "removal \"graveyard\"\n");
+
+        crate::run(test.cli(&["rm", "git", "--comment"])).unwrap();
+
+        assert_eq!(
+            test.read("packages.kdl"),
+            "cat:dev {\n    /- git\n    vim\n}\n"
+        );
+    }
+
+    const TOOLS: &str = "@include \"web.kdl\"\ncat:dev {\n    cat:tools {\n        git\n    }\n}\ncat:ops {\n    cat:tools {\n        htop\n    }\n}\n";
+    const WEB_TOOLS: &str = "cat:dev {\n    cat:tools {\n        curl\n    }\n}\n";
+
+    fn tools() -> (TestDir, App) {
+        let test = TestDir::new(TOOLS, "");
+        test.write("web.kdl", WEB_TOOLS);
+        let app = test.app(&test.cli(&["list"]));
+        (test, app)
+    }
+
+    fn canonical(test: &TestDir, name: &str) -> PathBuf {
+        test.path(name).canonicalize().unwrap()
+    }
+
+    #[test]
+    fn unique_category_path_resolves_to_its_occurrence() {
+        let (test, app) = tools();
+
+        let occurrence = resolve_category(&app, &Category::from_str("ops/tools"), None).unwrap();
+
+        assert_eq!(occurrence.category, Category::from_str("ops/tools"));
+        assert_eq!(occurrence.file, canonical(&test, "packages.kdl"));
+        assert_eq!(occurrence.line, 8);
+    }
+
+    #[test]
+    fn cat_file_chooses_between_files() {
+        let (test, app) = tools();
+        let web = test.path("web.kdl");
+
+        let occurrence =
+            resolve_category(&app, &Category::from_str("dev/tools"), Some(&web)).unwrap();
+
+        assert_eq!(occurrence.category, Category::from_str("dev/tools"));
+        assert_eq!(occurrence.file, canonical(&test, "web.kdl"));
+        assert_eq!(occurrence.line, 2);
+
+        // leaf name is narrowed down to the only occurrence in the file
+        let occurrence = resolve_category(&app, &Category::from_str("tools"), Some(&web)).unwrap();
+        assert_eq!(occurrence.file, canonical(&test, "web.kdl"));
+    }
+
+    #[test]
+    fn ambiguous_category_without_terminal_is_an_error() {
+        let (test, app) = tools();
+        let packages = canonical(&test, "packages.kdl").display().to_string();
+        let web = canonical(&test, "web.kdl").display().to_string();
+
+        // same leaf under two parents and in two files
+        let error = resolve_category(&app, &Category::from_str("tools"), None)
+            .err()
+            .unwrap();
+        assert_eq!(error_code(&error), "category_ambiguous");
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "category 'tools' exists in several places, use full category path or --cat-file:\n    dev/tools ({web}:2)\n    dev/tools ({packages}:3)\n    ops/tools ({packages}:8)"
+            )
+        );
+
+        // same path in two files
+        let error = resolve_category(&app, &Category::from_str("dev/tools"), None)
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "category 'dev/tools' exists in several places, use full category path or --cat-file:\n    dev/tools ({web}:2)\n    dev/tools ({packages}:3)"
+            )
+        );
+    }
+
+    #[test]
+    fn occurrences_of_the_same_category_in_several_files_are_not_ambiguous() {
+        let (test, app) = tools();
+
+        let occurrences =
+            category_occurrences(&app, &Category::from_str("dev/tools"), None).unwrap();
+
+        let files: Vec<&PathBuf> = occurrences
+            .iter()
+            .map(|occurrence| &occurrence.file)
+            .collect();
+        assert_eq!(
+            files,
+            [
+                &canonical(&test, "web.kdl"),
+                &canonical(&test, "packages.kdl")
+            ]
+        );
+        assert!(category_occurrences(&app, &Category::from_str("tools"), None).is_err());
+    }
+
+    /// Declaration files after `foo` is added to created `category`
+    fn created(files: &[(&str, &str)], category: &str, cat_file: Option<&str>) -> Vec<String> {
+        let test = TestDir::new(files[0].1, "");
+        for (name, content) in &files[1..] {
+            test.write(name, content);
         }
-        BackupMode::Git => todo!("backup mode not implemented"), // TODO: implement git mode
-        BackupMode::Off => Ok(()),
+        let mut app = test.app(&test.cli(&["list"]));
+        let category = Category::from_str(category);
+        let cat_file = cat_file.map(|name| test.path(name));
+        create_category(&mut app, &category, cat_file.as_deref()).unwrap();
+        add_pkgs(
+            &mut app,
+            &category,
+            cat_file.as_deref(),
+            &[Package::from_str("foo")],
+        )
+        .unwrap();
+        app.docs.iter().map(|(_, doc)| doc.to_string()).collect()
+    }
+
+    #[test]
+    fn missing_categories_are_created_under_longest_existing_parent() {
+        assert_eq!(
+            created(
+                &[("packages.kdl", "cat:dev {\n    git\n}\n")],
+                "dev/rust/new",
+                None
+            ),
+            [
+                "cat:dev {\n    git\n    cat:rust {\n        cat:new {\n            foo\n        }\n    }\n}\n"
+            ]
+        );
+        assert_eq!(
+            created(
+                &[(
+                    "packages.kdl",
+                    "cat:dev {\n    git\n    cat:rust {\n        cargo\n    }\n}\n"
+                )],
+                "dev/rust/new",
+                None
+            ),
+            [
+                "cat:dev {\n    git\n    cat:rust {\n        cargo\n        cat:new {\n            foo\n        }\n    }\n}\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn category_without_existing_parent_is_created_at_top_level() {
+        assert_eq!(
+            created(&[("packages.kdl", "cat:uncat {}\n")], "dev/rust/new", None),
+            [
+                "cat:uncat {}\n\ncat:dev {\n    cat:rust {\n        cat:new {\n            foo\n        }\n    }\n}\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn category_under_file_category_is_created_in_that_file() {
+        assert_eq!(
+            created(
+                &[
+                    ("packages.kdl", "@include \"dev.kdl\"\ncat:uncat {}\n"),
+                    ("dev.kdl", "@file \"cat:dev\"\ngit\n"),
+                ],
+                "dev/rust",
+                None
+            ),
+            [
+                "@file \"cat:dev\"\ngit\ncat:rust {\n    foo\n}\n",
+                "@include \"dev.kdl\"\ncat:uncat {}\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn top_level_category_is_not_created_in_file_category() {
+        let test = TestDir::new("@include \"dev.kdl\"\ncat:uncat {}\n", "");
+        test.write("dev.kdl", "@file \"cat:dev\"\ngit\n");
+        let mut app = test.app(&test.cli(&["list"]));
+        let dev = test.path("dev.kdl");
+
+        let error = create_category(&mut app, &Category::from_str("web"), Some(&dev))
+            .err()
+            .unwrap();
+
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "{} belongs to category 'dev', top level category 'web' can not be created in it",
+                dev.canonicalize().unwrap().display()
+            )
+        );
     }
 }
@@ -1,92 +1,259 @@
 use anyhow::{Context, Result, bail};
-use kdl::KdlDocument;
+use kdl::{KdlDocument, KdlNode};
+use miette::SourceSpan;
 use std::fs;
 use std::{
-    collections::{HashMap, HashSet},
-    fs::File,
-    io::{BufRead, BufReader},
+    collections::{BTreeSet, HashMap, HashSet},
     path::{Path, PathBuf},
 };
 
+use crate::alpm_db::{DbPackage, dep_name};
 use crate::app::App;
-use crate::packages::Package;
-use crate::pacman::run_pacman;
+use crate::config::Exclusion;
+use crate::kdl_edit::{IGNORED_CATEGORY, is_reserved_category};
+use crate::output::coded;
+use crate::packages::{Category, Package, SourceLocation, TagSelector};
+use crate::pacman_log::{install_order, read_log};
 
+/// Explicitly installed packages ordered by their first installation in pacman log, packages missing from
+/// the log come first. Ordering is skipped when the log does not exist or can not be read.
 pub fn get_exp_pkg_list(app: &App, log_file_path: &Path) -> Result<Vec<Package>> {
-    let output = run_pacman(&app.config, &["-Qqe"])?;
-    let mut explicit_pkgs: HashMap<String, Option<i32>> =
-        output.lines().map(|s| (s.to_string(), None)).collect();
-
-    let log_file = File::open(log_file_path)?; // TODO: log_file based sortering should be optional
-    let reader = BufReader::new(log_file);
-
-    let mut pkg_num = 0;
-    for line in reader.lines() {
-        let line = line?;
-        if line.contains("[ALPM] installed") {
-            // Parse the line: [timestamp] [ALPM] installed package_name (version)
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() >= 4 {
-                // let timestamp = &parts[0][1..parts[0].len() - 1]; // Remove the surrounding brackets
-                let package_with_version = parts[3];
-
-                // Extract just the package name (remove version in parentheses if present)
-                let package_name = if let Some(start_idx) = package_with_version.find('(') {
-                    package_with_version[..start_idx].trim_end()
-                } else {
-                    package_with_version
-                };
-
-                if !explicit_pkgs.contains_key(package_name) {
-                    continue;
-                }
+    let mut explicit_pkgs = app.backend.query_explicit()?;
+    explicit_pkgs.sort();
 
-                // Store only the first installation time found (like rg -m1)
-                explicit_pkgs
-                    .entry(package_name.to_string())
-                    .and_modify(|existing| {
-                        if existing.is_none() {
-                            *existing = Some(pkg_num);
-                        }
-                    });
-                pkg_num += 1;
-            }
-        }
-    }
-    let mut installation_timesvec: Vec<(String, Option<i32>)> = explicit_pkgs.into_iter().collect();
-    installation_timesvec.sort_by(|a, b| a.1.cmp(&b.1));
+    let events = if log_file_path.exists() {
+        read_log(log_file_path).unwrap_or_else(|e| {
+            eprintln!("Warning: {e:#}. Packages are not ordered by installation.");
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+    let order = install_order(&events);
+    explicit_pkgs.sort_by_key(|pkg| order.get(pkg.as_str()).copied());
 
-    Ok(installation_timesvec
-        .into_iter()
-        .map(|x| x.0.into())
-        .collect())
+    Ok(explicit_pkgs.into_iter().map(Package::from).collect())
 }
 
-/// Returns a tuple of (installed_only, declared_only)
-pub fn get_pkg_diff(app: &App) -> Result<(Vec<Package>, Vec<Package>)> {
+pub struct PkgDiff {
+    /// Explicitly installed packages missing from declaration and not needed by declared packages
+    pub installed_only: Vec<Package>,
+    /// Declared packages that are not installed
+    pub declared_only: Vec<Package>,
+    /// Declared packages disabled by whitelist/blacklist, they are neither installed nor reported as extra
+    pub excluded: Vec<(Package, Exclusion)>,
+    /// Packages declared with `reason "dependency"` that are installed explicitly
+    pub explicit_deps: Vec<Package>,
+    /// Declared packages installed as dependencies, their install reason is changed instead of reinstalling
+    pub installed_as_deps: Vec<Package>,
+    /// Explicitly installed packages missing from declaration that declared packages depend on, they are
+    /// marked as dependencies instead of uninstalled
+    pub required_undeclared: Vec<Package>,
+}
+
+/// Compares installed and declared packages. Declared packages not matching `selector` are ignored:
+/// they are neither reported as missing nor as extra
+pub fn get_pkg_diff(app: &App, selector: &TagSelector) -> Result<PkgDiff> {
     let installed_pkgs = get_exp_pkg_list(app, &app.config.pacman_log_file)?;
     let declared_pkgs = get_declared_pkg_list(app)?;
 
-    let installed_set: HashSet<Package> = installed_pkgs.iter().cloned().collect();
-
-    let installed_only: Vec<Package> = installed_pkgs
+    let explicit_set: HashSet<Package> = installed_pkgs.iter().cloned().collect();
+    let dependency_set: HashSet<Package> = app
+        .backend
+        .query_dependencies()?
         .into_iter()
-        .filter(|pkg| !declared_pkgs.contains(pkg))
+        .map(Package::from)
         .collect();
 
-    let declared_only: Vec<Package> = declared_pkgs
+    let ignored = get_ignored_pkg_list(app);
+    let undeclared: Vec<Package> = installed_pkgs
         .into_iter()
-        .filter(|pkg| !installed_set.contains(pkg))
+        .filter(|pkg| !declared_pkgs.contains(pkg) && !ignored.contains(&pkg.name))
+        .collect();
+    let (required_undeclared, installed_only) = if undeclared.is_empty() {
+        (Vec::new(), Vec::new())
+    } else {
+        let local = app.backend.query_local()?;
+        let required = required_by(
+            &local,
+            declared_pkgs.iter().map(|pkg| pkg.name.as_str()),
+            false,
+        );
+        undeclared
+            .into_iter()
+            .partition(|pkg| required.contains(&pkg.name))
+    };
+
+    let mut declared_only = Vec::new();
+    let mut excluded = Vec::new();
+    let mut explicit_deps = Vec::new();
+    let mut installed_as_deps = Vec::new();
+    for pkg in declared_pkgs
+        .iter()
+        .filter(|pkg| selector.matches(pkg))
+        .cloned()
+    {
+        if let Some(exclusion) = exclusion(app, &pkg, &declared_pkgs) {
+            excluded.push((pkg, exclusion));
+        } else if explicit_set.contains(&pkg) {
+            if pkg.options.as_dependency {
+                explicit_deps.push(pkg);
+            }
+        } else if dependency_set.contains(&pkg) {
+            if !pkg.options.as_dependency {
+                installed_as_deps.push(pkg);
+            }
+        } else {
+            declared_only.push(pkg);
+        }
+    }
+    excluded.sort_by(|a, b| a.0.cmp(&b.0));
+    explicit_deps.sort();
+    installed_as_deps.sort();
+
+    Ok(PkgDiff {
+        installed_only,
+        declared_only,
+        excluded,
+        explicit_deps,
+        installed_as_deps,
+        required_undeclared,
+    })
+}
+
+/// Names of installed packages `roots` depend on, directly or through other installed packages.
+/// Dependencies are matched by package name or by what packages provide. With `optional` set optional
+/// dependencies count too, like they do for `pacman -Qdt`.
+fn required_by<'a>(
+    local: &[DbPackage],
+    roots: impl Iterator<Item = &'a str>,
+    optional: bool,
+) -> HashSet<String> {
+    let mut providers: HashMap<&str, &DbPackage> =
+        local.iter().map(|pkg| (pkg.name.as_str(), pkg)).collect();
+    for pkg in local {
+        for provided in &pkg.provides {
+            providers.entry(dep_name(provided)).or_insert(pkg);
+        }
+    }
+
+    let mut required = HashSet::new();
+    let mut queue: Vec<&DbPackage> = roots
+        .filter_map(|name| providers.get(name).copied())
+        .collect();
+    while let Some(pkg) = queue.pop() {
+        let optional_deps = pkg.optional_deps.iter().filter(|_| optional);
+        for dep in pkg.depends.iter().chain(optional_deps) {
+            if let Some(dep) = providers.get(dep_name(dep))
+                && required.insert(dep.name.clone())
+            {
+                queue.push(dep);
+            }
+        }
+    }
+    required
+}
+
+/// Packages installed as dependencies that nothing needs once `diff` is applied by `sync`, like repeated
+/// `pacman -Qdtq`. Dependencies of declared packages, explicitly installed packages that stay, packages
+/// about to be installed and `keep_orphans` are kept. Dependencies of uninstalled packages are not
+/// reported, `pacman -Rns` takes them away anyway.
+pub fn get_orphans(app: &App, diff: &PkgDiff) -> Result<Vec<Package>> {
+    let local = app.backend.query_local()?;
+    let uninstalled: HashSet<&str> = diff
+        .installed_only
+        .iter()
+        .map(|pkg| pkg.name.as_str())
         .collect();
 
-    Ok((installed_only, declared_only))
+    let mut roots: HashSet<String> = local
+        .iter()
+        .filter(|pkg| pkg.explicit && !uninstalled.contains(pkg.name.as_str()))
+        .map(|pkg| pkg.name.clone())
+        .collect();
+    roots.extend(get_declared_pkg_list(app)?.into_iter().map(|pkg| pkg.name));
+    roots.extend(app.config.keep_orphans.iter().cloned());
+    let mut new_deps = Vec::new();
+    for pkg in &diff.declared_only {
+        if let Some(db_pkg) = app.backend.sync_info(&pkg.to_string())? {
+            new_deps.extend(db_pkg.depends);
+        }
+    }
+    // installed packages satisfying what new packages depend on are reused by the install
+    roots.extend(
+        local
+            .iter()
+            .filter(|pkg| new_deps.iter().any(|dep| pkg.satisfies(dep_name(dep))))
+            .map(|pkg| pkg.name.clone()),
+    );
+
+    let mut needed = required_by(&local, roots.iter().map(String::as_str), true);
+    needed.extend(roots);
+    let cascaded = required_by(&local, uninstalled.into_iter(), false);
+
+    Ok(local
+        .into_iter()
+        .filter(|pkg| !pkg.explicit && !needed.contains(&pkg.name) && !cascaded.contains(&pkg.name))
+        .map(|pkg| Package::from(pkg.name))
+        .collect())
+}
+
+/// Returns declared packages not disabled by whitelist/blacklist
+pub fn get_enabled_pkg_list(app: &App) -> Result<HashSet<Package>> {
+    let declared = get_declared_pkg_list(app)?;
+    Ok(declared
+        .iter()
+        .filter(|pkg| exclusion(app, pkg, &declared).is_none())
+        .cloned()
+        .collect())
+}
+
+/// Returns why declared `pkg` is disabled by whitelist/blacklist rules. Subpackages are disabled together
+/// with any package they are nested in, `declared` is where parents are looked up.
+pub fn exclusion(app: &App, pkg: &Package, declared: &HashSet<Package>) -> Option<Exclusion> {
+    let rules = &app.config.packages;
+    if let Some(exclusion) = rules.exclusion(&pkg.resolved_tags()) {
+        return Some(exclusion);
+    }
+    pkg.parents.iter().find_map(|parent| {
+        let parent = declared.get(&Package::from_str(parent))?;
+        rules
+            .exclusion(&parent.resolved_tags())
+            .map(|_| Exclusion::ParentDisabled(parent.name.clone()))
+    })
+}
+
+/// Names of packages in ignored category at top level of declaration files
+pub fn get_ignored_pkg_list(app: &App) -> HashSet<String> {
+    app.docs
+        .iter()
+        .filter(|(_, doc)| file_category(doc).is_empty())
+        .flat_map(|(_, doc)| doc.nodes())
+        .filter(|node| node.name().value().strip_prefix("cat:") == Some(IGNORED_CATEGORY))
+        .flat_map(|node| node.iter_children())
+        .map(|node| Package::from_str(node.name().value()).name)
+        .collect()
 }
 
 pub fn get_declared_pkg_list(app: &App) -> Result<HashSet<Package>> {
+    get_host_pkg_list(app, &app.config.host)
+}
+
+/// Returns every node declaring a package for any host, the same package may be declared several times
+pub fn get_declared_pkg_occurrences(app: &App) -> Result<Vec<Package>> {
+    let mut packages = Vec::new();
+    for (path, doc) in &app.docs {
+        collect_package_occurrences(doc, path, None, &mut packages)?;
+    }
+    Ok(packages)
+}
+
+/// Returns packages declared for `host`
+pub fn get_host_pkg_list(app: &App, host: &str) -> Result<HashSet<Package>> {
     let mut packages = HashSet::new();
 
-    for (_, doc) in &app.docs {
-        collect_packages_from_doc(doc, &mut packages)?;
+    for (path, doc) in &app.docs {
+        collect_packages_from_doc(doc, path, Some(host), &mut packages)?;
     }
 
     Ok(packages)
@@ -96,7 +263,29 @@ pub fn collect_documents(root_path: &Path) -> Result<Vec<(PathBuf, KdlDocument)>
     let mut visited: HashSet<PathBuf> = HashSet::new();
     let mut documents = Vec::new();
 
-    collect_documents_recursive(root_path, &mut visited, &mut documents)?;
+    collect_documents_recursive(root_path, &mut visited, &mut documents, None)?;
+
+    Ok(documents)
+}
+
+/// `@include` that could not be loaded by `collect_documents_lenient`
+pub struct IncludeProblem {
+    /// File containing the `@include`
+    pub file: PathBuf,
+    pub span: SourceSpan,
+    pub error: anyhow::Error,
+}
+
+/// Like `collect_documents`, but includes which do not exist, are cyclic or can not be parsed are
+/// recorded in `problems` and skipped. Only the root file is required to load.
+pub fn collect_documents_lenient(
+    root_path: &Path,
+    problems: &mut Vec<IncludeProblem>,
+) -> Result<Vec<(PathBuf, KdlDocument)>> {
+    let mut visited: HashSet<PathBuf> = HashSet::new();
+    let mut documents = Vec::new();
+
+    collect_documents_recursive(root_path, &mut visited, &mut documents, Some(problems))?;
 
     Ok(documents)
 }
@@ -105,6 +294,7 @@ fn collect_documents_recursive(
     path: &Path,
     visited: &mut HashSet<PathBuf>,
     documents: &mut Vec<(PathBuf, KdlDocument)>,
+    mut problems: Option<&mut Vec<IncludeProblem>>,
 ) -> Result<()> {
     let canonical_path = path
         .canonicalize()
@@ -129,8 +319,21 @@ fn collect_documents_recursive(
                 && let Some(path_str) = entry.value().as_string()
             {
                 let include_path = base_dir.join(path_str);
-                collect_documents_recursive(&include_path, visited, documents)
-                    .with_context(|| format!("failed to include file: {path_str}"))?;
+                let result = collect_documents_recursive(
+                    &include_path,
+                    visited,
+                    documents,
+                    problems.as_deref_mut(),
+                )
+                .with_context(|| format!("failed to include file: {path_str}"));
+                match (result, problems.as_deref_mut()) {
+                    (Err(error), Some(problems)) => problems.push(IncludeProblem {
+                        file: canonical_path.clone(),
+                        span: node.span(),
+                        error,
+                    }),
+                    (result, _) => result?,
+                }
             }
         }
     }
@@ -139,40 +342,159 @@ fn collect_documents_recursive(
     Ok(())
 }
 
-fn parse_kdl_document(config_file_path: &Path) -> Result<KdlDocument> {
+pub fn parse_kdl_document(config_file_path: &Path) -> Result<KdlDocument> {
     let src = fs::read_to_string(config_file_path)
         .with_context(|| format!("failed to read config file: {}", config_file_path.display()))?;
 
+    parse_kdl_source(&src, config_file_path)
+}
+
+pub fn parse_kdl_source(src: &str, config_file_path: &Path) -> Result<KdlDocument> {
     src.parse::<KdlDocument>()
         .map_err(|e| {
             let report = miette::Report::new(e);
-            anyhow::anyhow!("Failed to parse KDL: {report:?}").context("KDL parsing failed")
+            coded(
+                "declaration_invalid",
+                format!("Failed to parse KDL: {report:?}"),
+            )
+            .context("KDL parsing failed")
         })
         .with_context(|| format!("failed to parse KDL from: {}", config_file_path.display()))
 }
 
-fn collect_packages_from_doc(doc: &KdlDocument, packages: &mut HashSet<Package>) -> Result<()> {
-    for node in doc.nodes() {
+/// Collects packages active on `host`, or all packages if `host` is `None`. `file` is the path `doc` was
+/// read from, it is recorded as source of the packages
+pub fn collect_packages_from_doc(
+    doc: &KdlDocument,
+    file: &Path,
+    host: Option<&str>,
+    packages: &mut HashSet<Package>,
+) -> Result<()> {
+    let mut occurrences = Vec::new();
+    collect_package_occurrences(doc, file, host, &mut occurrences)?;
+    // the first declaration of a package wins
+    packages.extend(occurrences);
+    Ok(())
+}
+
+/// Like `collect_packages_from_doc`, but every node declaring a package is collected, in declaration order
+pub fn collect_package_occurrences(
+    doc: &KdlDocument,
+    file: &Path,
+    host: Option<&str>,
+    packages: &mut Vec<Package>,
+) -> Result<()> {
+    // spans point into the source the document was parsed from, which is reproduced by `to_string`
+    let src = doc.to_string();
+    let locate = |node: &KdlNode| SourceLocation {
+        file: file.to_path_buf(),
+        line: source_line(&src, node.span().offset()),
+    };
+    collect_packages_from_nodes(
+        doc.nodes(),
+        host,
+        &locate,
+        &mut file_category(doc),
+        &mut Vec::new(),
+        &mut Vec::new(),
+        packages,
+    )
+}
+
+/// 1-based line of byte `offset` in `src`
+pub fn source_line(src: &str, offset: usize) -> usize {
+    src.as_bytes()[..offset.min(src.len())]
+        .iter()
+        .filter(|&&byte| byte == b'\n')
+        .count()
+        + 1
+}
+
+/// `path` is the current category path, `tags` are explicit tags of enclosing categories and packages,
+/// `parents` are names of enclosing packages
+fn collect_packages_from_nodes(
+    nodes: &[KdlNode],
+    host: Option<&str>,
+    locate: &dyn Fn(&KdlNode) -> SourceLocation,
+    path: &mut Vec<String>,
+    tags: &mut Vec<String>,
+    parents: &mut Vec<String>,
+    packages: &mut Vec<Package>,
+) -> Result<()> {
+    for node in nodes {
         let node_name = node.name().value();
 
+        if let Some(host) = host
+            && !is_active_on_host(node, host)
+        {
+            continue;
+        }
+
         match node_name {
             name if !name.contains([':', '@']) => {
-                if let Ok(pkg) = Package::try_from(node.clone()) {
-                    packages.insert(pkg);
-                } else {
-                    println!(
-                        "Warning: Failed to parse package from node '{}'. Skipping.",
+                match Package::try_from(node.clone()) {
+                    Ok(mut pkg) => {
+                        pkg.category = Category::from_path(path);
+                        pkg.inherited_tags = tags.clone();
+                        pkg.parents = parents.clone();
+                        pkg.source = Some(locate(node));
+                        packages.push(pkg);
+                    }
+                    Err(e) => eprintln!(
+                        "Warning: Failed to parse package from node '{}': {e}. Skipping.",
                         node_name
-                    );
+                    ),
                 }
 
+                // subpackages follow their parent: they inherit its tags
                 if let Some(children) = node.children() {
-                    collect_packages_from_doc(children, packages)?;
+                    let tags_len = tags.len();
+                    tags.extend(node_tags(node));
+                    parents.push(Package::from_str(name).name);
+                    collect_packages_from_nodes(
+                        children.nodes(),
+                        host,
+                        locate,
+                        path,
+                        tags,
+                        parents,
+                        packages,
+                    )?;
+                    parents.pop();
+                    tags.truncate(tags_len);
                 }
             }
+            // packages moved to graveyard or ignored are not declared
+            name if path.is_empty() && is_reserved_category(name) => {}
             name if name.starts_with("cat:") => {
                 if let Some(children) = node.children() {
-                    collect_packages_from_doc(children, packages)?;
+                    let tags_len = tags.len();
+                    tags.extend(node_tags(node));
+                    path.push(name.trim_start_matches("cat:").to_string());
+                    collect_packages_from_nodes(
+                        children.nodes(),
+                        host,
+                        locate,
+                        path,
+                        tags,
+                        parents,
+                        packages,
+                    )?;
+                    path.pop();
+                    tags.truncate(tags_len);
+                }
+            }
+            name if name.starts_with("host:") => {
+                if let Some(children) = node.children() {
+                    collect_packages_from_nodes(
+                        children.nodes(),
+                        host,
+                        locate,
+                        path,
+                        tags,
+                        parents,
+                        packages,
+                    )?;
                 }
             }
             _ => {}
@@ -180,3 +502,155 @@ fn collect_packages_from_doc(doc: &KdlDocument, packages: &mut HashSet<Package>)
     }
     Ok(())
 }
+
+/// String arguments of a node, used as tags of packages and categories
+pub fn node_tags(node: &KdlNode) -> Vec<String> {
+    node.entries()
+        .iter()
+        .filter(|entry| entry.name().is_none())
+        .filter_map(|entry| entry.value().as_string())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Category path set by `@file cat:name` directive, whole file content belongs to this category.
+/// Nested path like `@file cat:dev/rust` is allowed, invalid directive is ignored (see `declaration_warnings`).
+pub fn file_category(doc: &KdlDocument) -> Vec<String> {
+    doc.get("@file")
+        .and_then(file_category_arg)
+        .map(|category| category.split('/').map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+/// Category path of `@file` node without `cat:` prefix, `None` if the argument is invalid
+pub fn file_category_arg(node: &KdlNode) -> Option<&str> {
+    node.entries()
+        .first()
+        .and_then(|entry| entry.value().as_string())
+        .and_then(|value| value.strip_prefix("cat:"))
+}
+
+/// Warnings about ignored directives of loaded documents, printed once by `App::init`. `check` reports
+/// them as diagnostics instead.
+pub fn declaration_warnings(docs: &[(PathBuf, KdlDocument)]) -> Vec<String> {
+    docs.iter()
+        .filter(|(_, doc)| {
+            doc.get("@file")
+                .is_some_and(|node| file_category_arg(node).is_none())
+        })
+        .map(|(file, _)| {
+            format!(
+                "Warning: @file in {} expects 'cat:name' argument. Ignoring.",
+                file.display()
+            )
+        })
+        .collect()
+}
+
+/// Hosts listed in a `host:laptop,desktop` node name or `hosts="laptop,desktop"` property
+pub fn node_hosts(node: &KdlNode) -> Option<Vec<&str>> {
+    let hosts = match node.name().value().strip_prefix("host:") {
+        Some(hosts) => hosts,
+        None => node.get("hosts")?.as_string()?,
+    };
+    Some(hosts.split(',').map(str::trim).collect())
+}
+
+/// Node is active if it is not restricted to any hosts or `host` is one of them
+pub fn is_active_on_host(node: &KdlNode, host: &str) -> bool {
+    node_hosts(node).is_none_or(|hosts| hosts.contains(&host))
+}
+
+/// Returns every host mentioned in declaration files
+pub fn collect_hosts(app: &App) -> BTreeSet<String> {
+    fn traverse(nodes: &[KdlNode], hosts: &mut BTreeSet<String>) {
+        for node in nodes {
+            if let Some(node_hosts) = node_hosts(node) {
+                hosts.extend(node_hosts.into_iter().map(str::to_string));
+            }
+            if let Some(children) = node.children() {
+                traverse(children.nodes(), hosts);
+            }
+        }
+    }
+
+    let mut hosts = BTreeSet::new();
+    for (_, doc) in &app.docs {
+        traverse(doc.nodes(), &mut hosts);
+    }
+    hosts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+
+    fn doc(src: &str) -> KdlDocument {
+        src.parse().unwrap()
+    }
+
+    #[test]
+    fn hosts_come_from_host_blocks_and_hosts_property() {
+        let doc =
+            doc("host:laptop,desktop {}\ngit hosts=\"laptop, server\"\nvim\ncat:dev hosts=1 {}\n");
+        let hosts: Vec<Option<Vec<&str>>> = doc.nodes().iter().map(node_hosts).collect();
+        assert_eq!(
+            hosts,
+            [
+                Some(vec!["laptop", "desktop"]),
+                Some(vec!["laptop", "server"]),
+                None,
+                None
+            ]
+        );
+
+        let active = |host: &str| -> Vec<bool> {
+            doc.nodes()
+                .iter()
+                .map(|node| is_active_on_host(node, host))
+                .collect()
+        };
+        assert_eq!(active("laptop"), [true, true, true, true]);
+        assert_eq!(active("desktop"), [true, false, true, true]);
+        assert_eq!(active("other"), [false, false, true, true]);
+    }
+
+    #[test]
+    fn hosts_are_collected_from_every_file_and_level() {
+        let test = TestDir::new(
+            "@include \"dev.kdl\"\nhost:laptop {\n    cat:web hosts=\"desktop\" {\n        curl\n    }\n}\n",
+            "",
+        );
+        test.write(
+            "dev.kdl",
+            "@file \"cat:dev\"\ngit {\n    tig hosts=\"server,laptop\"\n}\n",
+        );
+        let app = test.app(&test.cli(&["list"]));
+
+        assert_eq!(
+            collect_hosts(&app).into_iter().collect::<Vec<_>>(),
+            ["desktop", "laptop", "server"]
+        );
+    }
+
+    #[test]
+    fn invalid_file_category_is_ignored_with_one_warning() {
+        assert_eq!(
+            file_category(&doc("@file \"cat:dev/rust\"\n")),
+            ["dev", "rust"]
+        );
+        assert!(file_category(&doc("git\n")).is_empty());
+
+        let invalid = doc("@file \"dev\"\ngit\n");
+        assert!(file_category(&invalid).is_empty());
+        let docs = [
+            (PathBuf::from("dev.kdl"), invalid),
+            (PathBuf::from("rust.kdl"), doc("@file \"cat:rust\"\n")),
+        ];
+        assert_eq!(
+            declaration_warnings(&docs),
+            ["Warning: @file in dev.kdl expects 'cat:name' argument. Ignoring."]
+        );
+    }
+}
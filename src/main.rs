@@ -1,12 +1,25 @@
+mod alpm_db;
 mod app;
+mod backend;
+mod backup;
+mod check;
 mod cli;
 mod commands;
 mod config;
+#[cfg(any(test, feature = "fake-backend"))]
+mod fake_backend;
+mod formatter;
+mod hooks;
 mod kdl_edit;
 mod list_pkgs;
+mod output;
 mod packages;
 mod pacman;
+mod pacman_log;
 mod prompts;
+#[cfg(test)]
+mod testing;
+mod triage;
 
 use anyhow::Result;
 use clap::Parser;
@@ -15,29 +28,64 @@ use colored::Colorize;
 use commands::*;
 
 use crate::app::App;
+use crate::output::{
+    EXIT_DRIFT, EXIT_FAILURE, ErrorJson, OutputFormat, Reported, error_code, print_json,
+};
 
 fn main() {
-    if let Err(e) = run() {
-        eprintln!("{} {}", "Error:".red().bold(), e);
-        std::process::exit(1);
+    // usage errors exit with EXIT_FAILURE as well, clap would use 2 which means drift
+    let cli = Cli::try_parse().unwrap_or_else(|e| {
+        let _ = e.print();
+        std::process::exit(if e.use_stderr() { EXIT_FAILURE } else { 0 });
+    });
+    let output = cli.output;
+    if output == OutputFormat::Json {
+        colored::control::set_override(false);
     }
-}
 
-fn run() -> Result<()> {
-    let cli = Cli::parse();
+    match run(cli) {
+        Ok(true) => std::process::exit(EXIT_DRIFT),
+        Ok(false) => {}
+        Err(e) if e.is::<Reported>() => std::process::exit(EXIT_FAILURE),
+        Err(e) => {
+            match output {
+                OutputFormat::Text => eprintln!("{} {}", "Error:".red().bold(), e),
+                OutputFormat::Json => {
+                    let error = ErrorJson {
+                        code: error_code(&e),
+                        message: e.to_string(),
+                    };
+                    let _ = print_json(&serde_json::json!({ "error": error }));
+                }
+            }
+            std::process::exit(EXIT_FAILURE);
+        }
+    }
+}
 
+/// Returns whether drift between system and declaration was found and left unapplied
+fn run(cli: Cli) -> Result<bool> {
     let mut app = App::init(&cli)?;
 
     match &cli.command {
-        cli::Commands::Sync(_) => sync_cmd(&app)?,
-        cli::Commands::Generate(_) => gen_cmd(&mut app)?,
+        cli::Commands::Sync(args) => return sync_cmd(&app, args),
+        cli::Commands::Generate(args) => return gen_cmd(&mut app, args),
         cli::Commands::Add(_) => add_cmd(&mut app, cli, false)?,
         cli::Commands::Remove(_) => remove_cmd(&mut app, cli, false)?,
         cli::Commands::Install(_) => add_cmd(&mut app, cli, true)?,
         cli::Commands::Uninstall(_) => remove_cmd(&mut app, cli, true)?,
+        cli::Commands::Move(args) => move_cmd(&mut app, args)?,
+        cli::Commands::Cat(args) => cat_cmd(&mut app, args)?,
         cli::Commands::Search(args) => search_cmd(&app, args)?,
-        _ => unimplemented!(),
+        cli::Commands::Revert(args) => revert_cmd(&mut app, args)?,
+        cli::Commands::Log(args) => log_cmd(&app, args)?,
+        cli::Commands::History(args) => history_cmd(&app, args)?,
+        cli::Commands::Hosts(args) => hosts_cmd(&app, args)?,
+        cli::Commands::List(args) => list_cmd(&app, args)?,
+        cli::Commands::Tree(args) => tree_cmd(&app, args)?,
+        cli::Commands::Check(args) => check_cmd(&mut app, args)?,
+        cli::Commands::Fmt(args) => return fmt_cmd(&mut app, args),
     }
 
-    Ok(())
+    Ok(false)
 }
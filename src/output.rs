@@ -0,0 +1,334 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::backup::Snapshot;
+use crate::config::Exclusion;
+use crate::packages::{Category, Package};
+use crate::pacman_log::LogEvent;
+
+/// Exit code of any failure
+pub const EXIT_FAILURE: i32 = 1;
+/// Exit code of `sync`/`generate` when system and declaration differ and nothing was applied
+pub const EXIT_DRIFT: i32 = 2;
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Error with a stable code, reported as `error.code` in json output. Errors without one get code `error`.
+#[derive(Debug)]
+pub struct CodedError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl fmt::Display for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for CodedError {}
+
+pub fn coded(code: &'static str, message: impl Into<String>) -> anyhow::Error {
+    CodedError {
+        code,
+        message: message.into(),
+    }
+    .into()
+}
+
+/// Failure already described by the json document a command printed, `main` only sets the exit code
+#[derive(Debug)]
+pub struct Reported(pub anyhow::Error);
+
+impl fmt::Display for Reported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Reported {}
+
+pub fn error_code(e: &anyhow::Error) -> &'static str {
+    let e = e
+        .downcast_ref::<Reported>()
+        .map_or(e, |reported| &reported.0);
+    e.downcast_ref::<CodedError>()
+        .map_or("error", |coded| coded.code)
+}
+
+pub fn print_json(value: &impl Serialize) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct ErrorJson<'a> {
+    pub code: &'a str,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+pub struct PackageJson {
+    pub name: String,
+    pub repository: Option<String>,
+    pub tags: Vec<String>,
+    /// Tags inherited from categories and parent packages
+    pub implicit_tags: Vec<String>,
+    pub category: Option<String>,
+    /// Packages the package is nested in, outermost first
+    pub parents: Vec<String>,
+    pub file: Option<PathBuf>,
+    pub line: Option<usize>,
+}
+
+impl From<&Package> for PackageJson {
+    fn from(pkg: &Package) -> Self {
+        PackageJson {
+            name: pkg.name.clone(),
+            repository: pkg.repository.clone(),
+            tags: pkg.tags.clone(),
+            implicit_tags: pkg
+                .resolved_tags()
+                .into_iter()
+                .filter(|tag| !pkg.tags.contains(tag))
+                .collect(),
+            category: pkg.category.as_ref().map(Category::full_path),
+            parents: pkg.parents.clone(),
+            file: pkg.source.as_ref().map(|source| source.file.clone()),
+            line: pkg.source.as_ref().map(|source| source.line),
+        }
+    }
+}
+
+pub fn packages_json(pkgs: &[Package]) -> Vec<PackageJson> {
+    pkgs.iter().map(PackageJson::from).collect()
+}
+
+#[derive(Serialize)]
+pub struct ExcludedJson {
+    pub package: PackageJson,
+    /// `blacklist`, `whitelist` or `parent`
+    pub rule: &'static str,
+    /// Blacklisted tag
+    pub tag: Option<String>,
+    /// Disabled package the package is nested in
+    pub parent: Option<String>,
+}
+
+impl From<&(Package, Exclusion)> for ExcludedJson {
+    fn from((pkg, exclusion): &(Package, Exclusion)) -> Self {
+        let (rule, tag, parent) = match exclusion {
+            Exclusion::Blacklisted(tag) => ("blacklist", Some(tag.clone()), None),
+            Exclusion::NotWhitelisted => ("whitelist", None, None),
+            Exclusion::ParentDisabled(parent) => ("parent", None, Some(parent.clone())),
+        };
+        ExcludedJson {
+            package: pkg.into(),
+            rule,
+            tag,
+            parent,
+        }
+    }
+}
+
+/// Result of comparing system and declaration, shared by `sync` and `generate`
+#[derive(Serialize)]
+pub struct DiffJson {
+    /// `in_sync` or `drift`
+    pub status: &'static str,
+    /// Explicitly installed packages missing from declaration
+    pub installed_only: Vec<PackageJson>,
+    /// Declared packages that are not installed
+    pub declared_only: Vec<PackageJson>,
+    pub excluded: Vec<ExcludedJson>,
+    /// Packages declared with `reason "dependency"` that are installed explicitly
+    pub explicit_deps: Vec<PackageJson>,
+    /// Declared packages installed as dependencies
+    pub installed_as_deps: Vec<PackageJson>,
+    /// Explicitly installed packages missing from declaration that declared packages depend on
+    pub required_undeclared: Vec<PackageJson>,
+    /// Dependencies nothing needs after sync, reported by `sync` only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orphans: Option<Vec<PackageJson>>,
+}
+
+#[derive(Serialize)]
+pub struct CategoryJson {
+    pub name: String,
+    pub path: String,
+    pub children: Vec<CategoryJson>,
+}
+
+/// Nests categories by their paths, children are sorted by name
+pub fn category_tree<'a>(categories: impl IntoIterator<Item = &'a Category>) -> Vec<CategoryJson> {
+    #[derive(Default)]
+    struct Node(BTreeMap<String, Node>);
+
+    fn build(node: Node, parent: &[String]) -> Vec<CategoryJson> {
+        node.0
+            .into_iter()
+            .map(|(name, children)| {
+                let mut path = parent.to_vec();
+                path.push(name.clone());
+                CategoryJson {
+                    children: build(children, &path),
+                    path: path.join("/"),
+                    name,
+                }
+            })
+            .collect()
+    }
+
+    let mut root = Node::default();
+    for category in categories {
+        let mut node = &mut root;
+        for part in category.path.iter().chain([&category.name]) {
+            node = node.0.entry(part.clone()).or_default();
+        }
+    }
+    build(root, &[])
+}
+
+/// Declared packages nested by categories and by packages they are declared in, printed by `tree`
+#[derive(Serialize)]
+pub struct TreeJson {
+    /// Packages outside of any category
+    pub packages: Vec<PackageTreeJson>,
+    pub categories: Vec<CategoryTreeJson>,
+}
+
+#[derive(Serialize)]
+pub struct CategoryTreeJson {
+    pub name: String,
+    pub path: String,
+    pub packages: Vec<PackageTreeJson>,
+    pub children: Vec<CategoryTreeJson>,
+}
+
+#[derive(Serialize)]
+pub struct PackageTreeJson {
+    #[serde(flatten)]
+    pub package: PackageJson,
+    /// Not disabled by whitelist/blacklist rules or by a disabled parent
+    pub enabled: bool,
+    pub subpackages: Vec<PackageTreeJson>,
+}
+
+/// Packages grouped by category and chain of parents
+type PackageGroups<'a> = HashMap<(Option<Category>, Vec<String>), Vec<&'a (Package, bool)>>;
+
+/// Nests packages (with whether they are enabled) under their categories and parents, every level is
+/// sorted by name. Packages whose parent is not among `pkgs` are not included.
+pub fn package_tree(pkgs: &[(Package, bool)]) -> TreeJson {
+    fn packages(
+        groups: &PackageGroups,
+        category: &Option<Category>,
+        parents: &[String],
+    ) -> Vec<PackageTreeJson> {
+        let mut entries = groups
+            .get(&(category.clone(), parents.to_vec()))
+            .cloned()
+            .unwrap_or_default();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+            .into_iter()
+            .map(|(pkg, enabled)| {
+                let mut path = parents.to_vec();
+                path.push(pkg.name.clone());
+                PackageTreeJson {
+                    package: pkg.into(),
+                    enabled: *enabled,
+                    subpackages: packages(groups, category, &path),
+                }
+            })
+            .collect()
+    }
+
+    fn categories(groups: &PackageGroups, tree: Vec<CategoryJson>) -> Vec<CategoryTreeJson> {
+        tree.into_iter()
+            .map(|category| CategoryTreeJson {
+                packages: packages(groups, &Some(category.path.as_str().into()), &[]),
+                children: categories(groups, category.children),
+                name: category.name,
+                path: category.path,
+            })
+            .collect()
+    }
+
+    let mut groups = PackageGroups::new();
+    for entry in pkgs {
+        let pkg = &entry.0;
+        groups
+            .entry((pkg.category.clone(), pkg.parents.clone()))
+            .or_default()
+            .push(entry);
+    }
+    let tree = category_tree(pkgs.iter().filter_map(|(pkg, _)| pkg.category.as_ref()));
+    TreeJson {
+        packages: packages(&groups, &None, &[]),
+        categories: categories(&groups, tree),
+    }
+}
+
+#[derive(Serialize)]
+pub struct SnapshotJson {
+    pub id: String,
+    pub date: String,
+    pub message: Option<String>,
+    pub files: Vec<PathBuf>,
+    /// Id of the snapshot the change reverted to
+    pub reverted: Option<String>,
+}
+
+impl From<&Snapshot> for SnapshotJson {
+    fn from(snapshot: &Snapshot) -> Self {
+        SnapshotJson {
+            id: snapshot.id.clone(),
+            date: snapshot.date.clone(),
+            message: snapshot.message.clone(),
+            files: snapshot
+                .files
+                .iter()
+                .map(|(file, _)| file.clone())
+                .collect(),
+            reverted: snapshot.reverted.clone(),
+        }
+    }
+}
+
+/// Pacman log event joined with declaration
+#[derive(Serialize)]
+pub struct HistoryJson<'a> {
+    #[serde(flatten)]
+    pub event: &'a LogEvent,
+    pub declared: bool,
+    /// Package is currently installed
+    pub installed: bool,
+    /// Package is currently installed explicitly
+    pub explicit: bool,
+    pub category: Option<String>,
+    /// Explicit and inherited tags of declared package
+    pub tags: Vec<String>,
+}
+
+/// Problem found by `check`
+#[derive(Serialize)]
+pub struct DiagnosticJson {
+    /// `error` or `warning`
+    pub severity: &'static str,
+    pub code: &'static str,
+    pub message: String,
+    pub file: Option<PathBuf>,
+    pub line: Option<usize>,
+    pub help: Option<String>,
+}
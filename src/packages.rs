@@ -1,13 +1,126 @@
 use std::fmt;
+use std::path::PathBuf;
 
 use kdl::{KdlNode, KdlValue};
 
-/// Type used to represent a package, its repo and tags if any. Note that tags are not considered when comparing packages, they are mostly metadata.
+use crate::list_pkgs::node_tags;
+
+/// Type used to represent a package, its repo and tags if any. Note that tags, options and category are not considered when comparing packages, they are mostly metadata.
 #[derive(Debug, Clone)]
 pub struct Package {
     pub name: String,
     pub repository: Option<String>,
     pub tags: Vec<String>,
+    pub options: PackageOptions,
+    /// Innermost category the package is declared in, including `@file` category
+    pub category: Option<Category>,
+    /// Explicit tags of categories and packages the package is declared in
+    pub inherited_tags: Vec<String>,
+    /// Names of packages the package is nested in as subpackage, outermost first
+    pub parents: Vec<String>,
+    /// Where the package is declared, `None` for packages not read from declaration files
+    pub source: Option<SourceLocation>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub file: PathBuf,
+    /// 1-based line of the package node
+    pub line: usize,
+}
+
+/// Options from `:options` child node of a package
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PackageOptions {
+    /// `reason "dependency"` installs package with `--asdeps`
+    pub as_dependency: bool,
+    /// `type "full"` also installs all optional dependencies
+    pub full: bool,
+    /// `needed #true` does not reinstall up to date package
+    pub needed: bool,
+    /// `provider "name"` installs `name` as dependency before the package, so it provides its virtual dependency
+    pub provider: Option<String>,
+    /// `ignore_upgrade #true` passes `--ignore` for this package to pacman transactions
+    pub ignore_upgrade: bool,
+}
+
+impl TryFrom<&KdlNode> for PackageOptions {
+    type Error = String;
+
+    fn try_from(node: &KdlNode) -> Result<Self, Self::Error> {
+        let mut options = PackageOptions::default();
+
+        for child in node.iter_children() {
+            let key = child.name().value();
+            let value = match child.entries() {
+                [entry] if entry.name().is_none() => entry.value(),
+                _ => return Err(format!("option '{key}' takes exactly one value")),
+            };
+            let as_string = || {
+                value
+                    .as_string()
+                    .ok_or_else(|| format!("option '{key}' must be a string"))
+            };
+            let as_bool = || {
+                value
+                    .as_bool()
+                    .ok_or_else(|| format!("option '{key}' must be #true or #false"))
+            };
+
+            match key {
+                "type" => {
+                    options.full = match as_string()? {
+                        "full" => true,
+                        "minimal" => false,
+                        other => return Err(format!("unknown package type '{other}'")),
+                    }
+                }
+                "reason" => {
+                    options.as_dependency = match as_string()? {
+                        "dependency" => true,
+                        "explicit" => false,
+                        other => return Err(format!("unknown install reason '{other}'")),
+                    }
+                }
+                "needed" => options.needed = as_bool()?,
+                "provider" => options.provider = Some(as_string()?.to_string()),
+                "ignore_upgrade" => options.ignore_upgrade = as_bool()?,
+                _ => return Err(format!("unknown option '{key}'")),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+impl From<&PackageOptions> for KdlNode {
+    fn from(options: &PackageOptions) -> Self {
+        let mut node = KdlNode::new(":options");
+        let children = node.ensure_children().nodes_mut();
+        let mut option = |key: &str, value: KdlValue| {
+            let mut child = KdlNode::new(key);
+            child.push(value);
+            children.push(child);
+        };
+
+        if options.full {
+            option("type", "full".into());
+        }
+        if options.as_dependency {
+            option("reason", "dependency".into());
+        }
+        if options.needed {
+            option("needed", true.into());
+        }
+        if let Some(provider) = &options.provider {
+            option("provider", provider.as_str().into());
+        }
+        if options.ignore_upgrade {
+            option("ignore_upgrade", true.into());
+        }
+
+        node
+    }
 }
 
 impl Package {
@@ -24,7 +137,71 @@ impl Package {
             name,
             repository,
             tags: Vec::new(),
+            options: PackageOptions::default(),
+            category: None,
+            inherited_tags: Vec::new(),
+            parents: Vec::new(),
+            source: None,
+        }
+    }
+}
+
+impl Package {
+    /// Explicit tags followed by tags inherited from categories the package is declared in (their names,
+    /// including `@file` category, and their explicit tags) and from packages it is nested in
+    pub fn resolved_tags(&self) -> Vec<String> {
+        let mut tags = self.tags.clone();
+        let category_names = self
+            .category
+            .iter()
+            .flat_map(|category| category.path.iter().chain([&category.name]));
+        for tag in category_names.chain(&self.inherited_tags) {
+            if !tags.contains(tag) {
+                tags.push(tag.clone());
+            }
         }
+        tags
+    }
+}
+
+/// Tag selector expression like `dev,!gui`: package has to have at least one of included tags (if any) and none
+/// of excluded ones
+#[derive(Debug, Clone, Default)]
+pub struct TagSelector {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl TagSelector {
+    pub fn new(tags: &[String], exclude_tags: &[String]) -> Self {
+        let mut selector = TagSelector::default();
+        for tag in tags
+            .iter()
+            .map(|tag| tag.trim())
+            .filter(|tag| !tag.is_empty())
+        {
+            match tag.strip_prefix('!') {
+                Some(tag) => selector.exclude.push(tag.to_string()),
+                None => selector.include.push(tag.to_string()),
+            }
+        }
+        selector.exclude.extend(
+            exclude_tags
+                .iter()
+                .map(|tag| tag.trim().to_string())
+                .filter(|tag| !tag.is_empty()),
+        );
+        selector
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn matches(&self, pkg: &Package) -> bool {
+        let tags = pkg.resolved_tags();
+        (self.include.is_empty() || self.include.iter().any(|tag| tags.contains(tag)))
+            && !self.exclude.iter().any(|tag| tags.contains(tag))
     }
 }
 
@@ -70,28 +247,32 @@ impl From<Package> for KdlNode {
         for tag in pkg.tags {
             node.push(KdlValue::String(tag));
         }
+        if pkg.options != PackageOptions::default() {
+            node.ensure_children()
+                .nodes_mut()
+                .push((&pkg.options).into());
+        }
 
         node
     }
 }
 
 impl TryFrom<KdlNode> for Package {
-    type Error = &'static str;
+    type Error = String;
 
     fn try_from(node: KdlNode) -> Result<Self, Self::Error> {
         let mut pkg = Package::from_str(node.name().value());
 
-        pkg.tags = node
-            .entries()
-            .iter()
-            .filter_map(|entry| {
-                if let KdlValue::String(tag) = entry.value() {
-                    Some(tag.clone())
-                } else {
-                    None
-                }
-            })
-            .collect();
+        pkg.tags = node_tags(&node);
+
+        if let Some(options) = node
+            .iter_children()
+            .find(|child| child.name().value() == ":options")
+        {
+            pkg.options = options
+                .try_into()
+                .map_err(|e| format!("invalid :options of '{}': {e}", pkg.name))?;
+        }
         Ok(pkg)
     }
 }
@@ -147,6 +328,43 @@ impl Category {
         Category { name, path }
     }
 
+    /// Category from full path components, `None` if path is empty
+    pub fn from_path(path: &[String]) -> Option<Self> {
+        let (name, path) = path.split_last()?;
+        Some(Category {
+            name: name.clone(),
+            path: path.to_vec(),
+        })
+    }
+
+    /// Whether category at full `path` is this category. Category without path matches any category with the
+    /// same name.
+    pub fn matches(&self, path: &[String]) -> bool {
+        match path.split_last() {
+            Some((name, parents)) => {
+                *name == self.name && (self.path.is_empty() || self.path == parents)
+            }
+            None => false,
+        }
+    }
+
+    /// Whether `other` is this category or its subcategory. Category without path matches categories with
+    /// the same name anywhere in the tree.
+    pub fn includes(&self, other: &Category) -> bool {
+        let other = other.components();
+        if self.path.is_empty() {
+            return other.contains(&self.name);
+        }
+        other.starts_with(&self.components())
+    }
+
+    /// Full path of the category, its name last
+    pub fn components(&self) -> Vec<String> {
+        let mut components = self.path.clone();
+        components.push(self.name.clone());
+        components
+    }
+
     pub fn full_path(&self) -> String {
         if self.path.is_empty() {
             self.name.clone()
@@ -173,3 +391,172 @@ impl From<&str> for Category {
         Self::from_str(s)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::Path;
+
+    use kdl::KdlDocument;
+
+    use super::*;
+    use crate::list_pkgs::collect_packages_from_doc;
+
+    const DECLARATION: &str = r#"
+cat:dev work {
+    rust
+    python lang {
+        uv
+    }
+    cat:gui {
+        zed editor
+    }
+}
+cat:games {
+    steam gui
+}
+"#;
+
+    fn declared() -> Vec<Package> {
+        let doc: KdlDocument = DECLARATION.parse().unwrap();
+        let mut packages = HashSet::new();
+        collect_packages_from_doc(&doc, Path::new("packages.kdl"), None, &mut packages).unwrap();
+        let mut packages: Vec<Package> = packages.into_iter().collect();
+        packages.sort();
+        packages
+    }
+
+    #[test]
+    fn tag_selector_matches_resolved_tags() {
+        // tags and exclude tags as they come from `--tags` and `--exclude-tags` split on commas
+        let cases: &[(&[&str], &[&str], &[&str])] = &[
+            (&[], &[], &["python", "rust", "steam", "uv", "zed"]),
+            // category name
+            (&["dev"], &[], &["python", "rust", "uv", "zed"]),
+            // explicit tag of category
+            (&["work"], &[], &["python", "rust", "uv", "zed"]),
+            // subcategory name or explicit tag of package
+            (&["gui"], &[], &["steam", "zed"]),
+            // tag of parent package
+            (&["lang"], &[], &["python", "uv"]),
+            (&["editor", "games"], &[], &["steam", "zed"]),
+            (&["!gui"], &[], &["python", "rust", "uv"]),
+            (&[], &["gui"], &["python", "rust", "uv"]),
+            (&["dev", "!gui"], &[], &["python", "rust", "uv"]),
+            (&["dev"], &["lang"], &["rust", "zed"]),
+            (&["!dev"], &["gui"], &[]),
+            // exclusion wins over inclusion
+            (&["games", "!games"], &[], &[]),
+            (&[" dev ", ""], &[" "], &["python", "rust", "uv", "zed"]),
+            (&["unknown"], &[], &[]),
+        ];
+
+        let packages = declared();
+        for (tags, exclude_tags, expected) in cases {
+            let to_strings =
+                |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect::<Vec<_>>();
+            let selector = TagSelector::new(&to_strings(tags), &to_strings(exclude_tags));
+            let matched: Vec<&str> = packages
+                .iter()
+                .filter(|pkg| selector.matches(pkg))
+                .map(|pkg| pkg.name.as_str())
+                .collect();
+            assert_eq!(
+                &matched, expected,
+                "--tags {tags:?} --exclude-tags {exclude_tags:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn empty_selector_is_empty() {
+        assert!(TagSelector::new(&[" ".to_string()], &[String::new()]).is_empty());
+        assert!(!TagSelector::new(&["!gui".to_string()], &[]).is_empty());
+    }
+
+    #[test]
+    fn category_matching() {
+        let path = |path: &str| Category::from_str(path).components();
+        let cases = [
+            ("dev", "dev", true, true),
+            ("dev", "work/dev", true, true),
+            ("dev", "dev/rust", false, true),
+            ("dev", "work/dev/rust", false, true),
+            ("dev", "devel", false, false),
+            ("work/dev", "dev", false, false),
+            ("work/dev", "work/dev", true, true),
+            ("work/dev", "work/dev/rust", false, true),
+            ("work/dev", "home/work/dev", false, false),
+            ("dev/rust", "dev", false, false),
+        ];
+        for (category, other, matches, includes) in cases {
+            let category = Category::from_str(category);
+            assert_eq!(
+                category.matches(&path(other)),
+                matches,
+                "{category} matches {other}"
+            );
+            assert_eq!(
+                category.includes(&Category::from_str(other)),
+                includes,
+                "{category} includes {other}"
+            );
+        }
+        assert!(!Category::from_str("dev").matches(&[]));
+    }
+
+    fn options(src: &str) -> Result<PackageOptions, String> {
+        let doc: KdlDocument = format!(":options {{\n{src}\n}}").parse().unwrap();
+        PackageOptions::try_from(&doc.nodes()[0])
+    }
+
+    #[test]
+    fn options_are_parsed() {
+        assert_eq!(options("").unwrap(), PackageOptions::default());
+        assert_eq!(
+            options(
+                "type \"full\"\nreason \"dependency\"\nneeded #true\nprovider \"jack2\"\nignore_upgrade #true"
+            )
+            .unwrap(),
+            PackageOptions {
+                full: true,
+                as_dependency: true,
+                needed: true,
+                provider: Some("jack2".to_string()),
+                ignore_upgrade: true,
+            }
+        );
+        assert_eq!(
+            options("type \"minimal\"\nreason \"explicit\"\nneeded #false").unwrap(),
+            PackageOptions::default()
+        );
+    }
+
+    #[test]
+    fn invalid_options_are_errors() {
+        let cases = [
+            ("type \"huge\"", "unknown package type 'huge'"),
+            ("reason \"maybe\"", "unknown install reason 'maybe'"),
+            ("type #true", "option 'type' must be a string"),
+            ("provider 1", "option 'provider' must be a string"),
+            ("needed \"yes\"", "option 'needed' must be #true or #false"),
+            (
+                "ignore_upgrade 1",
+                "option 'ignore_upgrade' must be #true or #false",
+            ),
+            ("needed", "option 'needed' takes exactly one value"),
+            (
+                "needed #true #false",
+                "option 'needed' takes exactly one value",
+            ),
+            (
+                "needed value=#true",
+                "option 'needed' takes exactly one value",
+            ),
+            ("colour \"blue\"", "unknown option 'colour'"),
+        ];
+        for (src, error) in cases {
+            assert_eq!(options(src).unwrap_err(), error, "{src}");
+        }
+    }
+}
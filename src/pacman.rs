@@ -1,57 +1,454 @@
 use anyhow::Result;
-use std::process::{Command, ExitStatus};
+use colored::*;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::OnceLock;
 
+use crate::alpm_db::{DbPackage, dep_name, read_local_db, read_sync_dbs};
+use crate::backend::{Backend, InstallOptions};
 use crate::config::Config;
+use crate::output::coded;
 
-pub fn run_pacman(cfg: &Config, args: &[&str]) -> Result<String> {
-    if cfg.verbose {
-        println!("Executing: {} {}", cfg.package_manager, args.join(" "));
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flavor {
+    /// Needs sudo for transactions
+    Pacman,
+    Paru,
+    /// Does not support `--asexplicit`
+    Yay,
+    /// Any other program with pacman compatible interface, run without sudo
+    Other,
+}
+
+/// Backend running pacman or an AUR helper with pacman compatible interface. Queries read pacman databases
+/// in `db_path` directly when they exist and fall back to running the program otherwise.
+pub struct PacmanBackend {
+    program: String,
+    flavor: Flavor,
+    dry_run: bool,
+    verbose: bool,
+    db_path: PathBuf,
+    local_db: OnceLock<Option<Vec<DbPackage>>>,
+    sync_dbs: OnceLock<Option<Vec<DbPackage>>>,
+}
+
+impl PacmanBackend {
+    pub fn new(cfg: &Config) -> Self {
+        let program = cfg.package_manager.clone();
+        let flavor = match program.as_str() {
+            "pacman" => Flavor::Pacman,
+            "paru" => Flavor::Paru,
+            "yay" => Flavor::Yay,
+            _ => Flavor::Other,
+        };
+        PacmanBackend {
+            program,
+            flavor,
+            dry_run: cfg.dry_run,
+            verbose: cfg.verbose,
+            db_path: cfg.db_path.clone(),
+            local_db: OnceLock::new(),
+            sync_dbs: OnceLock::new(),
+        }
     }
 
-    let output = Command::new(&cfg.package_manager).args(args).output()?;
-    if output.status.code() != Some(0) {
-        return Err(anyhow::anyhow!(
-            "pacman command failed with code {:?}: {}",
-            output.status.code(),
-            String::from_utf8_lossy(&output.stderr)
-        ));
+    /// Local database, `None` if it does not exist or can not be read
+    fn local_db(&self) -> Option<&[DbPackage]> {
+        self.local_db
+            .get_or_init(|| {
+                if !self.db_path.join("local").is_dir() {
+                    return None;
+                }
+                self.read_db(read_local_db(&self.db_path))
+            })
+            .as_deref()
+    }
+
+    /// Packages of all sync databases, `None` if there are none or they can not be read
+    fn sync_dbs(&self) -> Option<&[DbPackage]> {
+        self.sync_dbs
+            .get_or_init(|| {
+                if !self.db_path.join("sync").is_dir() {
+                    return None;
+                }
+                self.read_db(read_sync_dbs(&self.db_path))
+                    .filter(|packages| !packages.is_empty())
+            })
+            .as_deref()
+    }
+
+    fn read_db(&self, result: Result<Vec<DbPackage>>) -> Option<Vec<DbPackage>> {
+        match result {
+            Ok(packages) => Some(packages),
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    format!("Warning: {e:#}, falling back to {}", self.program).yellow()
+                );
+                None
+            }
+        }
     }
-    let packages = String::from_utf8(output.stdout)?;
-    Ok(packages)
-}
 
-pub fn sudo_pacman(cfg: &Config, args: &[&str], pkgs: &[String]) -> Result<ExitStatus> {
-    let cmd_str = if cfg.package_manager == "pacman" {
-        format!("sudo pacman {} {}", args.join(" "), pkgs.join(" "))
-    } else {
-        format!(
-            "{} {} {}",
-            cfg.package_manager,
+    fn run(&self, args: &[&str]) -> Result<String> {
+        if self.verbose {
+            println!("Executing: {} {}", self.program, args.join(" "));
+        }
+
+        let output = Command::new(&self.program)
+            .args(args)
+            .env("LC_ALL", "C")
+            .output()?;
+        if output.status.code() != Some(0) {
+            return Err(anyhow::anyhow!(
+                "pacman command failed with code {:?}: {}",
+                output.status.code(),
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+        let packages = String::from_utf8(output.stdout)?;
+        Ok(packages)
+    }
+
+    fn run_lines(&self, args: &[&str]) -> Result<Vec<String>> {
+        Ok(self.run(args)?.lines().map(str::to_string).collect())
+    }
+
+    /// Runs a transaction changing system, only prints it in dry run
+    fn transaction(&self, args: &[&str], pkgs: &[String]) -> Result<()> {
+        let sudo = self.flavor == Flavor::Pacman;
+        let cmd_str = format!(
+            "{}{} {} {}",
+            if sudo { "sudo " } else { "" },
+            self.program,
             args.join(" "),
             pkgs.join(" ")
-        )
-    };
+        );
 
-    if cfg.dry_run {
-        println!("Dry run: would execute '{}'", cmd_str);
-        Ok(ExitStatus::default())
-    } else {
-        if cfg.verbose {
+        if self.dry_run {
+            println!("Dry run: would execute '{}'", cmd_str);
+            return Ok(());
+        }
+        if self.verbose {
             println!("Executing: {}", cmd_str);
         }
 
-        let mut cmd = if cfg.package_manager == "pacman" {
-            let mut cmd = std::process::Command::new("sudo");
-            cmd.arg("pacman");
+        let mut cmd = if sudo {
+            let mut cmd = Command::new("sudo");
+            cmd.arg(&self.program);
             cmd
         } else {
-            std::process::Command::new(&cfg.package_manager)
+            Command::new(&self.program)
         };
 
-        cmd.args(args).args(pkgs).status().map_err(Into::into)
+        let status = cmd.args(args).args(pkgs).status()?;
+        if !status.success() {
+            return Err(coded(
+                "transaction_failed",
+                format!("{} {} failed ({status})", self.program, args.join(" ")),
+            ));
+        }
+        Ok(())
     }
 }
 
-pub fn check_pkg_exists(cfg: &Config, pkg: &str) -> bool {
-    run_pacman(cfg, &["-Si", pkg]).is_ok()
+impl Backend for PacmanBackend {
+    fn name(&self) -> &str {
+        &self.program
+    }
+
+    fn query_local(&self) -> Result<Vec<DbPackage>> {
+        match self.local_db() {
+            Some(packages) => Ok(packages.to_vec()),
+            None => Ok(parse_info(&self.run(&["-Qi"])?)),
+        }
+    }
+
+    fn query_explicit(&self) -> Result<Vec<String>> {
+        match self.local_db() {
+            Some(packages) => Ok(names(packages.iter().filter(|pkg| pkg.explicit))),
+            None => self.run_lines(&["-Qqe"]),
+        }
+    }
+
+    fn query_installed(&self) -> Result<Vec<String>> {
+        match self.local_db() {
+            Some(packages) => Ok(names(packages.iter())),
+            None => self.run_lines(&["-Qq"]),
+        }
+    }
+
+    fn query_dependencies(&self) -> Result<Vec<String>> {
+        match self.local_db() {
+            Some(packages) => Ok(names(packages.iter().filter(|pkg| !pkg.explicit))),
+            None => self.run_lines(&["-Qqd"]),
+        }
+    }
+
+    fn list_sync(&self) -> Result<Vec<String>> {
+        if let Some(packages) = self.sync_dbs() {
+            return Ok(packages
+                .iter()
+                .map(|pkg| {
+                    format!(
+                        "{}/{}",
+                        pkg.repository.as_deref().unwrap_or_default(),
+                        pkg.name
+                    )
+                })
+                .collect());
+        }
+        Ok(self
+            .run(&["-Sl", "--color", "never"])?
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                Some(format!("{}/{}", fields.next()?, fields.next()?))
+            })
+            .collect())
+    }
+
+    fn sync_info(&self, pkg: &str) -> Result<Option<DbPackage>> {
+        if let Some(packages) = self.sync_dbs() {
+            let (repo, name) = match pkg.rsplit_once('/') {
+                Some((repo, name)) => (Some(repo), name),
+                None => (None, pkg),
+            };
+            let in_repo = |db_pkg: &&DbPackage| {
+                repo.is_none_or(|repo| db_pkg.repository.as_deref() == Some(repo))
+            };
+            // exact name wins over packages providing it
+            let found = packages
+                .iter()
+                .filter(in_repo)
+                .find(|db_pkg| db_pkg.name == name)
+                .or_else(|| {
+                    packages
+                        .iter()
+                        .filter(in_repo)
+                        .find(|db_pkg| db_pkg.satisfies(name))
+                });
+            if let Some(db_pkg) = found {
+                return Ok(Some(db_pkg.clone()));
+            }
+            // only AUR helpers know about packages outside of sync databases
+            if self.flavor == Flavor::Pacman {
+                return Ok(None);
+            }
+        }
+        match self.run(&["-Si", pkg]) {
+            Ok(output) => Ok(parse_info(&output).into_iter().next()),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn install(&self, pkgs: &[String], options: &InstallOptions) -> Result<()> {
+        let mut args = vec!["-S"];
+        if options.as_dependency {
+            args.push("--asdeps");
+        } else if self.flavor != Flavor::Yay {
+            args.push("--asexplicit");
+        }
+        if options.needed {
+            args.push("--needed");
+        }
+        let ignored = options.ignore.join(",");
+        if !ignored.is_empty() {
+            args.extend(["--ignore", &ignored]);
+        }
+        self.transaction(&args, pkgs)
+    }
+
+    fn remove(&self, pkgs: &[String]) -> Result<()> {
+        self.transaction(&["-Rns"], pkgs)
+    }
+
+    fn mark_as_deps(&self, pkgs: &[String]) -> Result<()> {
+        self.transaction(&["-D", "--asdeps"], pkgs)
+    }
+
+    fn mark_as_explicit(&self, pkgs: &[String]) -> Result<()> {
+        self.transaction(&["-D", "--asexplicit"], pkgs)
+    }
+
+    fn package_info(&self, pkg: &str) -> Result<String> {
+        self.run(&["-Qi", pkg])
+    }
+
+    fn preview_command(&self, sync: bool) -> String {
+        if sync {
+            format!("{0} -Qi {{}} || {0} -Sii {{}}", self.program)
+        } else {
+            format!("{} -Qi {{}}", self.program)
+        }
+    }
+}
+
+fn names<'a>(packages: impl Iterator<Item = &'a DbPackage>) -> Vec<String> {
+    packages.map(|pkg| pkg.name.clone()).collect()
+}
+
+/// Parses `-Qi`/`-Si` output, packages are separated by empty lines. Install date is not parsed, it is
+/// formatted according to locale.
+fn parse_info(output: &str) -> Vec<DbPackage> {
+    let mut packages = Vec::new();
+    let mut pkg = DbPackage {
+        explicit: true,
+        ..Default::default()
+    };
+    let mut key = String::new();
+
+    for line in output.lines() {
+        if line.trim().is_empty() {
+            if !pkg.name.is_empty() {
+                packages.push(std::mem::replace(
+                    &mut pkg,
+                    DbPackage {
+                        explicit: true,
+                        ..Default::default()
+                    },
+                ));
+            }
+            continue;
+        }
+        // continuation lines of a value are indented
+        let value = match line.split_once(" : ") {
+            Some((k, value)) if !k.starts_with(' ') => {
+                key = k.trim().to_string();
+                value.trim()
+            }
+            _ => line.trim(),
+        };
+        let list = || {
+            value
+                .split_whitespace()
+                .filter(|item| *item != "None")
+                .map(str::to_string)
+        };
+        match key.as_str() {
+            "Name" => pkg.name = value.to_string(),
+            "Version" => pkg.version = value.to_string(),
+            "Repository" => pkg.repository = Some(value.to_string()),
+            "Install Reason" => pkg.explicit = value.starts_with("Explicitly"),
+            "Depends On" => pkg.depends.extend(list()),
+            "Provides" => pkg.provides.extend(list()),
+            // "name: description" or "name>=version: description", "None" if there are none
+            "Optional Deps" => {
+                let name = dep_name(value);
+                if !name.is_empty() && name != "None" {
+                    pkg.optional_deps.push(name.to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    if !pkg.name.is_empty() {
+        packages.push(pkg);
+    }
+
+    packages
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// `-Qi` output of pacman with `LC_ALL=C`
+    const QUERY_INFO: &str = "Name            : python-foo
+Version         : 1.2.3-1
+Description     : Foo library
+Provides        : foo=1.2.3
+Depends On      : python>=3.12  glibc
+Optional Deps   : python-bar: bar support
+                  libx [installed]
+Install Reason  : Installed as a dependency for another package
+
+Name            : bar
+Version         : 2.0-1
+Provides        : None
+Depends On      : None
+Optional Deps   : None
+Install Reason  : Explicitly installed
+
+";
+
+    /// Backend running a script that answers queries instead of pacman, `db_path` is `db`
+    fn backend(dir: &TempDir, db: &Path) -> PacmanBackend {
+        let program = dir.path().join("fake-pacman");
+        fs::write(
+            &program,
+            format!(
+                "#!/bin/sh\ncase \"$1\" in\n-Qi) cat <<'EOF'\n{QUERY_INFO}EOF\n;;\n-Qqe) echo bar ;;\n-Sl) echo 'core glibc 2.40-1' ;;\n*) exit 1 ;;\nesac\n"
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+        PacmanBackend::new(&Config {
+            package_manager: program.to_string_lossy().to_string(),
+            db_path: db.to_path_buf(),
+            verbose: false,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn info_output_is_parsed() {
+        let packages = parse_info(QUERY_INFO);
+
+        assert_eq!(packages.len(), 2);
+        let foo = &packages[0];
+        assert_eq!(foo.name, "python-foo");
+        assert_eq!(foo.version, "1.2.3-1");
+        assert_eq!(foo.provides, ["foo=1.2.3"]);
+        assert_eq!(foo.depends, ["python>=3.12", "glibc"]);
+        assert_eq!(foo.optional_deps, ["python-bar", "libx"]);
+        assert!(!foo.explicit);
+        let bar = &packages[1];
+        assert!(bar.explicit);
+        assert!(bar.provides.is_empty() && bar.depends.is_empty() && bar.optional_deps.is_empty());
+    }
+
+    #[test]
+    fn missing_databases_fall_back_to_program() {
+        let dir = TempDir::new().unwrap();
+        let backend = backend(&dir, &dir.path().join("missing"));
+
+        assert_eq!(backend.query_explicit().unwrap(), ["bar"]);
+        let local = backend.query_local().unwrap();
+        assert_eq!(names(local.iter()), ["python-foo", "bar"]);
+        assert_eq!(backend.list_sync().unwrap(), ["core/glibc"]);
+    }
+
+    #[test]
+    fn unreadable_databases_fall_back_to_program() {
+        let dir = TempDir::new().unwrap();
+        let db = dir.path().join("db");
+        // local database directory that is a file and truncated sync database
+        fs::create_dir_all(db.join("sync")).unwrap();
+        fs::write(db.join("local"), "").unwrap();
+        fs::write(db.join("sync/core.db"), [0x1f, 0x8b, 0x08]).unwrap();
+        let backend = backend(&dir, &db);
+
+        assert_eq!(backend.query_explicit().unwrap(), ["bar"]);
+        assert_eq!(backend.list_sync().unwrap(), ["core/glibc"]);
+    }
+
+    #[test]
+    fn databases_are_read_instead_of_running_program() {
+        let dir = TempDir::new().unwrap();
+        let db = dir.path().join("db");
+        let desc = db.join("local/foo-1.0-1/desc");
+        fs::create_dir_all(desc.parent().unwrap()).unwrap();
+        fs::write(&desc, "%NAME%\nfoo\n\n%VERSION%\n1.0-1\n\n%REASON%\n0\n").unwrap();
+        let backend = backend(&dir, &db);
+
+        assert_eq!(backend.query_explicit().unwrap(), ["foo"]);
+        assert!(backend.query_dependencies().unwrap().is_empty());
+    }
 }
@@ -0,0 +1,442 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, TimeZone};
+use serde::Serialize;
+
+use crate::alpm_db::decompress;
+
+/// Package change or transaction boundary logged by ALPM
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum LogAction {
+    Installed {
+        package: String,
+        version: String,
+    },
+    Removed {
+        package: String,
+        version: String,
+    },
+    Upgraded {
+        package: String,
+        from: String,
+        to: String,
+    },
+    Downgraded {
+        package: String,
+        from: String,
+        to: String,
+    },
+    Reinstalled {
+        package: String,
+        version: String,
+    },
+    TransactionStarted,
+    TransactionCompleted,
+    /// Transaction was interrupted or failed
+    TransactionFailed,
+}
+
+impl LogAction {
+    /// Changed package, `None` for transaction boundaries
+    pub fn package(&self) -> Option<&str> {
+        match self {
+            LogAction::Installed { package, .. }
+            | LogAction::Removed { package, .. }
+            | LogAction::Upgraded { package, .. }
+            | LogAction::Downgraded { package, .. }
+            | LogAction::Reinstalled { package, .. } => Some(package),
+            LogAction::TransactionStarted
+            | LogAction::TransactionCompleted
+            | LogAction::TransactionFailed => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LogEvent {
+    pub timestamp: DateTime<FixedOffset>,
+    #[serde(flatten)]
+    pub action: LogAction,
+}
+
+/// Reads events of `path` and its rotated copies (`pacman.log.1`, `pacman.log.2.gz`,
+/// `pacman.log-20240101.zst`, ...), oldest first. Lines which are not ALPM events are skipped.
+pub fn read_log(path: &Path) -> Result<Vec<LogEvent>> {
+    let mut events = Vec::new();
+    for file in rotated_logs(path).into_iter().chain([path.to_path_buf()]) {
+        let mut reader = BufReader::new(
+            decompress(&file)
+                .with_context(|| format!("failed to open pacman log {}", file.display()))?,
+        );
+        // scriptlet output logged by pacman is not guaranteed to be valid UTF-8
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let read = reader
+                .read_until(b'\n', &mut line)
+                .with_context(|| format!("failed to read pacman log {}", file.display()))?;
+            if read == 0 {
+                break;
+            }
+            if let Some(event) = parse_line(&String::from_utf8_lossy(&line)) {
+                events.push(event);
+            }
+        }
+    }
+    Ok(events)
+}
+
+/// Index of the first `installed` event of every package, useful to order packages by installation
+pub fn install_order(events: &[LogEvent]) -> HashMap<&str, usize> {
+    let mut order = HashMap::new();
+    for event in events {
+        if let LogAction::Installed { package, .. } = &event.action {
+            let next = order.len();
+            order.entry(package.as_str()).or_insert(next);
+        }
+    }
+    order
+}
+
+/// Rotated logs next to `path` ordered from the oldest. Both numbered (`.1` is the newest) and dated
+/// (logrotate `dateext`) names are recognized, optionally with `.gz` or `.zst` extension.
+fn rotated_logs(path: &Path) -> Vec<PathBuf> {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return Vec::new();
+    };
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let name = name.to_string_lossy();
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut numbered = Vec::new();
+    let mut dated = Vec::new();
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let Some(suffix) = file_name.strip_prefix(name.as_ref()) else {
+            continue;
+        };
+        let suffix = suffix
+            .strip_suffix(".gz")
+            .or_else(|| suffix.strip_suffix(".zst"))
+            .unwrap_or(suffix);
+        let is_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+        if let Some(number) = suffix.strip_prefix('.').filter(|n| is_digits(n)) {
+            if let Ok(number) = number.parse::<u32>() {
+                numbered.push((number, entry.path()));
+            }
+        } else if let Some(date) = suffix.strip_prefix('-').filter(|date| is_digits(date)) {
+            dated.push((date.to_string(), entry.path()));
+        }
+    }
+    numbered.sort_by_key(|(number, _)| std::cmp::Reverse(*number));
+    dated.sort();
+
+    dated
+        .into_iter()
+        .map(|(_, path)| path)
+        .chain(numbered.into_iter().map(|(_, path)| path))
+        .collect()
+}
+
+/// Parses `[2024-01-15T10:23:45+0100] [ALPM] upgraded foo (1.0-1 -> 1.1-1)`. Logs written by old pacman
+/// versions use `[2019-01-15 10:23]` local time and may lack the `[ALPM]` tag.
+pub fn parse_line(line: &str) -> Option<LogEvent> {
+    let (timestamp, message) = line.trim_end().strip_prefix('[')?.split_once("] ")?;
+    let timestamp = parse_timestamp(timestamp)?;
+    let message = match message.strip_prefix("[ALPM] ") {
+        Some(message) => message,
+        // other tags (`[PACMAN]`, `[ALPM-SCRIPTLET]`, AUR helpers) are not package changes
+        None if message.starts_with('[') => return None,
+        None => message,
+    };
+    Some(LogEvent {
+        timestamp,
+        action: parse_action(message)?,
+    })
+}
+
+fn parse_timestamp(timestamp: &str) -> Option<DateTime<FixedOffset>> {
+    if let Ok(time) = DateTime::parse_from_str(timestamp, "%Y-%m-%dT%H:%M:%S%z") {
+        return Some(time);
+    }
+    let naive = NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M").ok()?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|time| time.fixed_offset())
+}
+
+fn parse_action(message: &str) -> Option<LogAction> {
+    match message {
+        "transaction started" => return Some(LogAction::TransactionStarted),
+        "transaction completed" => return Some(LogAction::TransactionCompleted),
+        "transaction interrupted" | "transaction failed" => {
+            return Some(LogAction::TransactionFailed);
+        }
+        _ => {}
+    }
+
+    let (verb, rest) = message.split_once(' ')?;
+    let (package, versions) = rest.split_once(" (")?;
+    let versions = versions.strip_suffix(')')?;
+    let package = package.to_string();
+    let version = || versions.to_string();
+    let from_to = || {
+        versions
+            .split_once(" -> ")
+            .map(|(from, to)| (from.to_string(), to.to_string()))
+    };
+
+    Some(match verb {
+        "installed" => LogAction::Installed {
+            package,
+            version: version(),
+        },
+        "removed" => LogAction::Removed {
+            package,
+            version: version(),
+        },
+        "reinstalled" => LogAction::Reinstalled {
+            package,
+            version: version(),
+        },
+        "upgraded" => {
+            let (from, to) = from_to()?;
+            LogAction::Upgraded { package, from, to }
+        }
+        "downgraded" => {
+            let (from, to) = from_to()?;
+            LogAction::Downgraded { package, from, to }
+        }
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use ruzstd::encoding::{CompressionLevel, compress_to_vec};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn installed(package: &str, version: &str) -> LogAction {
+        LogAction::Installed {
+            package: package.to_string(),
+            version: version.to_string(),
+        }
+    }
+
+    fn packages(events: &[LogEvent]) -> Vec<&str> {
+        events
+            .iter()
+            .filter_map(|event| event.action.package())
+            .collect()
+    }
+
+    fn gzip(data: &str) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zstd(data: &str) -> Vec<u8> {
+        compress_to_vec(data.as_bytes(), CompressionLevel::Fastest)
+    }
+
+    fn line(package: &str) -> String {
+        format!("[2024-01-15T10:23:45+0100] [ALPM] installed {package} (1.0-1)\n")
+    }
+
+    #[test]
+    fn actions_are_parsed() {
+        let cases = [
+            ("installed foo (1.0-1)", installed("foo", "1.0-1")),
+            (
+                "removed foo (1.0-1)",
+                LogAction::Removed {
+                    package: "foo".to_string(),
+                    version: "1.0-1".to_string(),
+                },
+            ),
+            (
+                "reinstalled foo (1.0-1)",
+                LogAction::Reinstalled {
+                    package: "foo".to_string(),
+                    version: "1.0-1".to_string(),
+                },
+            ),
+            (
+                "upgraded foo (1.0-1 -> 1:1.1-1)",
+                LogAction::Upgraded {
+                    package: "foo".to_string(),
+                    from: "1.0-1".to_string(),
+                    to: "1:1.1-1".to_string(),
+                },
+            ),
+            (
+                "downgraded foo (1.1-1 -> 1.0-1)",
+                LogAction::Downgraded {
+                    package: "foo".to_string(),
+                    from: "1.1-1".to_string(),
+                    to: "1.0-1".to_string(),
+                },
+            ),
+            ("transaction started", LogAction::TransactionStarted),
+            ("transaction completed", LogAction::TransactionCompleted),
+            ("transaction interrupted", LogAction::TransactionFailed),
+            ("transaction failed", LogAction::TransactionFailed),
+        ];
+        for (message, action) in cases {
+            let line = format!("[2024-01-15T10:23:45+0100] [ALPM] {message}\n");
+            assert_eq!(
+                parse_line(&line).map(|event| event.action),
+                Some(action),
+                "{line}"
+            );
+        }
+    }
+
+    #[test]
+    fn other_lines_are_skipped() {
+        for line in [
+            "",
+            "not a log line",
+            "[2024-01-15T10:23:45+0100] [PACMAN] Running 'pacman -Syu'",
+            "[2024-01-15T10:23:45+0100] [ALPM-SCRIPTLET] installed foo (1.0-1)",
+            "[2024-01-15T10:23:45+0100] [ALPM] running 'systemd-sysusers.hook'...",
+            "[2024-01-15T10:23:45+0100] [ALPM] upgraded foo (1.0-1)",
+            "[2024-01-15T10:23:45+0100] [ALPM] installed foo",
+            "[yesterday] [ALPM] installed foo (1.0-1)",
+            "[2024-01-15T10:23:45+0100]",
+        ] {
+            assert_eq!(parse_line(line), None, "{line}");
+        }
+    }
+
+    #[test]
+    fn iso_and_old_timestamps_are_parsed() {
+        let event = parse_line("[2024-01-15T10:23:45+0100] [ALPM] installed foo (1.0-1)").unwrap();
+        assert_eq!(event.timestamp.to_rfc3339(), "2024-01-15T10:23:45+01:00");
+
+        // old format is local time without seconds and without `[ALPM]` tag
+        let event = parse_line("[2019-01-15 10:23] installed foo (1.0-1)").unwrap();
+        let expected = Local
+            .with_ymd_and_hms(2019, 1, 15, 10, 23, 0)
+            .earliest()
+            .unwrap();
+        assert_eq!(event.timestamp, expected);
+        assert_eq!(event.action, installed("foo", "1.0-1"));
+
+        let event = parse_line("[2019-01-15 10:23] [ALPM] installed foo (1.0-1)").unwrap();
+        assert_eq!(event.action, installed("foo", "1.0-1"));
+        assert_eq!(
+            parse_line("[2019-01-15 10:23] [PACMAN] synchronizing"),
+            None
+        );
+    }
+
+    #[test]
+    fn rotated_logs_are_read_oldest_first() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("pacman.log");
+        fs::write(&path, line("current")).unwrap();
+        fs::write(dir.path().join("pacman.log.1"), line("numbered-1")).unwrap();
+        fs::write(
+            dir.path().join("pacman.log.2.gz"),
+            gzip(&line("numbered-2")),
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("pacman.log.10.zst"),
+            zstd(&line("numbered-10")),
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("pacman.log-20240101.zst"),
+            zstd(&line("dated-20240101")),
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("pacman.log-20231201"),
+            line("dated-20231201"),
+        )
+        .unwrap();
+        // not rotated copies of the log
+        fs::write(dir.path().join("pacman.log.bak"), line("backup")).unwrap();
+        fs::write(dir.path().join("pacman.log.old.gz"), gzip(&line("old"))).unwrap();
+        fs::write(dir.path().join("other.log.1"), line("other")).unwrap();
+
+        let events = read_log(&path).unwrap();
+
+        assert_eq!(
+            packages(&events),
+            [
+                "dated-20231201",
+                "dated-20240101",
+                "numbered-10",
+                "numbered-2",
+                "numbered-1",
+                "current"
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_utf8_lines_do_not_stop_reading() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("pacman.log");
+        let mut content = line("before").into_bytes();
+        content.extend(b"[2024-01-15T10:23:45+0100] [ALPM-SCRIPTLET] \xff\xfe\n");
+        content.extend(line("after").as_bytes());
+        fs::write(&path, content).unwrap();
+
+        assert_eq!(packages(&read_log(&path).unwrap()), ["before", "after"]);
+    }
+
+    #[test]
+    fn corrupt_rotated_log_is_an_error() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("pacman.log");
+        fs::write(&path, line("current")).unwrap();
+        let mut truncated = gzip(&line("rotated").repeat(100));
+        truncated.truncate(truncated.len() / 2);
+        fs::write(dir.path().join("pacman.log.1.gz"), truncated).unwrap();
+
+        let err = read_log(&path).unwrap_err();
+
+        assert!(format!("{err:#}").contains("pacman.log.1.gz"), "{err:#}");
+    }
+
+    #[test]
+    fn first_installation_orders_packages() {
+        let log = [
+            "[2024-01-01T00:00:00+0000] [ALPM] installed b (1.0-1)",
+            "[2024-01-02T00:00:00+0000] [ALPM] installed a (1.0-1)",
+            "[2024-01-03T00:00:00+0000] [ALPM] removed b (1.0-1)",
+            "[2024-01-04T00:00:00+0000] [ALPM] installed b (1.0-1)",
+            "[2024-01-05T00:00:00+0000] [ALPM] upgraded c (1.0-1 -> 2.0-1)",
+        ];
+        let events: Vec<LogEvent> = log.iter().filter_map(|line| parse_line(line)).collect();
+
+        let order = install_order(&events);
+
+        assert_eq!(order.get("b"), Some(&0));
+        assert_eq!(order.get("a"), Some(&1));
+        assert_eq!(order.get("c"), None);
+    }
+}
@@ -1,14 +1,139 @@
 use anyhow::Result;
 use duct::cmd;
-use inquire::Select;
+use inquire::{Confirm, Select, Text};
 use kdl::KdlNode;
 use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, IsTerminal};
 
 use crate::{
     app::App,
-    packages::{Category, Package},
+    kdl_edit::{CategoryOccurrence, is_reserved_category},
+    list_pkgs::{file_category, get_declared_pkg_list, get_exp_pkg_list},
+    output::coded,
+    packages::{Category, Package, TagSelector},
 };
 
+/// Whether the user can be asked, tests never prompt and take the non-interactive path
+pub fn can_prompt() -> bool {
+    !cfg!(test) && io::stdin().is_terminal()
+}
+
+/// Entry of category picker
+enum CategoryChoice {
+    Existing(Category),
+    /// Create given category or ask for path of the new one
+    New(Option<Category>),
+}
+
+impl fmt::Display for CategoryChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CategoryChoice::Existing(category) => write!(f, "{category}"),
+            CategoryChoice::New(Some(category)) => write!(f, "+ create '{category}'"),
+            CategoryChoice::New(None) => write!(f, "+ create new category"),
+        }
+    }
+}
+
+/// Decision about undeclared package made in `generate --triage`
+pub enum TriageAction {
+    /// Add to category chosen for the previous package
+    AddTo(Category),
+    /// Add to category chosen from picker, with tags
+    Add,
+    Ignore,
+    MarkAsDependency,
+    Uninstall,
+    /// Choose several packages the next action applies to
+    Batch,
+    /// Leave package undeclared until next triage
+    Skip,
+    Quit,
+}
+
+impl fmt::Display for TriageAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TriageAction::AddTo(category) => write!(f, "add to '{category}'"),
+            TriageAction::Add => write!(f, "add to category..."),
+            TriageAction::Ignore => write!(f, "ignore forever"),
+            TriageAction::MarkAsDependency => write!(f, "mark as dependency"),
+            TriageAction::Uninstall => write!(f, "uninstall"),
+            TriageAction::Batch => write!(f, "select several packages..."),
+            TriageAction::Skip => write!(f, "skip"),
+            TriageAction::Quit => write!(f, "save and quit"),
+        }
+    }
+}
+
+/// Prompts what to do with undeclared package(s), `single` adds batch, skip and quit choices
+pub fn prompt_triage_action(
+    message: &str,
+    last_category: Option<&Category>,
+    single: bool,
+) -> Result<TriageAction> {
+    let mut choices: Vec<TriageAction> = last_category
+        .cloned()
+        .map(TriageAction::AddTo)
+        .into_iter()
+        .collect();
+    choices.extend([
+        TriageAction::Add,
+        TriageAction::Ignore,
+        TriageAction::MarkAsDependency,
+        TriageAction::Uninstall,
+    ]);
+    if single {
+        choices.extend([TriageAction::Batch, TriageAction::Skip, TriageAction::Quit]);
+    }
+    Ok(Select::new(message, choices).prompt()?)
+}
+
+/// Prompts for comma separated tags, empty input means no tags
+pub fn prompt_tags() -> Result<Vec<String>> {
+    Ok(Text::new("tags (comma separated, empty for none)")
+        .prompt()?
+        .split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Prompts to choose any number of `pkgs` with fzf
+pub fn prompt_pkgs_from(app: &App, pkgs: &[Package]) -> Result<Vec<Package>> {
+    let pkgs: Vec<String> = pkgs.iter().map(|pkg| pkg.to_string()).collect();
+    fzf_select(&pkgs, &app.backend.preview_command(false), "right:75%")
+}
+
+/// What to do with packages of a deleted category
+pub enum CategoryRemoval {
+    MoveTo(Category),
+    Uninstall,
+    KeepInstalled,
+}
+
+/// Prompts what to do with `count` packages of category being deleted
+pub fn prompt_category_removal(
+    app: &App,
+    category: &Category,
+    count: usize,
+) -> Result<CategoryRemoval> {
+    let choices = vec![
+        "move packages to another category",
+        "uninstall packages",
+        "only delete packages from declaration",
+    ];
+    let message = format!("category '{category}' contains {count} package(s)");
+    Ok(match Select::new(&message, choices).raw_prompt()?.index {
+        0 => CategoryRemoval::MoveTo(prompt_category(app)?),
+        1 => CategoryRemoval::Uninstall,
+        _ => CategoryRemoval::KeepInstalled,
+    })
+}
+
+/// Prompts to choose existing category or to enter path of a new one, new category is not created here
 pub fn prompt_category(app: &App) -> Result<Category> {
     let mut categories: Vec<Category> = collect_categories(app).into_iter().collect();
     let default_cat = &app.config.default_category;
@@ -17,17 +142,99 @@ pub fn prompt_category(app: &App) -> Result<Category> {
         categories.retain(|cat| cat != default_cat);
         categories.insert(0, default_cat.clone());
     }
-    match Select::new("input category", categories).prompt() {
-        Ok(s) => Ok(s.to_owned()),
-        Err(e) => Err(e.into()),
+    let choices: Vec<CategoryChoice> = categories
+        .into_iter()
+        .map(CategoryChoice::Existing)
+        .chain([CategoryChoice::New(None)])
+        .collect();
+
+    match Select::new("input category", choices).prompt()? {
+        CategoryChoice::Existing(category) => Ok(category),
+        CategoryChoice::New(Some(category)) => Ok(category),
+        CategoryChoice::New(None) => Ok(Text::new("new category path (e.g. dev/rust)")
+            .prompt()?
+            .trim()
+            .trim_matches('/')
+            .into()),
     }
 }
 
+/// Prompts what to do with category that does not exist, returns one of `similar` categories or `None` if
+/// the category should be created
+pub fn prompt_missing_category(
+    category: &Category,
+    similar: Vec<Category>,
+) -> Result<Option<Category>> {
+    if similar.is_empty() {
+        let create = Confirm::new(&format!("category '{category}' does not exist, create it?"))
+            .with_default(true)
+            .prompt()?;
+        if !create {
+            return Err(coded(
+                "category_not_found",
+                format!("category '{category}' not found"),
+            ));
+        }
+        return Ok(None);
+    }
+
+    let choices: Vec<CategoryChoice> = similar
+        .into_iter()
+        .map(CategoryChoice::Existing)
+        .chain([CategoryChoice::New(Some(category.clone()))])
+        .collect();
+    let message = format!("category '{category}' does not exist, did you mean");
+    match Select::new(&message, choices).prompt()? {
+        CategoryChoice::Existing(category) => Ok(Some(category)),
+        CategoryChoice::New(_) => Ok(None),
+    }
+}
+
+/// Existing categories with name or full path similar to `category`, most similar first
+pub fn similar_categories(app: &App, category: &Category) -> Vec<Category> {
+    let wanted = category.full_path();
+    let mut similar: Vec<(f64, Category)> = collect_categories(app)
+        .into_iter()
+        .map(|existing| {
+            let mut score = strsim::normalized_damerau_levenshtein(&wanted, &existing.full_path());
+            if category.path.is_empty() {
+                score = score.max(strsim::normalized_damerau_levenshtein(
+                    &category.name,
+                    &existing.name,
+                ));
+            }
+            (score, existing)
+        })
+        .filter(|(score, _)| *score >= 0.6)
+        .collect();
+    similar.sort_by(|a, b| b.0.total_cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+    similar
+        .into_iter()
+        .take(3)
+        .map(|(_, category)| category)
+        .collect()
+}
+
+/// Prompts to choose one of places category is declared in
+pub fn prompt_category_occurrence(
+    category: &Category,
+    occurrences: Vec<CategoryOccurrence>,
+) -> Result<CategoryOccurrence> {
+    Ok(Select::new(
+        &format!("category '{category}' exists in several places, choose one"),
+        occurrences,
+    )
+    .prompt()?)
+}
+
 pub fn collect_categories(app: &App) -> HashSet<Category> {
     let mut categories = HashSet::new();
-    let mut path = Vec::new();
 
     for (_, doc) in &app.docs {
+        let mut path = file_category(doc);
+        for depth in 1..=path.len() {
+            categories.extend(Category::from_path(&path[..depth]));
+        }
         traverse_nodes(doc.nodes(), &mut path, &mut categories);
     }
     categories
@@ -37,6 +244,9 @@ fn traverse_nodes(nodes: &[KdlNode], path: &mut Vec<String>, categories: &mut Ha
     for node in nodes {
         if node.name().value().starts_with("cat:") {
             let cat_name = node.name().value().trim_start_matches("cat:").to_string();
+            if path.is_empty() && is_reserved_category(node.name().value()) {
+                continue;
+            }
 
             categories.insert(Category {
                 name: cat_name.clone(),
@@ -56,57 +266,66 @@ fn traverse_nodes(nodes: &[KdlNode], path: &mut Vec<String>, categories: &mut Ha
     }
 }
 
-pub fn prompt_pkgs_ins(app: &App) -> Result<Vec<Package>> {
-    let pkg_manager = &app.config.package_manager;
-
-    let output = cmd!(pkg_manager, "-Qq")
-        .pipe(cmd!(
-            "fzf",
-            "--multi",
-            "--preview",
-            format!("{} -Qi {{}}", pkg_manager),
-            "--preview-window=right:75%",
-            "--layout=default"
-        ))
-        .read()
-        .map_err(|e| anyhow::anyhow!("Failed to get installed packages: {}", e))?;
+/// Prompts to choose any number of `items` with fzf, `preview` is shell command showing item `{}`
+fn fzf_select(items: &[String], preview: &str, preview_window: &str) -> Result<Vec<Package>> {
+    let output = cmd!(
+        "fzf",
+        "--multi",
+        "--preview",
+        preview,
+        format!("--preview-window={preview_window}"),
+        "--layout=default"
+    )
+    .stdin_bytes(items.join("\n"))
+    .read()?;
 
     Ok(output.lines().map(Package::from).collect())
 }
 
+pub fn prompt_pkgs_ins(app: &App) -> Result<Vec<Package>> {
+    let pkgs = app
+        .backend
+        .query_installed()
+        .map_err(|e| anyhow::anyhow!("Failed to get installed packages: {}", e))?;
+    fzf_select(&pkgs, &app.backend.preview_command(false), "right:75%")
+}
+
 pub fn prompt_pkgs_exp(app: &App) -> Result<Vec<Package>> {
-    let pkg_manager = &app.config.package_manager;
-
-    let output = cmd!(pkg_manager, "-Qqe")
-        .pipe(cmd!(
-            "fzf",
-            "--multi",
-            "--preview",
-            format!("{} -Qi {{}}", pkg_manager),
-            "--preview-window=right:75%",
-            "--layout=default"
-        ))
-        .read()
+    let pkgs = app
+        .backend
+        .query_explicit()
         .map_err(|e| anyhow::anyhow!("Failed to get installed packages: {}", e))?;
+    fzf_select(&pkgs, &app.backend.preview_command(false), "right:75%")
+}
 
-    Ok(output.lines().map(Package::from).collect())
+/// Prompts to choose from explicitly installed packages, most recently installed are shown first
+pub fn prompt_pkgs_chronological(app: &App) -> Result<Vec<Package>> {
+    let pkgs: Vec<String> = get_exp_pkg_list(app, &app.config.pacman_log_file)
+        .map_err(|e| anyhow::anyhow!("Failed to get installed packages: {}", e))?
+        .into_iter()
+        .map(|pkg| pkg.to_string())
+        .collect();
+    // fzf default layout shows the first item at the bottom
+    fzf_select(&pkgs, &app.backend.preview_command(false), "right:75%")
 }
 
 pub fn prompt_pkgs_all(app: &App) -> Result<Vec<Package>> {
-    let pkg_manager = &app.config.package_manager;
-
-    let output = cmd!(pkg_manager, "-Sl", "--color", "never")
-        .pipe(cmd!("awk", "{{print $1 \"/\" $2}}"))
-        .pipe(cmd!(
-            "fzf",
-            "--multi",
-            "--preview",
-            format!("{} -Sii {{}}", pkg_manager),
-            "--preview-window=right:60%",
-            "--layout=default"
-        ))
-        .read()
-        .map_err(|e| anyhow::anyhow!("Failed to get installed packages: {}", e))?;
+    let pkgs = app
+        .backend
+        .list_sync()
+        .map_err(|e| anyhow::anyhow!("Failed to get repository packages: {}", e))?;
+    fzf_select(&pkgs, &app.backend.preview_command(true), "right:60%")
+}
 
-    Ok(output.lines().map(Package::from).collect())
+/// Prompts to choose from declared packages matching `selector`
+pub fn prompt_pkgs_declared(app: &App, selector: &TagSelector) -> Result<Vec<Package>> {
+    let mut pkgs: Vec<String> = get_declared_pkg_list(app)
+        .map_err(|e| anyhow::anyhow!("Failed to get declared packages: {}", e))?
+        .into_iter()
+        .filter(|pkg| selector.matches(pkg))
+        .map(|pkg| pkg.to_string())
+        .collect();
+    pkgs.sort();
+
+    fzf_select(&pkgs, &app.backend.preview_command(true), "right:75%")
 }
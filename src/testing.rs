@@ -0,0 +1,67 @@
+//! Temporary declaration directories with `FakeBackend` for tests of commands
+
+use std::fs;
+use std::path::PathBuf;
+
+use clap::Parser;
+use tempfile::TempDir;
+
+use crate::app::App;
+use crate::cli::Cli;
+
+/// Directory with `config.kdl`, `packages.kdl` declaration and `fixture.kdl` of fake backend
+pub struct TestDir {
+    dir: TempDir,
+}
+
+impl TestDir {
+    /// Empty config, `declaration` and `fixture` are written as given
+    pub fn new(declaration: &str, fixture: &str) -> Self {
+        let test = TestDir {
+            dir: tempfile::tempdir().unwrap(),
+        };
+        test.write("config.kdl", "");
+        test.write("packages.kdl", declaration);
+        test.write("fixture.kdl", fixture);
+        test
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    pub fn write(&self, name: &str, content: &str) {
+        let path = self.path(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    pub fn read(&self, name: &str) -> String {
+        fs::read_to_string(self.path(name)).unwrap()
+    }
+
+    /// Command line running `args` on this directory, changes are applied and nothing is printed by backend
+    pub fn cli(&self, args: &[&str]) -> Cli {
+        let path = |name: &str| self.path(name).to_string_lossy().to_string();
+        let fixture = format!("fake:{}", path("fixture.kdl"));
+        let mut argv = vec![
+            "pacdec".to_string(),
+            "--config".to_string(),
+            path("config.kdl"),
+            "--declare".to_string(),
+            path("packages.kdl"),
+            "--package-manager".to_string(),
+            fixture,
+            "--host".to_string(),
+            "test".to_string(),
+            "--no-dry-run".to_string(),
+            "--quiet".to_string(),
+        ];
+        argv.extend(args.iter().map(|arg| arg.to_string()));
+        Cli::try_parse_from(argv).unwrap()
+    }
+
+    pub fn app(&self, cli: &Cli) -> App {
+        App::init(cli).unwrap()
+    }
+}
@@ -0,0 +1,242 @@
+use anyhow::Result;
+use colored::*;
+use inquire::InquireError;
+
+use crate::app::App;
+use crate::kdl_edit::{add_pkgs, create_category, find_category, ignore_pkgs};
+use crate::packages::{Category, Package};
+use crate::prompts::{
+    TriageAction, prompt_category, prompt_pkgs_from, prompt_tags, prompt_triage_action,
+};
+
+/// Asks what to do with every undeclared package in order of `pkgs`, then of `required` which declared
+/// packages depend on. Declaration is changed in memory only, marking as dependency and uninstalling is done
+/// right away. Skipped packages and packages left after quitting stay undeclared, so running triage again
+/// resumes it. Returns number of undecided packages.
+pub fn triage(app: &mut App, pkgs: Vec<Package>, required: Vec<Package>) -> Result<usize> {
+    let total = pkgs.len() + required.len();
+    let mut queue = pkgs;
+    queue.extend(required.iter().cloned());
+    let mut skipped = 0;
+    let mut last_category: Option<Category> = None;
+
+    while let Some(pkg) = queue.first().cloned() {
+        let label = if required.contains(&pkg) {
+            format!(" {}", "(needed by declared packages)".dimmed())
+        } else {
+            String::new()
+        };
+        println!(
+            "\n{} {}{label}",
+            format!("[{}/{total}]", total - queue.len() + 1).dimmed(),
+            pkg.to_string().bold()
+        );
+        match app.backend.package_info(&pkg.name) {
+            Ok(info) => println!("{}", info.trim_end()),
+            Err(e) => eprintln!("{}", format!("Warning: {e:#}").yellow()),
+        }
+
+        // Esc or Ctrl-C saves decisions made so far
+        let action = match prompt_triage_action(&pkg.name, last_category.as_ref(), true) {
+            Err(e) if is_cancel(&e) => break,
+            action => action?,
+        };
+        let (action, pkgs) = match action {
+            TriageAction::Quit => break,
+            TriageAction::Skip => {
+                queue.remove(0);
+                skipped += 1;
+                continue;
+            }
+            TriageAction::Batch => {
+                // fzf exits with error when selection is cancelled
+                let Ok(selected) = prompt_pkgs_from(app, &queue) else {
+                    continue;
+                };
+                if selected.is_empty() {
+                    continue;
+                }
+                let message = format!("{} package(s)", selected.len());
+                match prompt_triage_action(&message, last_category.as_ref(), false) {
+                    Err(e) if is_cancel(&e) => continue,
+                    action => (action?, selected),
+                }
+            }
+            action => (action, vec![pkg]),
+        };
+
+        match decide(app, action, &pkgs, &mut last_category) {
+            Ok(()) => queue.retain(|pkg| !pkgs.contains(pkg)),
+            Err(e) if is_cancel(&e) => {}
+            // failed transaction leaves packages undecided
+            Err(e) => eprintln!("{} {e:#}", "Error:".red().bold()),
+        }
+    }
+
+    let undecided = queue.len() + skipped;
+    println!(
+        "\n{} {} of {total} package(s)",
+        "Triaged".blue().bold(),
+        total - undecided
+    );
+    if undecided > 0 {
+        println!(
+            "{undecided} package(s) left undeclared, run {} again to continue",
+            "pacdec gen --triage".blue().bold()
+        );
+    }
+    Ok(undecided)
+}
+
+fn decide(
+    app: &mut App,
+    action: TriageAction,
+    pkgs: &[Package],
+    last_category: &mut Option<Category>,
+) -> Result<()> {
+    let names: Vec<String> = pkgs.iter().map(|pkg| pkg.to_string()).collect();
+    match action {
+        TriageAction::AddTo(category) => add_pkgs(app, &category, None, pkgs)?,
+        TriageAction::Add => {
+            let category = prompt_category(app)?;
+            let tags = prompt_tags()?;
+            if find_category(app, &category).is_empty() {
+                create_category(app, &category, None)?;
+            }
+            let pkgs: Vec<Package> = pkgs
+                .iter()
+                .cloned()
+                .map(|mut pkg| {
+                    pkg.tags = tags.clone();
+                    pkg
+                })
+                .collect();
+            add_pkgs(app, &category, None, &pkgs)?;
+            *last_category = Some(category);
+        }
+        TriageAction::Ignore => ignore_pkgs(app, pkgs),
+        TriageAction::MarkAsDependency => app.backend.mark_as_deps(&names)?,
+        TriageAction::Uninstall => app.backend.remove(&names)?,
+        TriageAction::Batch | TriageAction::Skip | TriageAction::Quit => {}
+    }
+    Ok(())
+}
+
+/// Whether error comes from prompt cancelled with Esc or Ctrl-C
+fn is_cancel(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<InquireError>(),
+        Some(InquireError::OperationCanceled | InquireError::OperationInterrupted)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+
+    const FIXTURE: &str = r#"
+installed {
+    foo
+    bar
+    lib reason="dependency"
+}
+"#;
+
+    const DECLARATION: &str = "cat:uncat {}\ncat:dev {\n    lib\n}\n";
+
+    fn pkgs(names: &[&str]) -> Vec<Package> {
+        names.iter().map(|name| Package::from_str(name)).collect()
+    }
+
+    fn sorted(mut names: Vec<String>) -> Vec<String> {
+        names.sort();
+        names
+    }
+
+    fn decided(action: TriageAction, names: &[&str]) -> (TestDir, App, Result<()>) {
+        let test = TestDir::new(DECLARATION, FIXTURE);
+        let mut app = test.app(&test.cli(&["gen"]));
+        let result = decide(&mut app, action, &pkgs(names), &mut None);
+        (test, app, result)
+    }
+
+    fn declaration(app: &App) -> String {
+        app.docs[0].1.to_string()
+    }
+
+    #[test]
+    fn packages_are_added_to_last_category() {
+        let (_test, app, result) = decided(
+            TriageAction::AddTo(Category::from_str("dev")),
+            &["foo", "bar"],
+        );
+
+        result.unwrap();
+        assert_eq!(
+            declaration(&app),
+            "cat:uncat {}\ncat:dev {\n    lib\n    foo\n    bar\n}\n"
+        );
+    }
+
+    #[test]
+    fn ignored_packages_are_added_to_ignored_category_with_date() {
+        let (_test, app, result) = decided(TriageAction::Ignore, &["foo", "bar"]);
+
+        result.unwrap();
+        let today = chrono::Local::now().format("%Y-%m-%d");
+        assert_eq!(
+            declaration(&app),
+            format!(
+                "{DECLARATION}\ncat:ignored {{\n    foo ignored=\"{today}\"\n    bar ignored=\"{today}\"\n}}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn packages_are_marked_as_dependencies_right_away() {
+        let (_test, app, result) = decided(TriageAction::MarkAsDependency, &["foo"]);
+
+        result.unwrap();
+        assert_eq!(declaration(&app), DECLARATION);
+        assert_eq!(app.backend.query_explicit().unwrap(), ["bar"]);
+        assert_eq!(
+            sorted(app.backend.query_dependencies().unwrap()),
+            ["foo", "lib"]
+        );
+    }
+
+    #[test]
+    fn packages_are_uninstalled_right_away() {
+        let (_test, app, result) = decided(TriageAction::Uninstall, &["foo", "bar"]);
+
+        result.unwrap();
+        assert_eq!(declaration(&app), DECLARATION);
+        assert_eq!(app.backend.query_installed().unwrap(), ["lib"]);
+    }
+
+    #[test]
+    fn failed_transaction_is_an_error() {
+        let (_test, app, result) = decided(TriageAction::Uninstall, &["foo", "missing"]);
+
+        assert!(result.is_err());
+        assert_eq!(
+            sorted(app.backend.query_installed().unwrap()),
+            ["bar", "foo", "lib"]
+        );
+    }
+
+    #[test]
+    fn skipping_changes_nothing() {
+        for action in [TriageAction::Skip, TriageAction::Quit, TriageAction::Batch] {
+            let (_test, app, result) = decided(action, &["foo"]);
+
+            result.unwrap();
+            assert_eq!(declaration(&app), DECLARATION);
+            assert_eq!(
+                sorted(app.backend.query_explicit().unwrap()),
+                ["bar", "foo"]
+            );
+        }
+    }
+}
@@ -0,0 +1,49 @@
+//! Runs the `pacdec` binary against fake backend, which is only accepted in builds with `fake-backend` feature
+
+use std::fs;
+use std::process::{Command, Output};
+
+use tempfile::TempDir;
+
+fn pacdec(dir: &TempDir, args: &[&str]) -> Output {
+    let path = |name: &str| dir.path().join(name);
+    fs::write(path("config.kdl"), "").unwrap();
+    fs::write(path("packages.kdl"), "cat:uncat {\n    foo\n}\n").unwrap();
+    fs::write(path("fixture.kdl"), "installed {\n    foo\n    bar\n}\n").unwrap();
+    Command::new(env!("CARGO_BIN_EXE_pacdec"))
+        .arg("--config")
+        .arg(path("config.kdl"))
+        .arg("--declare")
+        .arg(path("packages.kdl"))
+        .arg("--package-manager")
+        .arg(format!("fake:{}", path("fixture.kdl").display()))
+        .args(["--host", "test", "--output", "json"])
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+#[cfg(feature = "fake-backend")]
+fn generate_against_fake_backend() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let output = pacdec(&dir, &["generate"]);
+
+    // system has undeclared `bar`
+    assert_eq!(output.status.code(), Some(2));
+    let diff: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(diff["installed_only"][0]["name"], "bar");
+}
+
+#[test]
+#[cfg(not(feature = "fake-backend"))]
+fn fake_backend_is_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let output = pacdec(&dir, &["generate"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    let error: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(error["error"]["code"], "config_invalid");
+}